@@ -1,13 +1,16 @@
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
-use std::io::SeekFrom;
+use std::io::Cursor;
+use std::mem;
 
-use byteorder::{WriteBytesExt, LittleEndian};
+use byteorder::{ByteOrder, WriteBytesExt, BigEndian, LittleEndian};
 
 use syntax_tree::NodeType;
 use syntax_tree::OpType;
+use diagnostic::{Diagnostic, Position};
+use sourcemap::SourceMapEntry;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum OpCode {
   // Stack
   PushNum = 0x20,
@@ -17,23 +20,43 @@ pub enum OpCode {
   Take    = 0x24,
   Swap    = 0x25,
   Pop     = 0x26,
+  // no operands, pushes the VM's Null, the value of `null` and `undefined`
+  PushNull = 0x27,
+  // `PushFn` of a function using `arguments`, with its parameter count
+  PushFnArgs = 0x28,
+  // pushes false for 0, true otherwise
+  PushBool = 0x29,
 
   // Memory
   Load = 0x31,
   Store = 0x32,
+  Offset = 0x33,
 
   // Control
   JumpIf = 0x40,
   Jump = 0x41,
   Call = 0x42,
+  Nop = 0x43,
 
   // Arithmetic operations
   Add = 0x50,
   Sub = 0x51,
   Mul = 0x52,
+  // Operands are converted to numbers, a TypeError if they aren't convertible.
+  // A zero divisor is no error: `x / 0` is Infinity with the sign of `x`,
+  // or NaN if `x` is 0 or NaN, as in IEEE 754 and JS.
   Div = 0x53,
+  // Sign of the dividend, `x % 0` is NaN for any `x`
   Mod = 0x54,
   Neg = 0x55,
+  ToNumber = 0x56,
+  TypeOf = 0x57,
+  BitAnd = 0x58,
+  BitOr = 0x59,
+  BitXor = 0x5A,
+  BitNot = 0x5B,
+  Shl = 0x5C,
+  Shr = 0x5D,
 
   // Logic operations
   Lt    = 0x60,
@@ -49,10 +72,87 @@ pub enum OpCode {
   // Dict operations
   Get = 0x70,
   PushDict = 0x71,
-  PushArray = 0x72
+  PushArray = 0x72,
+  Len = 0x73
 }
 
+const OPCODES: [OpCode; 44] = [
+  OpCode::PushNum, OpCode::PushStr, OpCode::PushInt, OpCode::PushFn,
+  OpCode::Take, OpCode::Swap, OpCode::Pop, OpCode::PushNull, OpCode::PushFnArgs, OpCode::PushBool,
+  OpCode::Load, OpCode::Store, OpCode::Offset,
+  OpCode::JumpIf, OpCode::Jump, OpCode::Call, OpCode::Nop,
+  OpCode::Add, OpCode::Sub, OpCode::Mul, OpCode::Div, OpCode::Mod, OpCode::Neg, OpCode::ToNumber,
+  OpCode::TypeOf, OpCode::BitAnd, OpCode::BitOr, OpCode::BitXor, OpCode::BitNot, OpCode::Shl, OpCode::Shr,
+  OpCode::Lt, OpCode::Gt, OpCode::Eq, OpCode::NotEq, OpCode::Leq, OpCode::Geq,
+  OpCode::And, OpCode::Or, OpCode::Not,
+  OpCode::Get, OpCode::PushDict, OpCode::PushArray, OpCode::Len
+];
+
+// Operations without operands, as named in the listing
+const ALU_OPS: [OpCode; 23] = [
+  OpCode::Add, OpCode::Sub, OpCode::Mul, OpCode::Div, OpCode::Mod, OpCode::Neg,
+  OpCode::ToNumber, OpCode::TypeOf,
+  OpCode::BitAnd, OpCode::BitOr, OpCode::BitXor, OpCode::BitNot, OpCode::Shl, OpCode::Shr,
+  OpCode::Lt, OpCode::Gt, OpCode::Eq, OpCode::NotEq, OpCode::Leq, OpCode::Geq,
+  OpCode::And, OpCode::Or, OpCode::Not
+];
+
 impl OpCode {
+  // Maps a byte of the bytecode back to its opcode, None for unknown bytes
+  pub fn from_u8(byte: u8) -> Option<OpCode> {
+    OPCODES.iter().find(|&&op| op as u8 == byte).cloned()
+  }
+
+  // Mnemonic used in the assembly listing
+  pub fn name(&self) -> &'static str {
+    match *self {
+      OpCode::PushNum   => "push_float",
+      OpCode::PushStr   => "push_str",
+      OpCode::PushInt   => "push_int",
+      OpCode::PushFn    => "push_fn",
+      OpCode::Take      => "take",
+      OpCode::Swap      => "swap",
+      OpCode::Pop       => "pop",
+      OpCode::PushNull  => "push_null",
+      OpCode::PushFnArgs => "push_fn_args",
+      OpCode::PushBool  => "push_bool",
+      OpCode::Load      => "load",
+      OpCode::Store     => "store",
+      OpCode::Offset    => "offset",
+      OpCode::JumpIf    => "jump_if",
+      OpCode::Jump      => "jump",
+      OpCode::Call      => "call",
+      OpCode::Nop       => "nop",
+      OpCode::Add       => "add",
+      OpCode::Sub       => "sub",
+      OpCode::Mul       => "mul",
+      OpCode::Div       => "div",
+      OpCode::Mod       => "mod",
+      OpCode::Neg       => "neg",
+      OpCode::ToNumber  => "to_number",
+      OpCode::TypeOf    => "typeof",
+      OpCode::BitAnd    => "bit_and",
+      OpCode::BitOr     => "bit_or",
+      OpCode::BitXor    => "bit_xor",
+      OpCode::BitNot    => "bit_not",
+      OpCode::Shl       => "shl",
+      OpCode::Shr       => "shr",
+      OpCode::Lt        => "lt",
+      OpCode::Gt        => "gt",
+      OpCode::Eq        => "eq",
+      OpCode::NotEq     => "neq",
+      OpCode::Leq       => "leq",
+      OpCode::Geq       => "geq",
+      OpCode::And       => "and",
+      OpCode::Or        => "or",
+      OpCode::Not       => "not",
+      OpCode::Get       => "get",
+      OpCode::PushDict  => "push_dict",
+      OpCode::PushArray => "push_array",
+      OpCode::Len       => "len"
+    }
+  }
+
   pub fn from_op_node_type(nt: &NodeType) -> Option<OpCode> {
     match nt {
       &NodeType::Op(OpType::OpMul)   => Some(OpCode::Mul),
@@ -69,68 +169,483 @@ impl OpCode {
       &NodeType::Op(OpType::OpNot)   => Some(OpCode::Not),
       &NodeType::Op(OpType::OpPlus)  => Some(OpCode::Add),
       &NodeType::Op(OpType::OpMinus) => Some(OpCode::Sub),
+      &NodeType::Op(OpType::OpBitAnd) => Some(OpCode::BitAnd),
+      &NodeType::Op(OpType::OpBitOr)  => Some(OpCode::BitOr),
+      &NodeType::Op(OpType::OpBitXor) => Some(OpCode::BitXor),
+      &NodeType::Op(OpType::OpBitNot) => Some(OpCode::BitNot),
+      &NodeType::Op(OpType::OpShl)    => Some(OpCode::Shl),
+      &NodeType::Op(OpType::OpShr)    => Some(OpCode::Shr),
+      _ => None
+    }
+  }
+}
+
+// First byte of bytecode which starts with an encoding header,
+// followed by the flags. It is not a valid opcode.
+const HEADER_MAGIC: u8 = 0xEC;
+const FLAG_BIG_ENDIAN: u8 = 0x01;
+const FLAG_SHORT_OPERANDS: u8 = 0x02;
+
+// Byte order and width of instruction operands. Numbers are always 4-byte
+// floats. Bytecode in the default encoding, little-endian with 4-byte
+// operands, has no header.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Encoding {
+  pub big_endian: bool,
+  // 2 or 4 bytes
+  pub operand_size: usize
+}
+
+impl Default for Encoding {
+  fn default() -> Encoding {
+    Encoding { big_endian: false, operand_size: 4 }
+  }
+}
+
+impl Encoding {
+  // Parses the names used on the command line: le32, be32, le16 or be16
+  pub fn from_name(name: &str) -> Option<Encoding> {
+    let big_endian = match name.get(..2) {
+      Some("le") => false,
+      Some("be") => true,
+      _ => return None
+    };
+    let operand_size = match name.get(2..) {
+      Some("32") => 4,
+      Some("16") => 2,
+      _ => return None
+    };
+
+    Some(Encoding { big_endian: big_endian, operand_size: operand_size })
+  }
+
+  pub fn header(&self) -> Vec<u8> {
+    if *self == Encoding::default() {
+      return vec![];
+    }
+
+    let mut flags = 0;
+    if self.big_endian {
+      flags |= FLAG_BIG_ENDIAN;
+    }
+    if self.operand_size == 2 {
+      flags |= FLAG_SHORT_OPERANDS;
+    }
+    vec![HEADER_MAGIC, flags]
+  }
+
+  // Splits the bytecode into its encoding and the code following the header
+  pub fn read_header(bytecode: &[u8]) -> (Encoding, &[u8]) {
+    match bytecode {
+      &[HEADER_MAGIC, flags, ..] => {
+        let encoding = Encoding {
+          big_endian: flags & FLAG_BIG_ENDIAN != 0,
+          operand_size: if flags & FLAG_SHORT_OPERANDS != 0 { 2 } else { 4 }
+        };
+        (encoding, &bytecode[2..])
+      },
+      _ => (Encoding::default(), bytecode)
+    }
+  }
+
+  // The largest value an operand can hold
+  pub fn max_operand(&self) -> u32 {
+    if self.operand_size == 2 { 0xFFFF } else { 0xFFFF_FFFF }
+  }
+
+  pub fn write_operand<W: Write>(&self, out: &mut W, value: u32) {
+    match (self.big_endian, self.operand_size) {
+      (false, 4) => out.write_u32::<LittleEndian>(value),
+      (true, 4) => out.write_u32::<BigEndian>(value),
+      (big_endian, _) => {
+        assert!(value <= 0xFFFF, "operand {} does not fit into 16 bits", value);
+
+        if big_endian {
+          out.write_u16::<BigEndian>(value as u16)
+        } else {
+          out.write_u16::<LittleEndian>(value as u16)
+        }
+      }
+    }.unwrap();
+  }
+
+  // `bytes` holds exactly `operand_size` bytes
+  pub fn read_operand(&self, bytes: &[u8]) -> u32 {
+    match (self.big_endian, self.operand_size) {
+      (false, 4) => LittleEndian::read_u32(bytes),
+      (true, 4) => BigEndian::read_u32(bytes),
+      (false, _) => LittleEndian::read_u16(bytes) as u32,
+      (true, _) => BigEndian::read_u16(bytes) as u32
+    }
+  }
+
+  pub fn write_f32<W: Write>(&self, out: &mut W, value: f32) {
+    if self.big_endian {
+      out.write_f32::<BigEndian>(value).unwrap();
+    } else {
+      out.write_f32::<LittleEndian>(value).unwrap();
+    }
+  }
+
+  pub fn read_f32(&self, bytes: &[u8]) -> f32 {
+    if self.big_endian {
+      BigEndian::read_f32(bytes)
+    } else {
+      LittleEndian::read_f32(bytes)
+    }
+  }
+}
+
+// Operand of a staged instruction
+#[derive(Clone, Debug, PartialEq)]
+enum Operand {
+  Int(u32),
+  Float(f32),
+  Str(String),
+  // offset of the label, resolved when the code is written out
+  Label(usize)
+}
+
+// Instructions are kept until `finish`, so redundant ones can be removed
+// before the offsets are known
+struct Instr {
+  op: OpCode,
+  operands: Vec<Operand>,
+  position: Option<Position>,
+  function: Option<String>
+}
+
+impl Instr {
+  fn size(&self, encoding: &Encoding) -> u32 {
+    let operand_size = encoding.operand_size as u32;
+    self.operands.iter().fold(1, |size, operand| size + match operand {
+      &Operand::Float(_) => 4,
+      &Operand::Str(ref s) => operand_size + s.as_bytes().len() as u32,
+      _ => operand_size
+    })
+  }
+
+  // Pushes a value without side effects
+  fn is_pure_push(&self) -> bool {
+    match self.op {
+      OpCode::PushInt | OpCode::PushNum | OpCode::PushStr | OpCode::PushNull | OpCode::PushBool |
+      OpCode::Take => true,
+      _ => false
+    }
+  }
+
+  fn pop_count(&self) -> Option<u32> {
+    match (self.op, self.operands.get(0)) {
+      (OpCode::Pop, Some(&Operand::Int(n))) => Some(n),
       _ => None
     }
   }
+
+  fn text(&self, labels: &[Option<u32>]) -> String {
+    let operands: Vec<String> = self.operands.iter().map(|operand| match operand {
+      &Operand::Int(value) => format!(" {}", value),
+      &Operand::Float(value) => format!(" {}", value),
+      &Operand::Str(ref value) => format!(" {:?}", value),
+      &Operand::Label(label) => format!(" {}", labels[label].unwrap())
+    }).collect();
+
+    format!("{}{}", self.op.name(), operands.concat())
+  }
 }
 
-pub struct Assembler<'a> {
-  file: &'a mut File,
-  asm_file: Option<File>,
+pub struct Assembler<'a, W: 'a + Write> {
+  file: &'a mut W,
+  encoding: Encoding,
+  // assembly listing output
+  asm_file: Option<Box<dyn Write + 'a>>,
+  code: Vec<Instr>,
+  // offset after the staged instructions
+  ip: u32,
+  // offset the code is loaded at, see `set_origin`
+  origin: u32,
+  // whether `finish` runs the peephole pass
+  peephole: bool,
   sp: Vec<i32>,
-  labels: Vec<Vec<u32>>
+  // whether `assert_sp` checks, on by default in debug builds
+  check_sp: bool,
+  // index of the instruction each filled label points at
+  labels: Vec<Option<usize>>,
+  // source position and function of the instructions being emitted
+  position: Option<Position>,
+  function: Option<String>,
+  source_map: Vec<SourceMapEntry>
 }
 
-impl<'a> Assembler<'a> {
-  pub fn new(f: &'a mut File, asm_f: Option<File>) -> Assembler<'a> {
+impl<'a, W: Write> Assembler<'a, W> {
+  pub fn new(f: &'a mut W, asm_f: Option<Box<dyn Write + 'a>>) -> Assembler<'a, W> {
+    Assembler::with_encoding(f, asm_f, Encoding::default())
+  }
+
+  // The code starts with the encoding header, unless the encoding is the default one
+  pub fn with_encoding(f: &'a mut W, asm_f: Option<Box<dyn Write + 'a>>, encoding: Encoding) -> Assembler<'a, W> {
     Assembler {
       file: f,
+      encoding: encoding,
       asm_file: asm_f,
+      code: vec![],
+      ip: 0,
+      origin: 0,
+      peephole: false,
       sp: vec![0],
-      labels: vec![]
+      check_sp: cfg!(debug_assertions),
+      labels: vec![],
+      position: None,
+      function: None,
+      source_map: vec![]
     }
   }
 
-  pub fn get_ip(&mut self) -> u32 {
-    self.file.seek(SeekFrom::Current(0)).unwrap() as u32
-  }
+  // Offset of the next instruction, before redundant ones are removed
+  pub fn get_ip(&mut self) -> u32 { self.ip }
   pub fn get_sp(&self) -> i32 { *self.sp.last().unwrap() }
   pub fn push_sp(&mut self, new: i32) { self.sp.push(new); }
   pub fn pop_sp(&mut self) -> i32 { self.sp.pop().unwrap() }
 
-  fn print_op(&mut self, op_text: String) {
-    let ip = self.get_ip();
+  pub fn set_check_sp(&mut self, enabled: bool) { self.check_sp = enabled; }
+
+  // The code is appended to code of the given length, the addresses start past it
+  pub fn set_origin(&mut self, origin: u32) {
+    self.origin = origin;
+    self.ip = origin;
+  }
+
+  // Remove redundant instructions when the code is written out, see `remove_redundant`
+  pub fn set_peephole(&mut self, enabled: bool) { self.peephole = enabled; }
+
+  // Panics if the tracked stack pointer differs from the one the compiler
+  // expects at this point, i.e. some instructions were left unbalanced
+  pub fn assert_sp(&mut self, expected: i32) {
+    if self.check_sp && self.get_sp() != expected {
+      panic!("unbalanced stack at {}: sp is {}, expected {}", self.get_ip(), self.get_sp(), expected);
+    }
+  }
+
+  pub fn position(&self) -> Option<Position> { self.position }
+  pub fn set_position(&mut self, pos: Option<Position>) { self.position = pos; }
+
+  // Returns the previous function name
+  pub fn set_function(&mut self, name: Option<String>) -> Option<String> {
+    mem::replace(&mut self.function, name)
+  }
+
+  // Complete after `finish`
+  pub fn source_map(&self) -> &[SourceMapEntry] { &self.source_map }
+
+  fn emit(&mut self, op: OpCode, operands: Vec<Operand>) {
+    let instr = Instr {
+      op: op,
+      operands: operands,
+      position: self.position,
+      function: self.function.clone()
+    };
+
+    self.ip += instr.size(&self.encoding);
+    self.code.push(instr);
+  }
+
+  // Encodes the staged instructions, resolves the labels and writes
+  // the code to the output at once, then the listing. Returns the code,
+  // or an error if an operand or an address doesn't fit into the encoding.
+  pub fn finish(&mut self) -> Result<Vec<u8>, Diagnostic> {
+    if self.peephole {
+      self.remove_redundant();
+    }
+
+    let code = mem::replace(&mut self.code, vec![]);
+
+    let mut offsets = Vec::with_capacity(code.len() + 1);
+    let mut ip = self.origin;
+    for instr in code.iter() {
+      offsets.push(ip);
+      ip += instr.size(&self.encoding);
+    }
+    offsets.push(ip);
+
+    let max = self.encoding.max_operand();
+    let msg = format!("program too large for {}-bit encoding", self.encoding.operand_size * 8);
+    if ip > max {
+      return Err(Diagnostic::error(msg, None));
+    }
+
+    let labels: Vec<Option<u32>> = self.labels.iter().map(|label| label.map(|i| offsets[i])).collect();
+
+    let mut bytes = self.encoding.header();
+    // positions of the label operands in `bytes`
+    let mut relocations = vec![];
+
+    for (i, instr) in code.iter().enumerate() {
+      if let Some(pos) = instr.position {
+        self.source_map.push(SourceMapEntry { ip: offsets[i], pos: pos, function: instr.function.clone() });
+      }
+
+      bytes.push(instr.op as u8);
+      for operand in instr.operands.iter() {
+        let value = match operand {
+          &Operand::Int(value) => value,
+          &Operand::Str(ref value) => value.as_bytes().len() as u32,
+          _ => 0
+        };
+        if value > max {
+          return Err(Diagnostic::error(msg, instr.position));
+        }
+
+        match operand {
+          &Operand::Int(value) => self.encoding.write_operand(&mut bytes, value),
+          &Operand::Float(value) => self.encoding.write_f32(&mut bytes, value),
+          &Operand::Str(ref value) => {
+            self.encoding.write_operand(&mut bytes, value.as_bytes().len() as u32);
+            bytes.extend_from_slice(value.as_bytes());
+          },
+          &Operand::Label(label) => {
+            relocations.push((bytes.len(), label));
+            self.encoding.write_operand(&mut bytes, 0);
+          }
+        }
+      }
+    }
+
+    for &(pos, label) in relocations.iter() {
+      let offset = labels[label].unwrap_or_else(|| panic!("label_{} is never filled", label));
+      let mut operand = vec![];
+      self.encoding.write_operand(&mut operand, offset);
+      bytes[pos..pos + operand.len()].copy_from_slice(&operand);
+    }
+
+    self.file.write_all(&bytes).unwrap();
 
     if let Some(ref mut file) = self.asm_file {
-      writeln!(file, "{:05} {}", ip, op_text).unwrap();
+      for i in 0..code.len() + 1 {
+        for (label, _) in self.labels.iter().enumerate().filter(|&(_, &l)| l == Some(i)) {
+          writeln!(file, "{:05} @label_{}:", offsets[i], label).unwrap();
+        }
+        if let Some(instr) = code.get(i) {
+          writeln!(file, "{:05} {}", offsets[i], instr.text(&labels)).unwrap();
+        }
+      }
+    }
+
+    Ok(bytes)
+  }
+
+  // Peephole pass: removes values pushed only to be popped, `pop 0`,
+  // `swap a a`, jumps to the next instruction and unreachable code after
+  // jumps, and merges consecutive pops. Nothing is merged with an
+  // instruction which is a jump target.
+  fn remove_redundant(&mut self) {
+    loop {
+      let targets: HashSet<usize> = self.labels.iter().filter_map(|&l| l).collect();
+      let mut removed = vec![false; self.code.len()];
+      // last instruction kept so far
+      let mut prev: Option<usize> = None;
+      let mut unreachable = false;
+      let mut i = 0;
+
+      while i < self.code.len() {
+        if targets.contains(&i) {
+          unreachable = false;
+          prev = None;
+        }
+
+        let noop = match (self.code[i].op, &self.code[i].operands[..]) {
+          (OpCode::Pop, &[Operand::Int(0)]) => true,
+          (OpCode::Swap, &[Operand::Int(a), Operand::Int(b)]) => a == b,
+          _ => false
+        };
+
+        if unreachable || noop {
+          removed[i] = true;
+          i += 1;
+          continue;
+        }
+
+        if let (Some(p), Some(n)) = (prev, self.code[i].pop_count()) {
+          if self.code[p].is_pure_push() {
+            removed[p] = true;
+            self.code[i].operands[0] = Operand::Int(n - 1);
+            removed[i] = n == 1;
+            prev = None;
+            i += 1;
+            continue;
+          }
+
+          if let Some(m) = self.code[p].pop_count() {
+            self.code[p].operands[0] = Operand::Int(m + n);
+            removed[i] = true;
+            i += 1;
+            continue;
+          }
+        }
+
+        // push_int @label; jump; @label:
+        if let Some(&Operand::Label(label)) = self.code[i].operands.get(0) {
+          let jumps = self.code.get(i + 1).map_or(false, |next| next.op == OpCode::Jump);
+          if jumps && self.labels[label] == Some(i + 2) && !targets.contains(&(i + 1)) {
+            removed[i] = true;
+            removed[i + 1] = true;
+            prev = None;
+            i += 2;
+            continue;
+          }
+        }
+
+        unreachable = self.code[i].op == OpCode::Jump;
+        prev = Some(i);
+        i += 1;
+      }
+
+      if !removed.contains(&true) {
+        break;
+      }
+
+      // labels of removed instructions move to the next kept one
+      let mut index = Vec::with_capacity(self.code.len() + 1);
+      let mut kept = 0;
+      for &r in removed.iter() {
+        index.push(kept);
+        if !r {
+          kept += 1;
+        }
+      }
+      index.push(kept);
+
+      for label in self.labels.iter_mut() {
+        if let Some(i) = *label {
+          *label = Some(index[i]);
+        }
+      }
+
+      let code = mem::replace(&mut self.code, vec![]);
+      self.code = code.into_iter().zip(removed).filter(|&(_, r)| !r).map(|(instr, _)| instr).collect();
     }
   }
   
   pub fn push_int(&mut self, value: u32) {
-    self.print_op(format!("push_int {}", value));
-
-    self.file.write_u8(OpCode::PushInt as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(value).unwrap();
+    self.emit(OpCode::PushInt, vec![Operand::Int(value)]);
     *self.sp.last_mut().unwrap() += 1;
   }
 
   pub fn push_float(&mut self, value: f32) {
-    self.print_op(format!("push_float {}", value));
-
-    self.file.write_u8(OpCode::PushNum as u8).unwrap();
-    self.file.write_f32::<LittleEndian>(value).unwrap();
+    self.emit(OpCode::PushNum, vec![Operand::Float(value)]);
     *self.sp.last_mut().unwrap() += 1;
   }
 
   pub fn push_str(&mut self, value: &str) {
-    self.print_op(format!("push_str \"{}\"", value));
-
-    let length = value.as_bytes().len() as u32;
+    self.emit(OpCode::PushStr, vec![Operand::Str(value.to_string())]);
+    *self.sp.last_mut().unwrap() += 1;
+  }
 
-    self.file.write_u8(OpCode::PushStr as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(length).unwrap();
-    self.file.write_all(value.as_bytes()).unwrap();
+  pub fn push_null(&mut self) {
+    self.emit(OpCode::PushNull, vec![]);
+    *self.sp.last_mut().unwrap() += 1;
+  }
 
+  pub fn push_bool(&mut self, value: bool) {
+    self.emit(OpCode::PushBool, vec![Operand::Int(value as u32)]);
     *self.sp.last_mut().unwrap() += 1;
   }
 
@@ -139,155 +654,481 @@ impl<'a> Assembler<'a> {
                  parent_frames_offset: u32,
                  own_frame_size: u32
   ) {
-    self.print_op(format!("push_fn {} {} {}",
-                          parent_frames_count,
-                          parent_frames_offset,
-                          own_frame_size));
+    self.emit(OpCode::PushFn, vec![Operand::Int(parent_frames_count),
+                                   Operand::Int(parent_frames_offset),
+                                   Operand::Int(own_frame_size)]);
+  }
 
-    self.file.write_u8(OpCode::PushFn as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(parent_frames_count).unwrap();
-    self.file.write_u32::<LittleEndian>(parent_frames_offset).unwrap();
-    self.file.write_u32::<LittleEndian>(own_frame_size).unwrap();
+  // Like `push_fn`, calls lay out the frame by the parameters and push
+  // the array of all the arguments after the parent frames
+  pub fn push_fn_args(&mut self,
+                      parent_frames_count: u32,
+                      parent_frames_offset: u32,
+                      own_frame_size: u32,
+                      params_count: u32
+  ) {
+    self.emit(OpCode::PushFnArgs, vec![Operand::Int(parent_frames_count),
+                                       Operand::Int(parent_frames_offset),
+                                       Operand::Int(own_frame_size),
+                                       Operand::Int(params_count)]);
   }
 
   pub fn push_dict(&mut self, len: u32) {
-    self.print_op(format!("push_dict {}", len));
-
-    self.file.write_u8(OpCode::PushDict as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(len).unwrap();
+    self.emit(OpCode::PushDict, vec![Operand::Int(len)]);
 
     *self.sp.last_mut().unwrap() -= len as i32 * 2;
     *self.sp.last_mut().unwrap() += 1;
   }
 
   pub fn push_array(&mut self, len: u32) {
-    self.print_op(format!("push_array {}", len));
-
-    self.file.write_u8(OpCode::PushArray as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(len).unwrap();
+    self.emit(OpCode::PushArray, vec![Operand::Int(len)]);
 
     *self.sp.last_mut().unwrap() -= len as i32;
     *self.sp.last_mut().unwrap() += 1;
   }
     
   pub fn take(&mut self, offset: u32) {
-    self.print_op(format!("take {}", offset));
-
-    self.file.write_u8(OpCode::Take as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(offset).unwrap();
-
+    self.emit(OpCode::Take, vec![Operand::Int(offset)]);
     *self.sp.last_mut().unwrap() += 1;
   }
 
   pub fn swap(&mut self, a: u32, b: u32) {
-    self.print_op(format!("swap {} {}", a, b));
-
-    self.file.write_u8(OpCode::Swap as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(a).unwrap();
-    self.file.write_u32::<LittleEndian>(b).unwrap();
+    self.emit(OpCode::Swap, vec![Operand::Int(a), Operand::Int(b)]);
   }
 
   pub fn pop(&mut self, n: u32) {
-    self.print_op(format!("pop {}", n));
-
-    self.file.write_u8(OpCode::Pop as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(n).unwrap();
-
+    self.emit(OpCode::Pop, vec![Operand::Int(n)]);
     *self.sp.last_mut().unwrap() -= n as i32;
   }
 
   pub fn load(&mut self, offset: u32) {
-    self.print_op(format!("load {}", offset));
-
-    self.file.write_u8(OpCode::Load as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(offset).unwrap();
+    self.emit(OpCode::Load, vec![Operand::Int(offset)]);
   }
   
-  pub fn store(&mut self) {
-    self.print_op("store".to_string());
-
-    self.file.write_u8(OpCode::Store as u8).unwrap();
+  pub fn offset(&mut self, offset: u32) {
+    self.emit(OpCode::Offset, vec![Operand::Int(offset)]);
+  }
 
+  pub fn store(&mut self) {
+    self.emit(OpCode::Store, vec![]);
     *self.sp.last_mut().unwrap() -= 2;
   }
 
   pub fn op_binary(&mut self, op: &NodeType) {
-    self.print_op(format!("op {:?}", op));
-
     let opcode = OpCode::from_op_node_type(op).unwrap();
-    self.file.write_u8(opcode as u8).unwrap();
+    self.op(opcode);
 
     *self.sp.last_mut().unwrap() -= 1;
   }
 
   pub fn op_unary(&mut self, op: &NodeType) {
-    self.print_op(format!("op {:?}", op));
-
     let op = match op {
-      &NodeType::Op(OpType::OpPlus) => return,
+      &NodeType::Op(OpType::OpPlus) => OpCode::ToNumber,
       &NodeType::Op(OpType::OpMinus) => OpCode::Neg,
       &NodeType::Op(OpType::OpNot) => OpCode::Not,
+      &NodeType::Op(OpType::OpTypeof) => OpCode::TypeOf,
+      &NodeType::Op(OpType::OpBitNot) => OpCode::BitNot,
       _ => panic!()
     };
-    self.file.write_u8(op as u8).unwrap();
+    self.op(op);
+  }
+
+  fn op(&mut self, op: OpCode) {
+    self.emit(op, vec![]);
   }
 
   pub fn gen_label(&mut self) -> usize {
-    self.labels.push(vec![]);
+    self.labels.push(None);
     self.labels.len() - 1
   }
 
+  // Pushes the offset of the label, it may be filled before or after
   pub fn put_label(&mut self, label: usize) {
-    self.print_op(format!("push_int @label_{}", label));
-
-    let ip = self.get_ip();
-    self.labels[label].push(ip);
-
-    self.file.write_u8(OpCode::PushInt as u8).unwrap();
-    self.file.write_u32::<LittleEndian>(0xDEAD).unwrap();
+    self.emit(OpCode::PushInt, vec![Operand::Label(label)]);
     *self.sp.last_mut().unwrap() += 1;
   }
 
   pub fn fill_label(&mut self, label: usize) {
-    self.print_op(format!("@label_{}:", label));
-
-    let offset = self.get_ip(); 
-    for pos in self.labels[label].iter() {
-      self.file.seek(SeekFrom::Start(*pos as u64)).unwrap();
-      self.file.write_u8(OpCode::PushInt as u8).unwrap();
-      self.file.write_u32::<LittleEndian>(offset as u32).unwrap();
-      self.file.seek(SeekFrom::End(0)).unwrap();
-    }
+    self.labels[label] = Some(self.code.len());
   }
 
   pub fn jump(&mut self) {
-    self.print_op("jump".to_string());
-
-    self.file.write_u8(OpCode::Jump as u8).unwrap();
-
+    self.emit(OpCode::Jump, vec![]);
     *self.sp.last_mut().unwrap() -= 1;
   }
 
   pub fn jump_if(&mut self) {
-    self.print_op("jump_if".to_string());
-
-    self.file.write_u8(OpCode::JumpIf as u8).unwrap();
-
+    self.emit(OpCode::JumpIf, vec![]);
     *self.sp.last_mut().unwrap() -= 2;
   }
 
   pub fn call(&mut self, n_args: u32) {
-    self.print_op("call".to_string());
-
-    self.file.write_u8(OpCode::Call as u8).unwrap();
+    self.emit(OpCode::Call, vec![]);
     *self.sp.last_mut().unwrap() -= 1 + n_args as i32 + 1;
   }
 
-  pub fn get(&mut self) {
-    self.print_op("get".to_string());
+  // Does nothing, fills the place of a removed instruction
+  pub fn nop(&mut self) {
+    self.op(OpCode::Nop);
+  }
 
-    self.file.write_u8(OpCode::Get as u8).unwrap();
+  pub fn get(&mut self) {
+    self.emit(OpCode::Get, vec![]);
     *self.sp.last_mut().unwrap() -= 1;
   }
+
+  pub fn len(&mut self) {
+    self.emit(OpCode::Len, vec![]);
+  }
+}
+
+// Assembles a listing written by the assembler back into bytecode.
+// Instruction offsets at the start of the lines are ignored.
+pub fn assemble_text(text: &str) -> Result<Vec<u8>, String> {
+  let mut code = Cursor::new(vec![]);
+
+  {
+    let mut asm = Assembler::new(&mut code, None);
+    let mut labels = HashMap::new();
+
+    for (n, line) in text.lines().enumerate() {
+      let err = |msg: String| format!("line {}: {}", n + 1, msg);
+
+      let mut line = line.trim();
+      if let Some(i) = line.find(' ') {
+        if line[..i].chars().all(|c| c.is_digit(10)) {
+          line = line[i..].trim();
+        }
+      }
+
+      if line.is_empty() {
+        continue;
+      }
+
+      if line.starts_with('@') && line.ends_with(':') {
+        let label = *labels.entry(line[..line.len() - 1].to_string())
+          .or_insert_with(|| asm.gen_label());
+        asm.fill_label(label);
+        continue;
+      }
+
+      let (op, args) = match line.find(' ') {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, "")
+      };
+
+      let ints = || -> Result<Vec<u32>, String> {
+        args.split_whitespace()
+          .map(|a| a.parse::<u32>().map_err(|_| err(format!("invalid integer '{}'", a))))
+          .collect()
+      };
+      let int = || -> Result<u32, String> {
+        ints()?.get(0).cloned().ok_or(err(format!("operand expected for '{}'", op)))
+      };
+
+      match op {
+        "push_int" if args.starts_with('@') => {
+          let label = *labels.entry(args.to_string()).or_insert_with(|| asm.gen_label());
+          asm.put_label(label);
+        },
+        "push_int" => asm.push_int(int()?),
+        "push_float" => asm.push_float(args.parse::<f32>()
+                                       .map_err(|_| err(format!("invalid number '{}'", args)))?),
+        "push_str" => asm.push_str(&unquote(args).map_err(&err)?),
+        "push_fn" => {
+          let a = ints()?;
+          if a.len() != 3 {
+            return Err(err("push_fn expects 3 operands".to_string()));
+          }
+          asm.push_fn(a[0], a[1], a[2]);
+        },
+        "push_null" => asm.push_null(),
+        "push_bool" => asm.push_bool(int()? != 0),
+        "push_dict" => asm.push_dict(int()?),
+        "push_array" => asm.push_array(int()?),
+        "take" => asm.take(int()?),
+        "swap" => {
+          let a = ints()?;
+          if a.len() != 2 {
+            return Err(err("swap expects 2 operands".to_string()));
+          }
+          asm.swap(a[0], a[1]);
+        },
+        "pop" => asm.pop(int()?),
+        "load" => asm.load(int()?),
+        "offset" => asm.offset(int()?),
+        "store" => asm.store(),
+        "jump" => asm.jump(),
+        "jump_if" => asm.jump_if(),
+        "call" => asm.call(0),
+        "nop" => asm.nop(),
+        "get" => asm.get(),
+        _ => match ALU_OPS.iter().find(|o| o.name() == op) {
+          Some(&o) => asm.op(o),
+          None => return Err(err(format!("unknown instruction '{}'", op)))
+        }
+      }
+    }
+
+    asm.finish().unwrap();
+  }
+
+  Ok(code.into_inner())
+}
+
+// Reverses the string quoting of the listing (Rust debug format)
+fn unquote(text: &str) -> Result<String, String> {
+  if text.len() < 2 || !text.starts_with('"') || !text.ends_with('"') {
+    return Err(format!("invalid string {}", text));
+  }
+
+  let mut s = String::new();
+  let mut chars = text[1..text.len() - 1].chars();
+
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      s.push(c);
+      continue;
+    }
+
+    match chars.next() {
+      Some('n') => s.push('\n'),
+      Some('r') => s.push('\r'),
+      Some('t') => s.push('\t'),
+      Some('0') => s.push('\0'),
+      Some('u') => {
+        let code: String = chars.by_ref().skip(1).take_while(|&c| c != '}').collect();
+        let c = u32::from_str_radix(&code, 16).ok().and_then(::std::char::from_u32);
+        s.push(c.ok_or(format!("invalid escape in {}", text))?);
+      },
+      Some(c) => s.push(c),
+      None => return Err(format!("invalid string {}", text))
+    }
+  }
+
+  Ok(s)
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::env;
+  use std::fs;
+  use std::fs::File;
+  use pipeline::{compile, CompileOptions};
+
+  #[test]
+  fn test_assert_sp() {
+    // functions are checked when they are compiled
+    compile("var f = fn(a) { var b = [a, {k: a}]; for (var k in b) { if (k) { return k; } } return a.x; };",
+            &CompileOptions::default(), None).ok().unwrap();
+
+    let mut code = Cursor::new(vec![]);
+    let mut asm = Assembler::new(&mut code, None);
+    asm.push_int(1);
+    asm.push_int(2);
+    asm.get();
+    asm.assert_sp(1);
+    asm.pop(1);
+    asm.assert_sp(0);
+
+    asm.set_check_sp(false);
+    asm.assert_sp(5);
+  }
+
+  #[test]
+  #[should_panic(expected = "unbalanced stack at 10: sp is 2, expected 1")]
+  fn test_assert_sp_unbalanced() {
+    let mut code = Cursor::new(vec![]);
+    let mut asm = Assembler::new(&mut code, None);
+    asm.set_check_sp(true);
+    asm.push_int(1);
+    asm.push_int(2);
+    asm.assert_sp(1);
+  }
+
+  #[test]
+  fn test_opcode_from_u8() {
+    // every byte decodes to the opcode with that value or to nothing
+    let decoded: Vec<OpCode> = (0..=255u8).filter_map(|byte| {
+      let op = OpCode::from_u8(byte);
+      if let Some(op) = op {
+        assert_eq!(op as u8, byte);
+      }
+      op
+    }).collect();
+
+    let names: HashSet<&str> = decoded.iter().map(|op| op.name()).collect();
+    assert_eq!(names.len(), decoded.len());
+    assert_eq!(decoded.len(), OPCODES.len());
+
+    for &op in ALU_OPS.iter() {
+      assert_eq!(OpCode::from_u8(op as u8), Some(op));
+    }
+
+    assert_eq!(OpCode::from_u8(0x00), None);
+    assert_eq!(OpCode::from_u8(0x44), None);
+    assert_eq!(OpCode::from_u8(0xFF), None);
+  }
+
+  #[test]
+  fn test_assemble_text() {
+    let text = "
+      var s = 'say \"hi\"\\n\tnow';
+      var f = fn(a, b) { if (a > -b) { return a; } else { return {x: [b, a]}; } };
+      var i = 0;
+      while (i < 3) { i = i + 1; }
+      std.io.println(s, f(1, 2), !i, -i);";
+
+    let path = env::temp_dir().join(format!("ecmascript_toy_{}.s", ::std::process::id()));
+    let bytecode = compile(text, &CompileOptions::default(), Some(File::create(&path).unwrap()))
+      .ok().unwrap().bytecode;
+
+    let listing = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(listing.contains("push_str \"say \\\"hi\\\"\\n\\tnow\""));
+    assert_eq!(assemble_text(&listing), Ok(bytecode));
+    assert!(assemble_text("00000 frobnicate").is_err());
+  }
+
+  #[test]
+  fn test_resolved_listing() {
+    let path = env::temp_dir().join(format!("ecmascript_toy_labels_{}.s", ::std::process::id()));
+    let bytecode = compile("var i = 0; while (i < 3) { i = i + 1; }",
+                           &CompileOptions::default(), Some(File::create(&path).unwrap()))
+      .ok().unwrap().bytecode;
+
+    let listing = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(!listing.contains("push_int @"));
+
+    // the loop exit jumps to the address of its label
+    let lines: Vec<&str> = listing.lines().collect();
+    let jump_if = lines.iter().position(|l| l.ends_with(" jump_if")).unwrap();
+    let target = lines[jump_if - 1].rsplit(' ').next().unwrap().parse::<u32>().unwrap();
+    assert!(lines.iter().any(|l| l.ends_with(':') && l.starts_with(&format!("{:05} ", target))));
+
+    assert_eq!(assemble_text(&listing), Ok(bytecode));
+  }
+
+  #[test]
+  fn test_peephole() {
+    let assemble = |peephole: bool, emit: &Fn(&mut Assembler<Cursor<Vec<u8>>>)| {
+      let mut code = Cursor::new(vec![]);
+      {
+        let mut asm = Assembler::new(&mut code, None);
+        asm.set_peephole(peephole);
+        emit(&mut asm);
+        asm.finish().unwrap();
+      }
+      code.into_inner()
+    };
+
+    let redundant = |asm: &mut Assembler<Cursor<Vec<u8>>>| {
+      let end = asm.gen_label();
+      asm.push_array(0);
+      asm.push_float(1.0);
+      asm.pop(1);
+      asm.push_null();
+      asm.take(0);
+      asm.pop(2);
+      asm.swap(1, 1);
+      asm.pop(0);
+      asm.push_array(0);
+      asm.pop(1);
+      asm.pop(1);
+      asm.put_label(end);
+      asm.jump();
+      asm.push_str("unreachable");
+      asm.fill_label(end);
+      asm.push_float(2.0);
+    };
+    let expected = assemble(false, &|asm| {
+      asm.push_array(0);
+      asm.push_array(0);
+      asm.pop(2);
+      asm.push_float(2.0);
+    });
+
+    assert!(assemble(false, &redundant).len() > expected.len());
+    assert_eq!(assemble(true, &redundant), expected);
+
+    // nothing is merged with a jump target
+    let target = |asm: &mut Assembler<Cursor<Vec<u8>>>| {
+      let skip = asm.gen_label();
+      asm.push_float(1.0);
+      asm.fill_label(skip);
+      asm.pop(1);
+      asm.put_label(skip);
+      asm.jump();
+    };
+    assert_eq!(assemble(true, &target), assemble(false, &target));
+  }
+
+  #[test]
+  fn test_peephole_labels() {
+    let path = env::temp_dir().join(format!("ecmascript_toy_peephole_{}.s", ::std::process::id()));
+    let text = "
+      var f = fn(n) { if (n < 2) { return n; } return f(n - 1) + f(n - 2); };
+      var i = 0;
+      while (i < 5) { f(i); i = i + 1; if (i > 3) { continue; } }";
+
+    let options = CompileOptions { optimize: true, ..CompileOptions::default() };
+    let bytecode = compile(text, &options, Some(File::create(&path).unwrap())).ok().unwrap().bytecode;
+    let listing = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    assert!(bytecode.len() < compile(text, &CompileOptions::default(), None).ok().unwrap().bytecode.len());
+
+    // jumps land on the start of an instruction or the end of the code
+    let lines: Vec<&str> = listing.lines().filter(|l| !l.ends_with(':')).collect();
+    let mut offsets: Vec<u32> = lines.iter().map(|l| l[..5].parse::<u32>().unwrap()).collect();
+    offsets.push(bytecode.len() as u32);
+    for (n, line) in lines.iter().enumerate().filter(|&(_, l)| l.ends_with(" jump") || l.ends_with(" jump_if")) {
+      if lines[n - 1][6..].starts_with("push_int ") {
+        let target = lines[n - 1].rsplit(' ').next().unwrap().parse::<u32>().unwrap();
+        assert!(offsets.contains(&target), "{} jumps to {}", line, target);
+      }
+    }
+
+    assert_eq!(assemble_text(&listing), Ok(bytecode));
+  }
+
+
+  #[test]
+  fn test_finish() {
+    let mut out = vec![];
+    let code = {
+      let mut asm = Assembler::with_encoding(&mut out, None, Encoding::from_name("be16").unwrap());
+      let back = asm.gen_label();
+      let forward = asm.gen_label();
+      asm.fill_label(back);
+      asm.push_null();
+      asm.put_label(forward);
+      asm.put_label(back);
+      asm.jump_if();
+      asm.fill_label(forward);
+      asm.finish().unwrap()
+    };
+
+    // header, push_null, the labels at offsets 8 and 0, jump_if
+    assert_eq!(code, vec![0xEC, 0x03, 0x27, 0x22, 0x00, 0x08, 0x22, 0x00, 0x00, 0x40]);
+    assert_eq!(out, code);
+
+    let text = include_str!("../samples/qsort.js");
+    let code = compile(text, &CompileOptions::default(), None).ok().unwrap().bytecode;
+    assert!(!code.windows(5).any(|w| w == [OpCode::PushInt as u8, 0xAD, 0xDE, 0x00, 0x00]));
+  }
+
+  #[test]
+  #[should_panic(expected = "label_0 is never filled")]
+  fn test_unfilled_label() {
+    let mut out = ::std::io::sink();
+    let mut asm = Assembler::new(&mut out, None);
+    let label = asm.gen_label();
+    asm.put_label(label);
+    asm.finish().unwrap();
+  }
+}
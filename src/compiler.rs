@@ -1,34 +1,139 @@
 use std::collections::HashMap;
-use std::fs::File;
+use std::io::Write;
+use std::mem;
 
 use syntax_tree::Node;
 use syntax_tree::NodeType;
 use syntax_tree::OpType;
-use assembler::Assembler;
+use assembler::{Assembler, Encoding};
+use diagnostic::{Diagnostic, Position};
+use sourcemap::SourceMapEntry;
 use frame_stack::FrameStackTree;
 
-use var_analyzer::build_frame_stack;
+use var_analyzer::{build_frame_stack, uses_arguments};
+use optimizer;
 
-pub struct Compiler<'a> {
+// Statement which can be left with `break`. Only loops can be continued.
+struct JumpTarget {
+  label: Option<String>,
+  break_label: usize,
+  // label `continue` jumps to: the start of the loop, or the code
+  // run before the next iteration
+  continue_target: Option<usize>,
+  // left by an unlabeled `break`, i.e. a loop or a switch
+  breakable: bool,
+  // stack depth at the target, deeper values are popped before jumping
+  sp: i32
+}
+
+pub struct Compiler<'a, W: 'a + Write> {
   frame_stack: FrameStackTree,
-  assembler: Assembler<'a>,
-  sys_objects: HashMap<&'a str, u32>
+  assembler: Assembler<'a, W>,
+  sys_objects: HashMap<&'a str, u32>,
+  jump_targets: Vec<JumpTarget>,
+  // end of the program, target of a top-level `return`
+  exit_label: Option<usize>,
+  // variable the function being compiled is assigned to
+  fn_name: Option<String>,
+  // reuse repeated subexpressions of assigned values
+  cse: bool,
+  // subexpressions evaluated ahead of the statement, with their stack depth
+  temporaries: HashMap<Node, i32>,
+  // errors found so far, compilation goes on to report all of them
+  errors: Vec<Diagnostic>,
+  // end label and stack depth of the `?.` chain whose object or callee is
+  // being compiled, a null object jumps past the rest of the chain
+  chain: Option<(usize, i32)>
 }
 
-impl<'a> Compiler<'a> {
-  pub fn new(file: &'a mut File, asm_file: Option<File>) -> Compiler<'a> {
+impl<'a, W: Write> Compiler<'a, W> {
+  pub fn new(file: &'a mut W, asm_file: Option<Box<dyn Write + 'a>>) -> Compiler<'a, W> {
+    Compiler::with_encoding(file, asm_file, Encoding::default())
+  }
+
+  pub fn with_encoding(file: &'a mut W, asm_file: Option<Box<dyn Write + 'a>>, encoding: Encoding) -> Compiler<'a, W> {
     Compiler {
       frame_stack: FrameStackTree::new(),
-      assembler: Assembler::new(file, asm_file),
+      assembler: Assembler::with_encoding(file, asm_file, encoding),
       sys_objects: [
         ("std",   0x00),
-      ].iter().cloned().collect()
+        ("Math",  0x01),
+      ].iter().cloned().collect(),
+      jump_targets: vec![],
+      exit_label: None,
+      fn_name: None,
+      cse: false,
+      temporaries: HashMap::new(),
+      errors: vec![],
+      chain: None
+    }
+  }
+
+  // Evaluate subexpressions repeated within an assigned value only once
+  pub fn eliminate_common_subexpressions(&mut self) {
+    self.cse = true;
+  }
+
+  // Drop instructions with no effect, e.g. a value pushed only to be popped
+  pub fn remove_redundant_instructions(&mut self) {
+    self.assembler.set_peephole(true);
+  }
+
+  // Instruction offsets mapped to the source positions they were compiled from
+  pub fn source_map(&self) -> &[SourceMapEntry] {
+    self.assembler.source_map()
+  }
+
+  // Attributes the following instructions to the node, returns the previous position
+  fn enter_node(&mut self, node: &Node) -> Option<Position> {
+    let prev = self.assembler.position();
+    if let Some(span) = node.span {
+      self.assembler.set_position(Some(span.start));
     }
+    prev
   }
 
-  pub fn compile(&mut self, ast: &mut Node) { 
+  // The output is incomplete if errors are returned
+  pub fn compile(&mut self, ast: &mut Node) -> Result<(), Vec<Diagnostic>> {
     self.frame_stack = build_frame_stack(ast);
 
+    self.begin_program();
+    self.compile_block(ast);
+    self.end_program()
+  }
+
+  // Compiles a program one statement at a time, `frame_stack` is the result
+  // of the var analysis of the whole program. The statements must be passed
+  // to `compile_statement` in the order they were analyzed.
+  pub fn begin_streaming(&mut self, frame_stack: FrameStackTree) {
+    self.frame_stack = frame_stack;
+    self.begin_program();
+  }
+
+  pub fn compile_statement(&mut self, stmt: &Node) {
+    self.compile_block(stmt);
+  }
+
+  pub fn finish_streaming(&mut self) -> Result<(), Vec<Diagnostic>> {
+    self.end_program()
+  }
+
+  // Compiles a program continuing one which has run, see `pipeline::compile_continuation`.
+  // `frame_stack` is the result of the var analysis of the program with the
+  // globals declared so far in the root frame.
+  pub fn compile_continuation(&mut self, ast: &Node, frame_stack: FrameStackTree, origin: u32)
+                              -> Result<(), Vec<Diagnostic>> {
+    self.frame_stack = frame_stack;
+    self.assembler.set_origin(origin);
+
+    // no call of the top-level code, it runs in the global frame on the stack
+    self.exit_label = Some(self.assembler.gen_label());
+    self.compile_block(ast);
+    self.end_program()
+  }
+
+  // Calls the function of the top-level code
+  fn begin_program(&mut self) {
     let num_global_vars = self.frame_stack.root_frame().var_offsets.len();
 
     self.assembler.push_int(0);
@@ -41,10 +146,30 @@ impl<'a> Compiler<'a> {
 
     self.assembler.fill_label(start_label);
 
-    self.compile_block(ast);
+    let exit_label = self.assembler.gen_label();
+    self.exit_label = Some(exit_label);
+  }
+
+  fn end_program(&mut self) -> Result<(), Vec<Diagnostic>> {
+    let exit_label = self.exit_label.unwrap();
+    self.assembler.fill_label(exit_label);
+    if let Err(err) = self.assembler.finish() {
+      self.errors.push(err);
+    }
+
+    if self.errors.is_empty() {
+      Ok(())
+    } else {
+      // hoisted functions are compiled out of order
+      let mut errors = mem::replace(&mut self.errors, vec![]);
+      errors.sort_by_key(|e| e.pos.map(|pos| (pos.line, pos.col)));
+      Err(errors)
+    }
   }
 
   fn compile_block(&mut self, node: &Node) {
+    let pos = self.enter_node(node);
+
     match node.type_ {
       NodeType::Block => {
         for ref stmt in &node.body {
@@ -52,11 +177,14 @@ impl<'a> Compiler<'a> {
         }
       },
       NodeType::Assign |
-      NodeType::StmtVar => {
+      NodeType::StmtVar |
+      NodeType::StmtConst |
+      NodeType::StmtFunction => {
         self.compile_assign(node);
       },
-      NodeType::Call => {
-        self.compile_call(node);
+      NodeType::Call |
+      NodeType::Update { .. } => {
+        self.compile_expr(node);
         self.assembler.pop(1);
       },
       NodeType::StmtIf |
@@ -64,25 +192,101 @@ impl<'a> Compiler<'a> {
         self.compile_if(node);
       },
       NodeType::StmtWhile => {
-        self.compile_while(node);
+        self.compile_while(node, None);
+      },
+      NodeType::StmtDoWhile => {
+        self.compile_do_while(node, None);
+      },
+      NodeType::StmtSwitch => {
+        self.compile_switch(node, None);
+      },
+      NodeType::StmtFor => {
+        self.compile_for(node, None);
+      },
+      NodeType::StmtForIn => {
+        self.compile_for_in(node, None);
       },
       NodeType::StmtReturn => {
         self.compile_return(node);
       },
+      NodeType::StmtLabel => {
+        self.compile_label(node);
+      },
+      NodeType::StmtBreak |
+      NodeType::StmtContinue => {
+        self.compile_jump(node);
+      },
       _ => {
-        panic!("unsupported statement");
+        self.error(node, format!("unsupported statement: {}", node.type_));
       }
     }
+
+    self.assembler.set_position(pos);
+  }
+
+  // Compiling goes on after an error, the output is discarded anyway
+  fn error(&mut self, node: &Node, msg: String) {
+    self.errors.push(Diagnostic::error(msg, node.span.map(|span| span.start)));
   }
 
   fn compile_assign(&mut self, node: &Node) {
     let lhand_node = node.body.get(0).unwrap();
     let rhand_node = node.body.get(1).unwrap();
 
+    if let (&NodeType::Symbol(ref name), &NodeType::Function) = (&lhand_node.type_, &rhand_node.type_) {
+      self.fn_name = Some(name.clone());
+    }
+
+    if self.cse {
+      for expr in optimizer::common_subexpressions(rhand_node) {
+        self.compile_expr(&expr);
+        let sp = self.assembler.get_sp();
+        self.temporaries.insert(expr, sp);
+      }
+    }
+
     self.compile_expr(rhand_node);
     self.take_value(rhand_node);
-    self.compile_expr(lhand_node);
+
+    let temporaries = mem::replace(&mut self.temporaries, HashMap::new());
+
+    self.compile_address(lhand_node);
     self.assembler.store();
+
+    if !temporaries.is_empty() {
+      self.assembler.pop(temporaries.len() as u32);
+    }
+  }
+
+  // Symbols, members and indices compile to the address of the value,
+  // which `take_value` then loads. Assignment stores to the address directly,
+  // so every container and key of a nested target is evaluated exactly once.
+  // Targets with no address (system objects, optional chains, literal
+  // lengths and other expressions) are reported and replaced with null.
+  fn compile_address(&mut self, node: &Node) {
+    match node.type_ {
+      NodeType::Symbol(ref name) if self.sys_objects.contains_key::<str>(name) => {
+        self.error(node, format!("cannot assign to {}", name));
+        self.assembler.push_null();
+      },
+      NodeType::Member if is_literal_length(node) => {
+        self.error(node, "cannot assign to the length of a literal".to_string());
+        self.assembler.push_null();
+      },
+      NodeType::Member |
+      NodeType::Index if is_length(node) && !is_optional_chain(node) => {
+        self.compile_length_address(node);
+      },
+      NodeType::Symbol(_) |
+      NodeType::Member |
+      NodeType::Index if !is_optional_chain(node) => {
+        self.compile_expr(node);
+      },
+      _ => {
+        self.error(node, format!("invalid assignment target: {}", node.type_));
+        self.assembler.push_null();
+      }
+    }
   }
 
   fn compile_dict_key(&mut self, node: &Node) {
@@ -94,23 +298,53 @@ impl<'a> Compiler<'a> {
       NodeType::Number(num) => {
         self.assembler.push_float(num);
       },
-      _ => { panic!("invalid dict key: {:?}", node.type_); }
+      _ => {
+        self.error(node, format!("invalid dict key: {}", node.type_));
+        self.assembler.push_null();
+      }
     }
   }
 
   fn compile_expr(&mut self, node: &Node) { 
+    // a copy of the temporary, loaded by `take_value` like the original
+    if let Some(&sp) = self.temporaries.get(node) {
+      let depth = self.assembler.get_sp() - sp;
+      self.assembler.take(depth as u32);
+      return;
+    }
+
+    if self.chain.is_none() && is_optional_chain(node) {
+      self.compile_optional_chain(node);
+      return;
+    }
+
+    // only the object or callee of a link continues the chain
+    let chain = self.chain.take();
+    let pos = self.enter_node(node);
+
+    // division of a literal by a literal zero yields a constant,
+    // see `OpCode::Div` for other dividends
+    if let Some(value) = literal_zero_division(node) {
+      self.assembler.push_float(value);
+      self.assembler.set_position(pos);
+      return;
+    }
+
     match &node.type_ {
       &NodeType::Op(OpType::OpMul)     |
       &NodeType::Op(OpType::OpDiv)     |
       &NodeType::Op(OpType::OpMod)     |
-      &NodeType::Op(OpType::OpOr)      |
-      &NodeType::Op(OpType::OpAnd)     |
       &NodeType::Op(OpType::OpLs)      |
       &NodeType::Op(OpType::OpGt)      |
       &NodeType::Op(OpType::OpLsEq)    |
       &NodeType::Op(OpType::OpGtEq)    |
       &NodeType::Op(OpType::OpEq)      |
-      &NodeType::Op(OpType::OpNotEq)   => {
+      &NodeType::Op(OpType::OpNotEq)   |
+      &NodeType::Op(OpType::OpBitAnd)  |
+      &NodeType::Op(OpType::OpBitOr)   |
+      &NodeType::Op(OpType::OpBitXor)  |
+      &NodeType::Op(OpType::OpShl)     |
+      &NodeType::Op(OpType::OpShr)     => {
         self.compile_expr(node.body.get(0).unwrap());
         self.take_value(node.body.get(0).unwrap());
 
@@ -119,8 +353,9 @@ impl<'a> Compiler<'a> {
 
         self.assembler.op_binary(&node.type_);
       },
-      &NodeType::Op(OpType::OpNot)  |
-      &NodeType::Op(OpType::OpPlus) => {
+      &NodeType::Op(OpType::OpNot)    |
+      &NodeType::Op(OpType::OpBitNot) |
+      &NodeType::Op(OpType::OpPlus)   => {
         self.compile_expr(node.body.get(0).unwrap());
         self.take_value(node.body.get(0).unwrap());
         
@@ -132,6 +367,69 @@ impl<'a> Compiler<'a> {
           self.assembler.op_unary(&node.type_);
         }
       },
+      &NodeType::Op(OpType::OpAnd) |
+      &NodeType::Op(OpType::OpOr)  => {
+        self.compile_expr(node.body.get(0).unwrap());
+        self.take_value(node.body.get(0).unwrap());
+
+        // the left operand is the result if it decides the outcome:
+        // a falsy one for `&&`, a truthy one for `||`
+        self.assembler.take(0);
+        if node.type_ == NodeType::Op(OpType::OpAnd) {
+          self.assembler.op_unary(&NodeType::Op(OpType::OpNot));
+        }
+
+        let out_label = self.assembler.gen_label();
+        self.assembler.put_label(out_label);
+        self.assembler.jump_if();
+
+        self.assembler.pop(1);
+        self.compile_expr(node.body.get(1).unwrap());
+        self.take_value(node.body.get(1).unwrap());
+
+        self.assembler.fill_label(out_label);
+      },
+      &NodeType::Op(OpType::OpNullish) => {
+        self.compile_expr(node.body.get(0).unwrap());
+        self.take_value(node.body.get(0).unwrap());
+
+        self.assembler.take(0);
+        self.assembler.push_null();
+        self.assembler.op_binary(&NodeType::Op(OpType::OpNotEq));
+
+        // the right operand is only evaluated for a null left one
+        let out_label = self.assembler.gen_label();
+        self.assembler.put_label(out_label);
+        self.assembler.jump_if();
+
+        self.assembler.pop(1);
+        self.compile_expr(node.body.get(1).unwrap());
+        self.take_value(node.body.get(1).unwrap());
+
+        self.assembler.fill_label(out_label);
+      },
+      &NodeType::Op(OpType::OpTypeof) => {
+        let operand = node.body.get(0).unwrap();
+
+        // feature detection, `typeof undeclared` is not an error
+        if let NodeType::Symbol(ref name) = operand.type_ {
+          if !self.sys_objects.contains_key::<str>(name) && self.frame_stack.find_var(name).is_none() {
+            self.assembler.push_str("undefined");
+            self.assembler.set_position(pos);
+            return;
+          }
+        }
+
+        self.compile_expr(operand);
+        self.take_value(operand);
+        self.assembler.op_unary(&node.type_);
+      },
+      &NodeType::Op(OpType::OpVoid) => {
+        self.compile_expr(node.body.get(0).unwrap());
+        self.take_value(node.body.get(0).unwrap());
+        self.assembler.pop(1);
+        self.assembler.push_null();
+      },
       &NodeType::Op(OpType::OpMinus) => {
         if let Some(ref right_node) = node.body.get(1) {
           self.compile_expr(node.body.get(0).unwrap());
@@ -149,17 +447,113 @@ impl<'a> Compiler<'a> {
           }
         }
       },
-      &NodeType::Member => {
+      &NodeType::Ternary => {
+        let cond = node.body.get(0).unwrap();
+
+        self.compile_negated(cond);
+
+        let else_label = self.assembler.gen_label();
+        self.assembler.put_label(else_label);
+        self.assembler.jump_if();
+
         self.compile_expr(node.body.get(1).unwrap());
         self.take_value(node.body.get(1).unwrap());
 
+        let out_label = self.assembler.gen_label();
+        self.assembler.put_label(out_label);
+        self.assembler.jump();
+
+        // only one of the values is pushed
+        let sp = self.assembler.get_sp();
+        self.assembler.push_sp(sp - 1);
+
+        self.assembler.fill_label(else_label);
+        self.compile_expr(node.body.get(2).unwrap());
+        self.take_value(node.body.get(2).unwrap());
+
+        self.assembler.pop_sp();
+        self.assembler.fill_label(out_label);
+      },
+      &NodeType::Update { op, postfix } => {
+        let target = node.body.get(0).unwrap();
+
+        // [old] -> [old, new] for postfix, [new, new] for prefix updates;
+        // the top value is stored leaving the result
+        self.compile_expr(target);
+        self.take_value(target);
+        self.assembler.op_unary(&NodeType::Op(OpType::OpPlus));
+
+        if postfix {
+          self.assembler.take(0);
+        }
+
+        self.assembler.push_float(1.0);
+        self.assembler.op_binary(&NodeType::Op(op));
+
+        if !postfix {
+          self.assembler.take(0);
+        }
+
+        self.compile_address(target);
+        self.assembler.store();
+      },
+      &NodeType::Member | &NodeType::Index if is_length(node) => {
+        self.chain = chain;
+        self.compile_expr(node.body.get(1).unwrap());
+        self.take_link_value(node.body.get(1).unwrap());
+        self.assembler.len();
+      },
+      &NodeType::Member => {
+        self.chain = chain;
+        self.compile_expr(node.body.get(1).unwrap());
+        self.take_link_value(node.body.get(1).unwrap());
+
         self.compile_dict_key(node.body.get(0).unwrap());
 
         self.assembler.get();
       },
+      &NodeType::OptMember => {
+        // an address like member access, so methods called through `?.` get `this`
+        self.chain = chain;
+        self.compile_expr(node.body.get(1).unwrap());
+        self.take_link_value(node.body.get(1).unwrap());
+
+        self.assembler.take(0);
+        self.assembler.push_null();
+        self.assembler.op_binary(&NodeType::Op(OpType::OpNotEq));
+
+        let access_label = self.assembler.gen_label();
+        self.assembler.put_label(access_label);
+        self.assembler.jump_if();
+
+        // the null object is the result of the chain, anything pushed before it
+        // within the chain (a return label, call arguments) is dropped
+        let (end_label, chain_sp) = chain.unwrap();
+        let sp = self.assembler.get_sp();
+        self.assembler.push_sp(sp);
+
+        let extra = (sp - chain_sp - 1) as u32;
+        if extra > 0 {
+          self.assembler.swap(0, extra);
+          self.assembler.pop(extra);
+        }
+        self.assembler.put_label(end_label);
+        self.assembler.jump();
+
+        self.assembler.pop_sp();
+
+        self.assembler.fill_label(access_label);
+        if is_length(node) {
+          self.assembler.len();
+        } else {
+          self.compile_dict_key(node.body.get(0).unwrap());
+          self.assembler.get();
+        }
+      },
       &NodeType::Index => {
+        self.chain = chain;
         self.compile_expr(node.body.get(1).unwrap());
-        self.take_value(node.body.get(1).unwrap());
+        self.take_link_value(node.body.get(1).unwrap());
 
         self.compile_expr(node.body.get(0).unwrap());
         self.take_value(node.body.get(0).unwrap());
@@ -169,7 +563,12 @@ impl<'a> Compiler<'a> {
       &NodeType::Dict => {
         for kv in node.body.chunks(2) {
           let (k, val) = (&kv[0], &kv[1]);
-          self.compile_dict_key(k);
+          if k.type_ == NodeType::ComputedKey {
+            self.compile_expr(&k.body[0]);
+            self.take_value(&k.body[0]);
+          } else {
+            self.compile_dict_key(k);
+          }
           self.compile_expr(val);
           self.take_value(val);
         }
@@ -188,6 +587,13 @@ impl<'a> Compiler<'a> {
       &NodeType::String(ref s) => {
         self.assembler.push_str(s);
       },
+      // a value rather than an address, left as is by `take_value`
+      &NodeType::Null | &NodeType::Undefined => {
+        self.assembler.push_null();
+      },
+      &NodeType::Boolean(value) => {
+        self.assembler.push_bool(value);
+      },
       &NodeType::Symbol(ref s) => {
         if let Some(&sys_ptr) = self.sys_objects.get::<str>(s) {
           self.assembler.push_int(sys_ptr);
@@ -196,25 +602,49 @@ impl<'a> Compiler<'a> {
             let sp_offset = self.assembler.get_sp() as u32 - var.frame_offset as u32;
 
             self.assembler.take(sp_offset);
-            self.assembler.push_int(var.var_offset as u32);
-            self.assembler.op_binary(&NodeType::Op(OpType::OpPlus));
+            self.assembler.offset(var.var_offset as u32);
           } else {
-            panic!("No such variable: {}", &s);
+            self.error(node, format!("No such variable: {}", s));
+            // keeps the stack balanced
+            self.assembler.push_null();
           }
         }
       },
       &NodeType::Call => {
-        self.compile_call(node);
+        self.compile_call(node, chain);
       },
       &NodeType::Function => {
         self.compile_fn(node);
       },
-      _ => panic!()
+      &NodeType::BlockExpr => {
+        // loops outside can't be left with temporaries on the stack
+        let jump_targets = mem::replace(&mut self.jump_targets, vec![]);
+        self.compile_block(node.body.get(0).unwrap());
+        self.jump_targets = jump_targets;
+
+        let value = node.body.get(1).unwrap();
+        if value.type_ == NodeType::Empty {
+          self.assembler.push_null();
+        } else {
+          self.compile_expr(value);
+          self.take_value(value);
+        }
+      },
+      _ => {
+        self.error(node, format!("unsupported expression: {}", node.type_));
+        self.assembler.push_null();
+      }
     }
+
+    self.assembler.set_position(pos);
   }
 
   fn compile_fn(&mut self, node: &Node) {
     self.frame_stack.enter();
+
+    // loops of the enclosing function can't be jumped to from here
+    let jump_targets = mem::replace(&mut self.jump_targets, vec![]);
+    let exit_label = self.exit_label.take();
     
     let label_bypass = self.assembler.gen_label();
     let label_begin = self.assembler.gen_label();
@@ -231,8 +661,14 @@ impl<'a> Compiler<'a> {
 
     let sp = self.assembler.get_sp() as u32 + 1;
     
+    let takes_arguments = uses_arguments(node);
+
     self.assembler.put_label(label_begin);
-    self.assembler.push_fn(parents_len, sp, frame_size);
+    if takes_arguments {
+      self.assembler.push_fn_args(parents_len, sp, frame_size, node.body[0].body.len() as u32);
+    } else {
+      self.assembler.push_fn(parents_len, sp, frame_size);
+    }
 
     // setup bypass jump
     
@@ -241,13 +677,26 @@ impl<'a> Compiler<'a> {
 
     self.assembler.fill_label(label_begin);
 
+    let name = self.fn_name.take();
+    let outer_name = self.assembler.set_function(name);
+
     // function body 
 
-    self.assembler.push_sp(parents_len as i32);
+    // the call pushes the array of the arguments past the parent frames
+    if takes_arguments {
+      self.assembler.push_sp(parents_len as i32 + 1);
+      self.compile_address(&Node::new(NodeType::Symbol("arguments".to_string())));
+      self.assembler.store();
+    } else {
+      self.assembler.push_sp(parents_len as i32);
+    }
 
     let body = node.body.get(1).unwrap();
     self.compile_block(body);
 
+    // statements leave nothing but the parent frames on the stack
+    self.assembler.assert_sp(parents_len as i32);
+
     // clean up stack and jump back
 
     let sp = self.assembler.get_sp();
@@ -258,8 +707,11 @@ impl<'a> Compiler<'a> {
     self.assembler.swap(0, 1);
     self.assembler.jump();
 
+    self.assembler.set_function(outer_name);
     self.assembler.fill_label(label_bypass);
 
+    self.jump_targets = jump_targets;
+    self.exit_label = exit_label;
     self.frame_stack.exit();
   }
 
@@ -275,84 +727,440 @@ impl<'a> Compiler<'a> {
       self.assembler.push_int(0);
     }
     
-    self.assembler.swap(0, sp as u32 + 1);
-    self.assembler.pop(sp as u32 + 1);
+    if let Some(exit_label) = self.exit_label {
+      // the program has no caller, leave the value on the stack and stop
+      self.assembler.put_label(exit_label);
+      self.assembler.jump();
+    } else {
+      self.assembler.swap(0, sp as u32 + 1);
+      self.assembler.pop(sp as u32 + 1);
 
-    self.assembler.swap(0, 1);
-    self.assembler.jump();
+      self.assembler.swap(0, 1);
+      self.assembler.jump();
+    }
 
     self.assembler.pop_sp();
   }
 
-  fn compile_call(&mut self, node: &Node) {
+  // Evaluates a chain of accesses and calls containing `?.` to a value,
+  // a null object anywhere in it jumps to the end with null as the result
+  fn compile_optional_chain(&mut self, node: &Node) {
+    let end_label = self.assembler.gen_label();
+    self.chain = Some((end_label, self.assembler.get_sp()));
+
+    self.compile_expr(node);
+    self.take_link_value(node);
+
+    self.assembler.fill_label(end_label);
+  }
+
+  fn compile_call(&mut self, node: &Node, chain: Option<(usize, i32)>) {
     let ret_label = self.assembler.gen_label();
     self.assembler.put_label(ret_label);
 
     let addr_node = &node.body[0];
     let args_node = &node.body[1];
+    let n_args = args_node.body.len() as u32;
+
+    // a callee reached through `?.` is checked for null before the arguments
+    // are evaluated, then moved above them
+    let callee_first = is_optional_chain(addr_node);
+    if callee_first {
+      self.chain = chain;
+      self.compile_expr(&addr_node);
+    }
 
     for ref n in &args_node.body {
       self.compile_expr(n);
       self.take_value(n);
     }
 
-    self.assembler.push_int(args_node.body.len() as u32);
-    self.compile_expr(&addr_node);
+    self.assembler.push_int(n_args);
 
-    self.assembler.call(args_node.body.len() as u32);
+    if callee_first {
+      for depth in (1..n_args + 2).rev() {
+        self.assembler.swap(depth, depth - 1);
+      }
+    } else if is_length(addr_node) {
+      self.compile_length_address(addr_node);
+    } else {
+      self.compile_expr(&addr_node);
+    }
+
+    self.assembler.call(n_args);
     self.assembler.fill_label(ret_label);
   }
 
+  // Pushes the negated condition for a jump skipping a body. Equality tests
+  // are inverted instead of being negated by a separate instruction. Ordering
+  // comparisons are not: `a < b` and `a >= b` are both false for NaN operands.
+  fn compile_negated(&mut self, cond: &Node) {
+    let inverse = match cond.type_ {
+      NodeType::Op(OpType::OpEq) => Some(OpType::OpNotEq),
+      NodeType::Op(OpType::OpNotEq) => Some(OpType::OpEq),
+      _ => None
+    };
+
+    if let Some(op) = inverse {
+      let pos = self.enter_node(cond);
+      for operand in cond.body.iter() {
+        self.compile_expr(operand);
+        self.take_value(operand);
+      }
+      self.assembler.op_binary(&NodeType::Op(op));
+      self.assembler.set_position(pos);
+    } else {
+      self.compile_expr(cond);
+      self.take_value(cond);
+      self.assembler.op_unary(&NodeType::Op(OpType::OpNot));
+    }
+  }
+
   fn compile_if(&mut self, node: &Node) {
     let cond = node.body.get(0).unwrap();
     let if_body = node.body.get(1).unwrap();
     
-    self.compile_expr(cond);
-    self.take_value(cond);
-    
-    self.assembler.op_unary(&NodeType::Op(OpType::OpNot));
+    self.compile_negated(cond);
 
     let else_label = self.assembler.gen_label(); 
     self.assembler.put_label(else_label);
     self.assembler.jump_if();
 
     self.compile_block(if_body);
-    
-    let out_label = self.assembler.gen_label();
-    self.assembler.put_label(out_label);
-    self.assembler.jump();
-    
-    self.assembler.fill_label(else_label); 
+
+    // an `else if` is an if statement in the else block, compiled the same way
     if let Some(else_body) = node.body.get(2) {
+      let out_label = self.assembler.gen_label();
+      self.assembler.put_label(out_label);
+      self.assembler.jump();
+
+      self.assembler.fill_label(else_label);
       self.compile_block(else_body);
+      self.assembler.fill_label(out_label);
+    } else {
+      self.assembler.fill_label(else_label);
     }
-    self.assembler.fill_label(out_label);
   }
   
-  fn compile_while(&mut self, node: &Node) {
+  fn compile_while(&mut self, node: &Node, label: Option<String>) {
     let cond = node.body.get(0).unwrap();
     let body = node.body.get(1).unwrap();
 
-    let begin = self.assembler.get_ip();
+    let begin = self.assembler.gen_label();
+    self.assembler.fill_label(begin);
+    
+    self.compile_negated(cond);
     
+    let out_label = self.assembler.gen_label();
+    self.assembler.put_label(out_label);
+    self.assembler.jump_if();
+
+    self.jump_targets.push(JumpTarget {
+      label: label,
+      break_label: out_label,
+      continue_target: Some(begin),
+      breakable: true,
+      sp: self.assembler.get_sp()
+    });
+
+    self.compile_block(body);
+
+    self.jump_targets.pop();
+
+    self.assembler.put_label(begin);
+    self.assembler.jump();
+
+    self.assembler.fill_label(out_label); 
+  }
+
+  // The body runs before the condition is checked, `continue` jumps to the check
+  fn compile_do_while(&mut self, node: &Node, label: Option<String>) {
+    let body = node.body.get(0).unwrap();
+    let cond = node.body.get(1).unwrap();
+
+    let begin = self.assembler.gen_label();
+    self.assembler.fill_label(begin);
+
+    let cond_label = self.assembler.gen_label();
+    let out_label = self.assembler.gen_label();
+
+    self.jump_targets.push(JumpTarget {
+      label: label,
+      break_label: out_label,
+      continue_target: Some(cond_label),
+      breakable: true,
+      sp: self.assembler.get_sp()
+    });
+
+    self.compile_block(body);
+
+    self.jump_targets.pop();
+
+    self.assembler.fill_label(cond_label);
     self.compile_expr(cond);
     self.take_value(cond);
+    self.assembler.put_label(begin);
+    self.assembler.jump_if();
+
+    self.assembler.fill_label(out_label);
+  }
+
+  // `for (init; cond; step) body` runs like `init; while (cond) { body; step; }`,
+  // except that `continue` jumps to the step
+  fn compile_for(&mut self, node: &Node, label: Option<String>) {
+    let init = node.body.get(0).unwrap();
+    let cond = node.body.get(1).unwrap();
+    let step = node.body.get(2).unwrap();
+    let body = node.body.get(3).unwrap();
+
+    if init.type_ != NodeType::Empty {
+      self.compile_block(init);
+    }
+
+    let begin = self.assembler.gen_label();
+    self.assembler.fill_label(begin);
+
+    // an empty condition is always true
+    let out_label = self.assembler.gen_label();
+    if cond.type_ != NodeType::Empty {
+      self.compile_negated(cond);
+      self.assembler.put_label(out_label);
+      self.assembler.jump_if();
+    }
+
+    let step_label = self.assembler.gen_label();
+
+    self.jump_targets.push(JumpTarget {
+      label: label,
+      break_label: out_label,
+      continue_target: Some(step_label),
+      breakable: true,
+      sp: self.assembler.get_sp()
+    });
+
+    self.compile_block(body);
+
+    self.jump_targets.pop();
+
+    self.assembler.fill_label(step_label);
+    if step.type_ != NodeType::Empty {
+      self.compile_block(step);
+    }
+
+    self.assembler.put_label(begin);
+    self.assembler.jump();
+
+    self.assembler.fill_label(out_label);
+  }
+
+  // The items (array indices, dict keys or what `__iter__` returns) and
+  // the current index are kept on the stack while the loop runs
+  fn compile_for_in(&mut self, node: &Node, label: Option<String>) {
+    let target = node.body.get(0).unwrap();
+    let container = node.body.get(1).unwrap();
+    let body = node.body.get(2).unwrap();
+
+    let ret_label = self.assembler.gen_label();
+    self.assembler.put_label(ret_label);
+    self.compile_expr(container);
+    self.take_value(container);
+    self.assembler.push_int(1);
+    self.assembler.push_int(self.sys_objects["std"]);
+    self.assembler.load(0);
+    self.assembler.push_str("iter");
+    self.assembler.get();
+    self.assembler.call(1);
+    self.assembler.fill_label(ret_label);
+
+    self.assembler.push_float(-1.0);
+
+    let begin = self.assembler.gen_label();
+    self.assembler.fill_label(begin);
+
+    self.assembler.push_float(1.0);
+    self.assembler.op_binary(&NodeType::Op(OpType::OpPlus));
+
+    // index < items.length
+    self.assembler.take(1);
+    self.assembler.push_str("length");
+    self.assembler.get();
+    self.assembler.load(0);
+    self.assembler.take(1);
+    self.assembler.op_binary(&NodeType::Op(OpType::OpGt));
     self.assembler.op_unary(&NodeType::Op(OpType::OpNot));
-    
+
     let out_label = self.assembler.gen_label();
     self.assembler.put_label(out_label);
     self.assembler.jump_if();
 
+    // target = items[index]
+    self.assembler.take(1);
+    self.assembler.take(1);
+    self.assembler.get();
+    self.assembler.load(0);
+    self.compile_address(&target.body[0]);
+    self.assembler.store();
+
+    self.jump_targets.push(JumpTarget {
+      label: label,
+      break_label: out_label,
+      continue_target: Some(begin),
+      breakable: true,
+      sp: self.assembler.get_sp()
+    });
+
     self.compile_block(body);
 
-    self.assembler.push_int(begin);
+    self.jump_targets.pop();
+
+    self.assembler.put_label(begin);
     self.assembler.jump();
 
-    self.assembler.fill_label(out_label); 
+    self.assembler.fill_label(out_label);
+    self.assembler.pop(2);
+  }
+
+  // The discriminant is kept on the stack while it's compared to the case
+  // values with `==`. Bodies fall through to the next one unless they `break`,
+  // `default` is jumped to when no value matches wherever it's placed.
+  fn compile_switch(&mut self, node: &Node, label: Option<String>) {
+    let discriminant = node.body.get(0).unwrap();
+    let cases = &node.body[1..];
+
+    self.compile_expr(discriminant);
+    self.take_value(discriminant);
+
+    let body_labels: Vec<usize> = cases.iter().map(|_| self.assembler.gen_label()).collect();
+    let out_label = self.assembler.gen_label();
+
+    for (case, &body_label) in cases.iter().zip(body_labels.iter()) {
+      let value = case.body.get(0).unwrap();
+      if value.type_ == NodeType::Empty {
+        continue;
+      }
+
+      self.assembler.take(0);
+      self.compile_expr(value);
+      self.take_value(value);
+      self.assembler.op_binary(&NodeType::Op(OpType::OpEq));
+      self.assembler.put_label(body_label);
+      self.assembler.jump_if();
+    }
+
+    let default = cases.iter().position(|c| c.body[0].type_ == NodeType::Empty);
+    self.assembler.put_label(default.map_or(out_label, |i| body_labels[i]));
+    self.assembler.jump();
+
+    self.jump_targets.push(JumpTarget {
+      label: label,
+      break_label: out_label,
+      continue_target: None,
+      breakable: true,
+      sp: self.assembler.get_sp()
+    });
+
+    for (case, &body_label) in cases.iter().zip(body_labels.iter()) {
+      self.assembler.fill_label(body_label);
+      self.compile_block(case.body.get(1).unwrap());
+    }
+
+    self.jump_targets.pop();
+
+    self.assembler.fill_label(out_label);
+    self.assembler.pop(1);
+  }
+
+  fn compile_label(&mut self, node: &Node) {
+    let name = match node.body[0].type_ {
+      NodeType::Symbol(ref s) => s.clone(),
+      _ => return self.error(node, "invalid label".to_string())
+    };
+    let stmt = node.body.get(1).unwrap();
+
+    match stmt.type_ {
+      NodeType::StmtWhile => return self.compile_while(stmt, Some(name)),
+      NodeType::StmtDoWhile => return self.compile_do_while(stmt, Some(name)),
+      NodeType::StmtSwitch => return self.compile_switch(stmt, Some(name)),
+      NodeType::StmtFor => return self.compile_for(stmt, Some(name)),
+      NodeType::StmtForIn => return self.compile_for_in(stmt, Some(name)),
+      _ => {}
+    }
+
+    let out_label = self.assembler.gen_label();
+
+    self.jump_targets.push(JumpTarget {
+      label: Some(name),
+      break_label: out_label,
+      continue_target: None,
+      breakable: false,
+      sp: self.assembler.get_sp()
+    });
+
+    self.compile_block(stmt);
+
+    self.jump_targets.pop();
+    self.assembler.fill_label(out_label);
+  }
+
+  fn compile_jump(&mut self, node: &Node) {
+    let name = match node.body.get(0).map(|n| &n.type_) {
+      Some(&NodeType::Symbol(ref s)) => Some(s.clone()),
+      None => None,
+      _ => return self.error(node, "invalid label".to_string())
+    };
+
+    let keyword = if node.type_ == NodeType::StmtBreak { "break" } else { "continue" };
+
+    // unlabeled jumps go to the innermost loop (or switch for `break`),
+    // labeled blocks are skipped
+    let target = match name {
+      Some(ref name) => self.jump_targets.iter().rev().find(|t| t.label.as_ref() == Some(name)),
+      None if keyword == "break" => self.jump_targets.iter().rev().find(|t| t.breakable),
+      None => self.jump_targets.iter().rev().find(|t| t.continue_target.is_some())
+    };
+
+    let (break_label, continue_target, target_sp) = match (target, &name) {
+      (Some(target), _) => (target.break_label, target.continue_target, target.sp),
+      (None, &Some(ref name)) => return self.error(node, format!("undefined label: {}", name)),
+      (None, &None) => return self.error(node, format!("'{}' outside of a loop", keyword))
+    };
+
+    let label = match (keyword, continue_target) {
+      ("break", _) => break_label,
+      (_, Some(label)) => label,
+      (_, None) => return self.error(node, format!("cannot continue non-loop label: {}", name.unwrap()))
+    };
+
+    // values kept on the stack by inner loops, the code after the jump
+    // is unreachable so the stack pointer is restored
+    let sp = self.assembler.get_sp();
+    self.assembler.push_sp(sp);
+    if sp > target_sp {
+      self.assembler.pop((sp - target_sp) as u32);
+    }
+
+    self.assembler.put_label(label);
+
+    self.assembler.jump();
+    self.assembler.pop_sp();
+  }
+
+  // Address of the `length` key, which reads compile to `len` instead
+  fn compile_length_address(&mut self, node: &Node) {
+    self.compile_expr(node.body.get(1).unwrap());
+    self.take_value(node.body.get(1).unwrap());
+    self.compile_dict_key(node.body.get(0).unwrap());
+    self.assembler.get();
   }
 
   fn take_value(&mut self, node: &Node) {
     match node.type_ {
+      // `len` yields a value rather than an address
+      NodeType::Member |
+      NodeType::Index if is_length(node) => {},
+      // a `?.` chain is evaluated to a value by `compile_optional_chain`
+      NodeType::Member |
+      NodeType::Index if is_optional_chain(node) => {},
       NodeType::Symbol(_) |
       NodeType::Member |
       NodeType::Index => {
@@ -361,5 +1169,426 @@ impl<'a> Compiler<'a> {
       _ => {}
     }
   }
+
+  // Same as `take_value` for the links of a `?.` chain, which are addresses
+  fn take_link_value(&mut self, node: &Node) {
+    match node.type_ {
+      NodeType::Member |
+      NodeType::OptMember |
+      NodeType::Index if !is_length(node) => {
+        self.assembler.load(0);
+      },
+      _ => self.take_value(node)
+    }
+  }
+}
+
+// Value of a number literal divided (or taken modulo) by a literal zero.
+// Either may be negated, `-0` is parsed as a minus applied to 0.
+fn literal_zero_division(node: &Node) -> Option<f32> {
+  let op = match node.type_ {
+    NodeType::Op(op @ OpType::OpDiv) | NodeType::Op(op @ OpType::OpMod) => op,
+    _ => return None
+  };
+
+  match (literal_number(&node.body[0]), literal_number(&node.body[1])) {
+    (Some(a), Some(b)) if b == 0.0 => Some(if op == OpType::OpDiv { a / b } else { a % b }),
+    _ => None
+  }
+}
+
+// A number literal, possibly under unary minuses
+fn literal_number(node: &Node) -> Option<f32> {
+  match node.type_ {
+    NodeType::Number(n) => Some(n),
+    NodeType::Op(OpType::OpMinus) if node.body.len() == 1 => literal_number(&node.body[0]).map(|n| -n),
+    _ => None
+  }
 }
 
+// Whether a chain of accesses and calls has a `?.` link
+fn is_optional_chain(node: &Node) -> bool {
+  match node.type_ {
+    NodeType::OptMember => true,
+    NodeType::Member | NodeType::Index => is_optional_chain(&node.body[1]),
+    NodeType::Call => is_optional_chain(&node.body[0]),
+    _ => false
+  }
+}
+
+// `.length` or `['length']`, read by the `len` instruction. Strings aren't
+// heap objects, so `get` can't address their length.
+fn is_length(node: &Node) -> bool {
+  let key = match node.type_ {
+    NodeType::Member | NodeType::OptMember | NodeType::Index => &node.body[0],
+    _ => return false
+  };
+
+  match key.type_ {
+    NodeType::Symbol(ref name) if node.type_ != NodeType::Index => name == "length",
+    NodeType::String(ref name) => name == "length",
+    _ => false
+  }
+}
+
+// `.length` of an array, dict or string literal, which can't be assigned.
+// A dict which has or may have a `length` key of its own can. Other targets
+// are only known when run: arrays are resized, dicts get a `length` key and
+// strings fail with a type error.
+fn is_literal_length(node: &Node) -> bool {
+  let is_length_key = |key: &Node| match key.type_ {
+    NodeType::Symbol(ref name) | NodeType::String(ref name) => name == "length",
+    _ => false
+  };
+
+  if node.type_ != NodeType::Member || !is_length_key(&node.body[0]) {
+    return false;
+  }
+
+  match node.body[1].type_ {
+    NodeType::Array | NodeType::String(_) => true,
+    NodeType::Dict => node.body[1].body.iter().step_by(2)
+      .all(|k| k.type_ != NodeType::ComputedKey && !is_length_key(k)),
+    _ => false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+  use tokenizer::Tokenizer;
+  use parser::Parser;
+
+  fn compile(text: &str) -> Vec<u8> {
+    let mut ast = Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse();
+    let mut code = Cursor::new(vec![]);
+    Compiler::new(&mut code, None).compile(&mut ast).unwrap();
+    code.into_inner()
+  }
+
+  // Messages of the errors the program fails to compile with
+  fn compile_errors(text: &str) -> Vec<String> {
+    let mut ast = Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse();
+    let mut code = Cursor::new(vec![]);
+    let errors = Compiler::new(&mut code, None).compile(&mut ast).err().unwrap();
+    errors.iter().map(|e| e.to_string()).collect()
+  }
+
+  // Assembly listing of the compiled program
+  fn listing(text: &str) -> String {
+    listing_with(text, |_| {})
+  }
+
+  // `setup` enables optional passes of the compiler before it runs
+  fn listing_with<F>(text: &str, setup: F) -> String where F: FnOnce(&mut Compiler<Cursor<Vec<u8>>>) {
+    let mut ast = Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse();
+    let mut code = Cursor::new(vec![]);
+    let mut listing = vec![];
+    {
+      let mut compiler = Compiler::new(&mut code, Some(Box::new(&mut listing)));
+      setup(&mut compiler);
+      compiler.compile(&mut ast).unwrap();
+    }
+    String::from_utf8(listing).unwrap()
+  }
+
+  #[test]
+  fn test_inverted_conditions() {
+    let ops = [("==", "neq"), ("!=", "eq")];
+
+    for &(op, inverse) in ops.iter() {
+      let text = format!("var a = 1; var b = 2; if (a {} b) {{ a = 3; }} while (a {} b) {{ a = 3; }}", op, op);
+      let listing = listing(&text);
+      let ops: Vec<&str> = listing.lines()
+        .map(|l| l.splitn(2, ' ').nth(1).unwrap())
+        .filter(|op| !op.contains(' ') && !op.ends_with(':'))
+        .collect();
+      assert_eq!(ops.iter().filter(|&&o| o == inverse).count(), 2, "{}", op);
+      assert!(!ops.contains(&"not"), "{}", op);
+    }
+
+    assert!(listing("var a = 1; if (a) { a = 3; }").lines().any(|l| l.ends_with(" not")));
+
+    // the inverse of an ordering comparison differs for NaN operands
+    for &op in ["<", ">", "<=", ">="].iter() {
+      let listing = listing(&format!("var a = 1; var b = 2; if (a {} b) {{ a = 3; }}", op));
+      assert!(listing.lines().any(|l| l.ends_with(" not")), "{}", op);
+    }
+  }
+
+  #[test]
+  fn test_literal_length() {
+    let ops = |text: &str| -> Vec<String> {
+      listing(text).lines().map(|l| l.splitn(2, ' ').nth(1).unwrap().to_string()).collect()
+    };
+
+    let array = ops("var n = [1, 2, 3].length;");
+    assert!(array.contains(&"len".to_string()));
+    assert!(!array.contains(&"get".to_string()));
+    assert!(ops("var n = {a: 1}.length + 'abc'.length;").iter().filter(|&op| op == "len").count() == 2);
+
+    // any read of `length` is a `len`, only assignments go through `get`
+    let variable = ops("var a = [1]; var n = a.length + a['length'];");
+    assert_eq!(variable.iter().filter(|&op| op == "len").count(), 2);
+    assert!(!variable.contains(&"get".to_string()));
+    assert!(ops("var n = {length: 5}.length;").contains(&"len".to_string()));
+    assert!(!ops("var a = [1]; a.length = 3;").contains(&"len".to_string()));
+  }
+
+  #[test]
+  fn test_computed_keys() {
+    let listing = listing("var k = 'b'; var d = {a: 1, ['a' + 'b']: 2, [k]: 3};");
+    let ops: Vec<&str> = listing.lines().map(|l| l.splitn(2, ' ').nth(1).unwrap()).collect();
+    let dict = ops.iter().position(|&op| op == "push_dict 3").unwrap();
+    assert_eq!(&ops[dict - 8..dict - 4], &["push_str \"a\"", "push_str \"b\"", "add", "push_float 2"]);
+  }
+
+  #[test]
+  fn test_typeof() {
+    let listing = listing("var a = 1; var t = typeof a; var u = typeof b;");
+    let ops: Vec<&str> = listing.lines().map(|l| l.splitn(2, ' ').nth(1).unwrap()).collect();
+    assert_eq!(ops.iter().filter(|&&op| op == "typeof").count(), 1);
+    // an undeclared name is known to be undefined at compile time
+    assert!(ops.contains(&"push_str \"undefined\""));
+  }
+
+  #[test]
+  fn test_string_comparison() {
+    // comparisons of literals are folded into a constant
+    let ops = |text: &str| -> Vec<String> {
+      let mut ast = Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse();
+      optimizer::fold_constants(&mut ast);
+      let mut code = Cursor::new(vec![]);
+      let mut listing = vec![];
+      Compiler::new(&mut code, Some(Box::new(&mut listing))).compile(&mut ast).unwrap();
+      String::from_utf8(listing).unwrap().lines().map(|l| l.splitn(2, ' ').nth(1).unwrap().to_string()).collect()
+    };
+
+    for &(text, value) in [("'abc' == 'abc'", "push_bool 1"), ("'abc' != 'abc'", "push_bool 0")].iter() {
+      let ops = ops(&format!("var a = {};", text));
+      assert!(ops.iter().any(|op| op == value), "{}", text);
+      assert!(!ops.iter().any(|op| op.starts_with("push_str") || op == "eq" || op == "neq" || op == "not"), "{}", text);
+    }
+  }
+
+  #[test]
+  fn test_bitwise_ops() {
+    let listing = listing("var a = 6; var b = (a & 3) | (a ^ 1) | ~a | a << 2 | a >> 1;");
+    for op in ["bit_and", "bit_or", "bit_xor", "bit_not", "shl", "shr"].iter() {
+      assert!(listing.lines().any(|l| l.ends_with(&format!(" {}", op))), "{}", op);
+    }
+  }
+
+  #[test]
+  fn test_for_loop() {
+    // labels are resolved to offsets, their names differ
+    let code = |text: &str| -> Vec<String> {
+      listing(text).lines().filter(|l| !l.ends_with(':')).map(|l| l.to_string()).collect()
+    };
+
+    assert_eq!(code("var s = 0; for (var i = 0; i < 3; i += 1) { s = s + i; }"),
+               code("var s = 0; var i = 0; while (i < 3) { s = s + i; i += 1; }"));
+    assert_eq!(code("var s = 0; for (s = 1; s < 3;) { std.io.print(s); }"),
+               code("var s = 0; s = 1; while (s < 3) { std.io.print(s); }"));
+
+    // without a condition the loop is only left with `break`
+    assert!(!code("var s = 0; for (;; std.io.print(s)) { s = 1; }").iter().any(|l| l.ends_with(" jump_if")));
+  }
+
+  #[test]
+  fn test_do_while() {
+    let listing = listing("var i = 0; do { std.io.print(i); } while (i < 3);");
+    let ops: Vec<&str> = listing.lines()
+      .map(|l| l.splitn(2, ' ').nth(1).unwrap())
+      .filter(|op| !op.ends_with(':'))
+      .collect();
+
+    // the body comes first, the loop ends with a single jump back to it
+    let call = ops.iter().position(|&o| o == "call").unwrap();
+    let cmp = ops.iter().position(|&o| o == "lt").unwrap();
+    assert!(call < cmp);
+    assert_eq!(ops.iter().filter(|&&o| o == "jump_if").count(), 1);
+    assert_eq!(ops.iter().rposition(|&o| o == "jump_if"), Some(cmp + 2));
+  }
+
+  #[test]
+  fn test_switch() {
+    let listing = listing("var x = 2; switch (x) { case 1: x = 10; default: x = 0; case 2: x = 20; }");
+    let ops: Vec<&str> = listing.lines()
+      .map(|l| l.splitn(2, ' ').nth(1).unwrap())
+      .filter(|op| !op.ends_with(':'))
+      .collect();
+
+    // both values are tested before any body, `default` is the last resort
+    assert_eq!(ops.iter().filter(|&&o| o == "eq").count(), 2);
+    let last_test = ops.iter().rposition(|&o| o == "jump_if").unwrap();
+    let first_body = ops.iter().position(|&o| o == "push_float 10").unwrap();
+    let last_body = ops.iter().position(|&o| o == "push_float 20").unwrap();
+    assert!(last_test < first_body);
+    assert_eq!(ops[last_test + 2], "jump");
+
+    // the discriminant is popped once after the bodies
+    assert_eq!(ops[last_body..].iter().filter(|&&o| o == "pop 1").count(), 1);
+  }
+
+  #[test]
+  fn test_continue_switch_label() {
+    assert_eq!(compile_errors("outer: switch (1) { case 1: continue outer; }"),
+               vec!["error at line 1 column 28: cannot continue non-loop label: outer"]);
+  }
+
+  #[test]
+  fn test_short_circuit() {
+    for &op in ["&&", "||"].iter() {
+      let listing = listing(&format!("var a = 0; var b = a {} std.io.print(a);", op));
+      let lines: Vec<&str> = listing.lines().collect();
+
+      let jump_if = lines.iter().position(|l| l.ends_with(" jump_if")).unwrap();
+      let call = lines.iter().rposition(|l| l.ends_with(" call")).unwrap();
+      let target = lines[jump_if - 1].rsplit(' ').next().unwrap();
+      let label = lines.iter().position(|l| l.starts_with(&format!("{:0>5} ", target)) && l.ends_with(':')).unwrap();
+
+      // the jump skips the call forward
+      assert!(jump_if < call && call < label, "{}", op);
+      assert!(!lines.iter().any(|l| l.ends_with(" and") || l.ends_with(" or")), "{}", op);
+    }
+  }
+
+  #[test]
+  fn test_ternary() {
+    let listing = listing("var a = 1; var b = a > 0 ? 'pos' : a < 0 ? 'neg' : 'zero';");
+    let ops: Vec<&str> = listing.lines()
+      .map(|l| l.splitn(2, ' ').nth(1).unwrap())
+      .filter(|op| !op.ends_with(':'))
+      .collect();
+
+    // every condition is tested once, the values follow their tests
+    assert_eq!(ops.iter().filter(|&&o| o == "jump_if").count(), 2);
+    let pos = ops.iter().position(|&o| o == "push_str \"pos\"").unwrap();
+    let neg = ops.iter().position(|&o| o == "push_str \"neg\"").unwrap();
+    let zero = ops.iter().position(|&o| o == "push_str \"zero\"").unwrap();
+    assert!(ops[..pos].contains(&"gt") && pos < neg && neg < zero);
+  }
+
+  #[test]
+  fn test_undeclared_variables() {
+    let mut ast = Parser::new(Tokenizer::new("var a = b + 1;\nfn f() { return a + c; }\nf(d);").tokenize().unwrap()).parse();
+    let mut code = Cursor::new(vec![]);
+    let errors = Compiler::new(&mut code, None).compile(&mut ast).err().unwrap();
+
+    // all of them are reported, in the order of the source
+    let errors: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    assert_eq!(errors, vec![
+      "error at line 1 column 8: No such variable: b",
+      "error at line 2 column 20: No such variable: c",
+      "error at line 3 column 2: No such variable: d"
+    ]);
+  }
+
+  #[test]
+  fn test_invalid_assignment() {
+    assert_eq!(compile_errors("var a = 1;\n1 = 2;"), vec!["error at line 2 column 0: invalid assignment target: 1"]);
+    assert_eq!(compile_errors("[1, 2].length = 3;"), vec!["error at line 1 column 7: cannot assign to the length of a literal"]);
+    assert_eq!(compile_errors("var a = {};\na?.b = 1;"), vec!["error at line 2 column 3: invalid assignment target: ?."]);
+    assert_eq!(compile_errors("var a = 1;\nMath += 1;"), vec!["error at line 2 column 0: cannot assign to Math"]);
+    assert_eq!(compile_errors("var a = {};\na?.b.c += 1;").len(), 1);
+  }
+
+  #[test]
+  fn test_continue_loop_label() {
+    assert!(!compile("var i = 0; outer: while (i < 3) { i = i + 1; continue outer; }").is_empty());
+    assert!(!compile("outer: { break outer; }").is_empty());
+  }
+
+  #[test]
+  fn test_continue_block_label() {
+    assert_eq!(compile_errors("outer: { continue outer; }"),
+               vec!["error at line 1 column 9: cannot continue non-loop label: outer"]);
+  }
+
+  #[test]
+  fn test_break_outside_loop() {
+    assert_eq!(compile_errors("outer: { break; }"),
+               vec!["error at line 1 column 9: 'break' outside of a loop"]);
+  }
+
+  #[test]
+  fn test_continue_in_function() {
+    assert_eq!(compile_errors("while (1) { var f = fn() { continue; }; }"),
+               vec!["error at line 1 column 27: 'continue' outside of a loop"]);
+  }
+
+  #[test]
+  fn test_jump_out_of_function() {
+    assert_eq!(compile_errors("outer: while (1) { var f = fn() { break outer; }; }\nbreak foo;"), vec![
+      "error at line 1 column 34: undefined label: outer",
+      "error at line 2 column 0: undefined label: foo"
+    ]);
+  }
+
+  #[test]
+  fn test_common_subexpressions() {
+    let gets = |text: &str, cse: bool| {
+      let listing = listing_with(text, |compiler| if cse {
+        compiler.eliminate_common_subexpressions();
+      });
+
+      listing.lines().filter(|l| l.ends_with(" get")).count()
+    };
+
+    let text = "var a = [3]; var i = 0; var x = a[i] * a[i];";
+    assert_eq!(gets(text, false), 2);
+    assert_eq!(gets(text, true), 1);
+
+    // the target is not shared with the value
+    assert_eq!(gets("var a = [3]; var i = 0; a[i] = a[i] * a[i];", true), 2);
+  }
+
+  #[test]
+  fn test_compile_to_memory() {
+    use vm::Vm;
+
+    let mut ast = Parser::new(Tokenizer::new("var x = [1, 2]; std.io.println(x[0] + x[1]);").tokenize().unwrap()).parse();
+    let mut code = Cursor::new(vec![]);
+    let mut listing = vec![];
+    Compiler::new(&mut code, Some(Box::new(&mut listing))).compile(&mut ast).unwrap();
+
+    assert!(String::from_utf8(listing).unwrap().lines().any(|l| l.ends_with(" add")));
+
+    let mut vm = Vm::new(code.into_inner());
+    vm.capture_output();
+    vm.run().unwrap();
+    assert_eq!(vm.output(), "3\n");
+  }
+
+  #[test]
+  fn test_division_by_zero() {
+    let ops = |text: &str| -> Vec<String> {
+      listing(text).lines().filter(|l| !l.ends_with(':')).map(|l| l[6..].to_string()).collect()
+    };
+
+    // the dividend is only known at runtime
+    assert!(ops("var x = 1; var y = x / 0;").contains(&"div".to_string()));
+    assert!(ops("var x = 1; var y = x % 0;").contains(&"mod".to_string()));
+
+    for &(text, value) in [("1 / 0", "inf"), ("0 / 0", "NaN"), ("1 % 0", "NaN"), ("0 % 0", "NaN"),
+                           ("1 / -0", "-inf"), ("-1 / 0", "-inf"), ("-1 / -0", "inf"), ("-0 % 0", "NaN")].iter() {
+      let ops = ops(&format!("var y = {};", text));
+      assert!(ops.contains(&format!("push_float {}", value)), "{}", text);
+      assert!(!ops.iter().any(|op| op == "div" || op == "mod"), "{}", text);
+    }
+  }
+
+  #[test]
+  fn test_null() {
+    for text in ["var a = null;", "var a = undefined;"].iter() {
+      let listing = listing(text);
+      assert!(listing.lines().any(|l| l.ends_with(" push_null")), "{}", text);
+      assert!(!listing.contains("push_float"), "{}", text);
+    }
+
+    // 0 stays a number
+    assert!(!listing("var a = 0;").contains("push_null"));
+  }
+}
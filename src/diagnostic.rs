@@ -0,0 +1,105 @@
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Severity {
+  Error,
+  Warning,
+  Note
+}
+
+impl fmt::Display for Severity {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &Severity::Error => write!(f, "error"),
+      &Severity::Warning => write!(f, "warning"),
+      &Severity::Note => write!(f, "note")
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Position {
+  pub line: usize,
+  pub col: usize
+}
+
+// Source range, `end` points right past the last character
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Span {
+  pub start: Position,
+  pub end: Position
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+  pub severity: Severity,
+  pub message: String,
+  pub pos: Option<Position>,
+  // source file name, if the diagnostic comes from a file
+  pub file: Option<String>
+}
+
+impl Diagnostic {
+  pub fn error(message: String, pos: Option<Position>) -> Diagnostic {
+    Diagnostic { severity: Severity::Error, message: message, pos: pos, file: None }
+  }
+
+  pub fn warning(message: String, pos: Option<Position>) -> Diagnostic {
+    Diagnostic { severity: Severity::Warning, message: message, pos: pos, file: None }
+  }
+
+  pub fn note(message: String) -> Diagnostic {
+    Diagnostic { severity: Severity::Note, message: message, pos: None, file: None }
+  }
+
+  pub fn in_file(mut self, file: &str) -> Diagnostic {
+    self.file = Some(file.to_string());
+    self
+  }
+
+  // Formats the diagnostic followed by the offending source line, if known
+  pub fn format(&self, source: &str) -> String {
+    let mut text = format!("{}: {}", self.severity, self.message);
+
+    if let Some(pos) = self.pos {
+      let file = self.file.as_ref().map(|f| format!("{}:", f)).unwrap_or_default();
+      text += &format!("\n  --> {}{}:{}", file, pos.line, pos.col);
+
+      if let Some(line) = source.lines().nth(pos.line.wrapping_sub(1)) {
+        let margin = " ".repeat(pos.line.to_string().len());
+        text += &format!("\n{} |\n{} | {}\n{} | {}^",
+                         margin, pos.line, line, margin, " ".repeat(pos.col));
+      }
+    }
+
+    text
+  }
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match (&self.file, self.pos) {
+      (&Some(ref file), Some(pos)) =>
+        write!(f, "{}:{}:{}: {}: {}", file, pos.line, pos.col, self.severity, self.message),
+      (&Some(ref file), None) =>
+        write!(f, "{}: {}: {}", file, self.severity, self.message),
+      (&None, Some(pos)) =>
+        write!(f, "{} at line {} column {}: {}", self.severity, pos.line, pos.col, self.message),
+      (&None, None) =>
+        write!(f, "{}: {}", self.severity, self.message)
+    }
+  }
+}
+
+// Keeps the first `max` diagnostics, the rest are counted in a note
+pub fn limit(mut diagnostics: Vec<Diagnostic>, max: usize) -> Vec<Diagnostic> {
+  if diagnostics.len() > max {
+    let rest = diagnostics.len() - max;
+    diagnostics.truncate(max);
+
+    let plural = if rest == 1 { "" } else { "s" };
+    diagnostics.push(Diagnostic::note(format!("... and {} more error{}", rest, plural)));
+  }
+
+  diagnostics
+}
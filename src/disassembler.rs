@@ -0,0 +1,168 @@
+use std::io::prelude::*;
+
+use assembler::{Encoding, OpCode};
+use sourcemap::SourceMapEntry;
+use vm::{Vm, VmError};
+
+// Decodes the instruction at `ip` into its listing form, i.e. `push_int 5`.
+// Returns the text and the address of the next instruction, or None if the
+// opcode is unknown or the operands run past the end of the code.
+pub fn instruction(code: &[u8], encoding: &Encoding, ip: usize) -> Option<(String, usize)> {
+  let op = OpCode::from_u8(*code.get(ip)?)?;
+  let operands = ip + 1;
+  let size = encoding.operand_size;
+
+  let u32_at = |i: usize| -> Option<u32> {
+    let at = operands + i * size;
+    code.get(at..at + size).map(|bytes| encoding.read_operand(bytes))
+  };
+
+  let (text, next) = match op {
+    OpCode::PushInt | OpCode::PushDict | OpCode::PushArray | OpCode::Take |
+    OpCode::Pop | OpCode::Load | OpCode::Offset | OpCode::PushBool =>
+      (format!("{} {}", op.name(), u32_at(0)?), operands + size),
+    OpCode::Swap =>
+      (format!("{} {} {}", op.name(), u32_at(0)?, u32_at(1)?), operands + 2 * size),
+    OpCode::PushFn =>
+      (format!("{} {} {} {}", op.name(), u32_at(0)?, u32_at(1)?, u32_at(2)?), operands + 3 * size),
+    OpCode::PushFnArgs =>
+      (format!("{} {} {} {} {}", op.name(), u32_at(0)?, u32_at(1)?, u32_at(2)?, u32_at(3)?), operands + 4 * size),
+    OpCode::PushNum => {
+      let value = encoding.read_f32(code.get(operands..operands + 4)?);
+      (format!("{} {}", op.name(), value), operands + 4)
+    },
+    OpCode::PushStr => {
+      let begin = operands + size;
+      let end = begin + u32_at(0)? as usize;
+      let value = String::from_utf8_lossy(code.get(begin..end)?);
+      (format!("{} {:?}", op.name(), value), end)
+    },
+    _ => (op.name().to_string(), operands)
+  };
+
+  Some((text, next))
+}
+
+// Decodes the bytecode into lines in the format of the `.s` listing, offsets
+// are counted from the end of the encoding header. Bytes which don't start a
+// valid instruction are written as `.byte N`.
+pub fn disassemble(bytecode: &[u8]) -> Vec<String> {
+  let (encoding, code) = Encoding::read_header(bytecode);
+  let mut lines = vec![];
+  let mut ip = 0;
+
+  while ip < code.len() {
+    match instruction(code, &encoding, ip) {
+      Some((text, next)) => {
+        lines.push(format!("{:05} {}", ip, text));
+        ip = next;
+      },
+      None => {
+        lines.push(format!("{:05} .byte {}", ip, code[ip]));
+        ip += 1;
+      }
+    }
+  }
+
+  lines
+}
+
+// Runs the program writing every executed instruction along with the stack
+// it leaves behind. Source lines from the map are written as they change:
+//
+// ; line 1
+// 00030 push_float 1             [0, frame@3, 1]
+pub fn debug_run<W: Write>(vm: &mut Vm, source_map: &[SourceMapEntry],
+                           out: &mut W) -> Result<(), VmError> {
+  let mut line = None;
+
+  vm.run_traced(|vm, ip| {
+    if let Some(entry) = source_map.iter().find(|e| e.ip as usize == ip) {
+      if line != Some(entry.pos.line) {
+        line = Some(entry.pos.line);
+
+        let function = entry.function.as_ref().map(|f| format!(" in {}", f)).unwrap_or_default();
+        writeln!(out, "; line {}{}", entry.pos.line, function).unwrap();
+      }
+    }
+
+    let text = instruction(vm.code(), &vm.encoding(), ip).map(|i| i.0).unwrap_or("???".to_string());
+    let stack: Vec<String> = vm.stack().iter().map(|v| vm.format_value(v)).collect();
+    writeln!(out, "{:05} {:<24} [{}]", ip, text, stack.join(", ")).unwrap();
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs::{self, File};
+  use pipeline::{compile, CompileOptions};
+
+  #[test]
+  fn test_disassemble() {
+    let text = "var a = [1, 'x'];\nif (a.length > 1) { std.io.print(a[1]); }";
+    let path = ::std::env::temp_dir().join(format!("ecmascript_toy_disasm_{}.s", ::std::process::id()));
+    let result = compile(text, &CompileOptions::default(), Some(File::create(&path).unwrap())).ok().unwrap();
+    let listing = fs::read_to_string(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // label lines mark an offset, they don't stand for an instruction
+    let expected: Vec<&str> = listing.lines().filter(|l| !l.ends_with(':')).collect();
+    assert_eq!(disassemble(&result.bytecode), expected);
+
+    let options = CompileOptions { encoding: Encoding::from_name("be16").unwrap(), ..CompileOptions::default() };
+    let result = compile("var a = 'x';", &options, None).ok().unwrap();
+    assert_eq!(disassemble(&result.bytecode)[4], "00014 push_str \"x\"");
+
+    assert_eq!(disassemble(&[OpCode::Pop as u8, 1, 0, 0, 0, 0xFF, OpCode::Add as u8, OpCode::Pop as u8]),
+      vec!["00000 pop 1", "00005 .byte 255", "00006 add", "00007 .byte 38"]);
+  }
+
+  #[test]
+  fn test_debug_run() {
+    let result = compile("var a = 1;\nvar b = a + 'x';", &CompileOptions::default(), None).ok().unwrap();
+
+    // the trace line of the first instruction with the given text, at its offset in the listing
+    let listing = disassemble(&result.bytecode);
+    let traced = |text: &str, stack: &str| {
+      let line = listing.iter().find(|l| &l[6..] == text).unwrap();
+      format!("{} {:<24} [{}]", &line[..5], text, stack)
+    };
+
+    let mut out = vec![];
+    debug_run(&mut Vm::new(result.bytecode.clone()), &result.source_map, &mut out).unwrap();
+    let trace = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = trace.lines().collect();
+
+    assert_eq!(lines[0], traced("push_int 0", "0"));
+    assert_eq!(lines[4], "; line 1");
+    assert_eq!(lines[5], traced("push_float 1", "frame@4, 1"));
+    assert_eq!(lines[9], "; line 2");
+    assert_eq!(lines[13], traced("push_str \"x\"", "frame@4, 1, 'x'"));
+    assert_eq!(lines[14], traced("add", "frame@4, '1x'"));
+
+    // a source line is announced before the instruction the map puts at its start
+    let line_2 = result.source_map.iter().find(|e| e.pos.line == 2).unwrap().ip;
+    assert!(lines[10].starts_with(&format!("{:05} ", line_2)));
+  }
+
+  #[test]
+  fn test_else_if_trace() {
+    let text = "var x = 2;
+      if (x == 1) { std.io.print('one'); }
+      else if (x == 2) { std.io.print('two'); }
+      else { std.io.print('other'); }";
+    let result = compile(text, &CompileOptions::default(), None).ok().unwrap();
+
+    let mut vm = Vm::new(result.bytecode);
+    vm.capture_output();
+    let mut out = vec![];
+    debug_run(&mut vm, &result.source_map, &mut out).unwrap();
+    let trace = String::from_utf8(out).unwrap();
+
+    assert_eq!(vm.output(), "two");
+    assert!(trace.contains("push_str \"two\""));
+    assert!(!trace.contains("push_str \"one\""));
+    assert!(!trace.contains("push_str \"other\""));
+  }
+}
@@ -1,11 +1,15 @@
+#[derive(Clone)]
 pub struct Frame {
-  pub var_offsets: Vec<String>
+  pub var_offsets: Vec<String>,
+  // variables declared with `const`
+  pub consts: Vec<String>
 }
 
 impl Frame {
   pub fn new() -> Frame {
     Frame {
-      var_offsets: vec![ "this".to_string() ]
+      var_offsets: vec![ "this".to_string() ],
+      consts: vec![]
     }
   }
 }
@@ -125,6 +129,14 @@ impl FrameStackTree {
     }
   }
 
+  pub fn put_const(&mut self, name: &String) {
+    self.put_var(name);
+    let consts = &mut self.frames[self.cur_frame].consts;
+    if !consts.contains(name) {
+      consts.push(name.clone());
+    }
+  }
+
   pub fn put_var_global(&mut self, name: &String) {
     let index = self.frames[0].var_offsets.len() as u32;
     let offsets = &mut self.frames[0].var_offsets;
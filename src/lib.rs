@@ -0,0 +1,18 @@
+extern crate byteorder;
+
+pub mod tokenizer;
+pub mod parser;
+pub mod syntax_tree;
+pub mod frame_stack;
+pub mod var_analyzer;
+pub mod assembler;
+pub mod util;
+pub mod compiler;
+pub mod diagnostic;
+pub mod lint;
+pub mod pipeline;
+pub mod vm;
+pub mod repl;
+pub mod sourcemap;
+pub mod optimizer;
+pub mod disassembler;
@@ -0,0 +1,378 @@
+use std::collections::{HashMap, HashSet};
+
+use syntax_tree::Visitor;
+use syntax_tree::Node;
+use syntax_tree::NodeType;
+use frame_stack::FrameStackTree;
+use diagnostic::{Diagnostic, Position};
+
+use var_analyzer::{build_frame_stack, uses_arguments};
+
+// Variable slot identified by (frame id, offset in frame)
+type VarKey = (usize, usize);
+
+// Warns about calls passing a wrong number of arguments to functions
+// whose arity is known statically, i.e. `var f = fn(a, b) {...}; f(1);`
+// Variables which are ever reassigned or redeclared are considered dynamic.
+pub fn check_arity(ast: &mut Node) -> Vec<Diagnostic> {
+  let mut fstack = build_frame_stack(ast);
+
+  let mut arity = HashMap::new();
+  ast.visit(&mut ArityDefPass::new(&mut fstack, &mut arity));
+  fstack.reset();
+
+  let mut warnings = vec![];
+  ast.visit(&mut ArityCallPass::new(&mut fstack, &arity, &mut warnings));
+  fstack.reset();
+
+  warnings
+}
+
+// Warns about `while` and `if` statements with an empty body, which is
+// usually a mistake. Opt-in, as busy-waiting loops are sometimes intended.
+pub fn check_empty_bodies(ast: &mut Node) -> Vec<Diagnostic> {
+  let mut pass = EmptyBodyPass { warnings: vec![] };
+  ast.visit(&mut pass);
+  pass.warnings
+}
+
+// Warns about assignments to a parameter which is also captured by a
+// closure. Closures keep a reference to the enclosing frame, so they see
+// the reassigned value rather than the argument the function was called with.
+pub fn check_captured_writes(ast: &mut Node) -> Vec<Diagnostic> {
+  let mut fstack = build_frame_stack(ast);
+
+  let mut pass = CapturePass {
+    fstack: &mut fstack,
+    params: HashMap::new(),
+    visited: 0,
+    skip: HashSet::new(),
+    captured: HashSet::new(),
+    writes: vec![]
+  };
+  ast.visit(&mut pass);
+
+  let captured = &pass.captured;
+  pass.writes.iter()
+    .filter(|w| captured.contains(&w.1))
+    .map(|w| {
+      let msg = format!("parameter '{}' is captured by a closure and reassigned", w.0);
+      Diagnostic::warning(msg, w.2)
+    })
+    .collect()
+}
+
+// Warns about dict literals with the same key given twice, i.e. `{a: 1, a: 2}`.
+// Only the last value is kept at runtime.
+pub fn check_duplicate_keys(ast: &mut Node) -> Vec<Diagnostic> {
+  let mut pass = DuplicateKeyPass { warnings: vec![] };
+  ast.visit(&mut pass);
+  pass.warnings
+}
+
+fn resolve(fstack: &mut FrameStackTree, node: &Node) -> Option<(String, VarKey)> {
+  if let NodeType::Symbol(ref name) = node.type_ {
+    fstack.find_var(name).map(|v| (name.clone(), (v.frame_id, v.var_offset)))
+  } else {
+    None
+  }
+}
+
+struct ArityDefPass<'a> {
+  fstack: &'a mut FrameStackTree,
+  arity: &'a mut HashMap<VarKey, Option<usize>>
+}
+
+impl<'a> ArityDefPass<'a> {
+  fn new(fstack: &'a mut FrameStackTree,
+         arity: &'a mut HashMap<VarKey, Option<usize>>) -> ArityDefPass<'a> {
+    ArityDefPass {
+      fstack: fstack,
+      arity: arity
+    }
+  }
+}
+
+impl<'a> Visitor for ArityDefPass<'a> {
+  fn enter_var(&mut self, node: &mut Node) {
+    let key = match resolve(self.fstack, &node.body[0]) {
+      Some((_, key)) => key,
+      None => return
+    };
+
+    let args = match node.body[1].type_ {
+      NodeType::Function if !uses_arguments(&node.body[1]) => Some(node.body[1].body[0].body.len()),
+      _ => None
+    };
+
+    let redeclared = self.arity.contains_key(&key);
+    self.arity.insert(key, if redeclared { None } else { args });
+  }
+
+  fn enter_assign(&mut self, node: &mut Node) {
+    if let Some((_, key)) = resolve(self.fstack, &node.body[0]) {
+      self.arity.insert(key, None);
+    }
+  }
+
+  fn enter_fun(&mut self, _node: &mut Node) {
+    self.fstack.enter();
+  }
+
+  fn exit_fun(&mut self, _node: &mut Node) {
+    self.fstack.exit();
+  }
+}
+
+struct ArityCallPass<'a> {
+  fstack: &'a mut FrameStackTree,
+  arity: &'a HashMap<VarKey, Option<usize>>,
+  warnings: &'a mut Vec<Diagnostic>
+}
+
+impl<'a> ArityCallPass<'a> {
+  fn new(fstack: &'a mut FrameStackTree,
+         arity: &'a HashMap<VarKey, Option<usize>>,
+         warnings: &'a mut Vec<Diagnostic>) -> ArityCallPass<'a> {
+    ArityCallPass {
+      fstack: fstack,
+      arity: arity,
+      warnings: warnings
+    }
+  }
+}
+
+impl<'a> Visitor for ArityCallPass<'a> {
+  fn enter_call(&mut self, node: &mut Node) {
+    let (name, key) = match resolve(self.fstack, &node.body[0]) {
+      Some(var) => var,
+      None => return
+    };
+
+    let given = node.body[1].body.len();
+
+    if let Some(&Some(expected)) = self.arity.get(&key) {
+      if expected != given {
+        let msg = format!("function '{}' expects {} argument(s), but {} given",
+                          name, expected, given);
+        self.warnings.push(Diagnostic::warning(msg, None));
+      }
+    }
+  }
+
+  fn enter_fun(&mut self, _node: &mut Node) {
+    self.fstack.enter();
+  }
+
+  fn exit_fun(&mut self, _node: &mut Node) {
+    self.fstack.exit();
+  }
+}
+
+struct CapturePass<'a> {
+  fstack: &'a mut FrameStackTree,
+  // number of parameters of each function frame
+  params: HashMap<usize, usize>,
+  // nodes are identified by their index in visiting (pre-)order
+  visited: usize,
+  // indices of symbol nodes which don't refer to variables (keys, labels, ...)
+  skip: HashSet<usize>,
+  captured: HashSet<VarKey>,
+  writes: Vec<(String, VarKey, Option<Position>)>
+}
+
+impl<'a> CapturePass<'a> {
+  fn is_param(&self, key: &VarKey) -> bool {
+    self.params.get(&key.0).map_or(false, |&n| key.1 < n)
+  }
+}
+
+impl<'a> Visitor for CapturePass<'a> {
+  fn visit(&mut self, node: &mut Node) {
+    let index = self.visited;
+    self.visited += 1;
+
+    let children = node.child_indices(index);
+    let names: Vec<usize> = match node.type_ {
+      NodeType::Member | NodeType::OptMember | NodeType::StmtLabel |
+      NodeType::StmtBreak | NodeType::StmtContinue => children.into_iter().take(1).collect(),
+      NodeType::Dict => children.into_iter().step_by(2).collect(),
+      NodeType::Function => node.body[0].child_indices(children[0]),
+      _ => vec![]
+    };
+
+    self.skip.extend(names);
+  }
+
+  // called before `visit`, so the node's index is the next one
+  fn enter_term(&mut self, node: &mut Node) {
+    if self.skip.contains(&self.visited) {
+      return;
+    }
+
+    if let NodeType::Symbol(ref name) = node.type_ {
+      if let Some(var) = self.fstack.find_var(name) {
+        let key = (var.frame_id, var.var_offset);
+        if var.frame_offset > 0 && self.is_param(&key) {
+          self.captured.insert(key);
+        }
+      }
+    }
+  }
+
+  fn enter_assign(&mut self, node: &mut Node) {
+    if let NodeType::Symbol(ref name) = node.body[0].type_ {
+      if let Some(var) = self.fstack.find_var(name) {
+        let key = (var.frame_id, var.var_offset);
+        if var.frame_offset == 0 && self.is_param(&key) {
+          self.writes.push((name.clone(), key, node.span.map(|s| s.start)));
+        }
+      }
+    }
+  }
+
+  fn enter_fun(&mut self, node: &mut Node) {
+    self.fstack.enter();
+    self.params.insert(self.fstack.cur_frame(), node.body[0].body.len());
+  }
+
+  fn exit_fun(&mut self, _node: &mut Node) {
+    self.fstack.exit();
+  }
+}
+
+struct EmptyBodyPass {
+  warnings: Vec<Diagnostic>
+}
+
+impl EmptyBodyPass {
+  fn check(&mut self, node: &Node, stmt: &str) {
+    if node.body[1].body.is_empty() {
+      let pos = node.span.map(|s| s.start);
+      self.warnings.push(Diagnostic::warning(format!("empty '{}' body", stmt), pos));
+    }
+  }
+}
+
+impl Visitor for EmptyBodyPass {
+  fn enter_while(&mut self, node: &mut Node) {
+    self.check(node, "while");
+  }
+
+  fn enter_if(&mut self, node: &mut Node) {
+    self.check(node, "if");
+  }
+}
+
+struct DuplicateKeyPass {
+  warnings: Vec<Diagnostic>
+}
+
+impl Visitor for DuplicateKeyPass {
+  fn visit(&mut self, node: &mut Node) {
+    if node.type_ != NodeType::Dict {
+      return;
+    }
+
+    let mut seen: HashMap<String, Option<Position>> = HashMap::new();
+
+    for key in node.body.iter().step_by(2) {
+      let name = match key.type_ {
+        NodeType::Symbol(ref name) | NodeType::String(ref name) => name.clone(),
+        NodeType::Number(n) => n.to_string(),
+        _ => continue
+      };
+      let pos = key.span.map(|s| s.start);
+
+      if let Some(&first) = seen.get(&name) {
+        let msg = match first {
+          Some(first) => format!("duplicate key '{}' in dict, first defined at {}:{}",
+                                 name, first.line, first.col),
+          None => format!("duplicate key '{}' in dict", name)
+        };
+        self.warnings.push(Diagnostic::warning(msg, pos));
+      } else {
+        seen.insert(name, pos);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokenizer::Tokenizer;
+  use parser::Parser;
+  use diagnostic::Position;
+
+  fn parse(text: &str) -> Node {
+    Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse()
+  }
+
+  fn warnings(text: &str) -> Vec<Diagnostic> {
+    check_arity(&mut parse(text))
+  }
+
+  #[test]
+  fn test_arity() {
+    let w = warnings("var f = fn(a, b) { return a + b; }; f(1);");
+    assert_eq!(w.len(), 1);
+    assert!(w[0].message.contains("'f' expects 2"));
+
+    assert!(warnings("var f = fn(a, b) { return a + b; }; f(1, 2);").is_empty());
+
+    // reassigned variables are dynamic
+    assert!(warnings("var f = fn(a, b) { return a; }; f = fn(a) { return a; }; f(1);").is_empty());
+
+    // calls from nested functions resolve to the outer definition
+    let w = warnings("var f = fn(a) { return a; }; var g = fn() { return f(); };");
+    assert_eq!(w.len(), 1);
+
+    // shadowing parameter is not the same variable
+    assert!(warnings("var f = fn(a) { return a; }; var g = fn(f) { return f(); };").is_empty());
+  }
+
+  #[test]
+  fn test_empty_bodies() {
+    let w = check_empty_bodies(&mut parse("var x = 1;\nwhile (x) {}"));
+    assert_eq!(w.len(), 1);
+    assert_eq!(w[0].message, "empty 'while' body");
+    assert_eq!(w[0].pos, Some(Position { line: 2, col: 0 }));
+
+    assert!(check_empty_bodies(&mut parse("while (x) { y; }")).is_empty());
+    assert_eq!(check_empty_bodies(&mut parse("if (x) {} else { y; }")).len(), 1);
+  }
+
+  #[test]
+  fn test_captured_writes() {
+    let text = "var f = fn(a, b) {
+      var g = fn() { return a + std.b; };
+      a = a + 1;
+      b = 2;
+      return g;
+    };";
+    let w = check_captured_writes(&mut parse(text));
+    assert_eq!(w.len(), 1);
+    assert!(w[0].message.contains("'a'"));
+    assert_eq!(w[0].pos.map(|p| p.line), Some(3));
+
+    // writes to locals and to parameters which aren't captured are fine
+    assert!(check_captured_writes(&mut parse("var f = fn(a) { a = 1; var b = 1; b = 2; \
+                                              return fn() { return b; }; };")).is_empty());
+
+    // keys after values with subtrees of their own are still keys
+    assert!(check_captured_writes(&mut parse("var f = fn(a, b) { var g = fn() { return {k: [a, a + 1], b: 1}; }; \
+                                              b = 2; return g; };")).is_empty());
+  }
+
+  #[test]
+  fn test_duplicate_keys() {
+    let w = check_duplicate_keys(&mut parse("var d = {a: 1,\n  a: 2};"));
+    assert_eq!(w.len(), 1);
+    assert_eq!(w[0].message, "duplicate key 'a' in dict, first defined at 1:9");
+    assert_eq!(w[0].pos, Some(Position { line: 2, col: 2 }));
+
+    assert!(check_duplicate_keys(&mut parse("var d = {a: 1, b: 2};")).is_empty());
+    assert_eq!(check_duplicate_keys(&mut parse("var d = {a: 1, 'a': 2, b: {a: 3}};")).len(), 1);
+  }
+}
@@ -2,27 +2,56 @@ use std::env;
 use std::io::prelude::*;
 use std::fs::File;
 use std::path::Path;
+use std::panic::{self, AssertUnwindSafe};
 
-extern crate byteorder;
 extern crate getopts;
+extern crate ecmascript_toy;
 
 use getopts::{Options, Matches};
 
-mod tokenizer;
-mod parser;
-mod syntax_tree;
-mod frame_stack;
-mod var_analyzer;
-mod assembler;
-mod util;
-mod compiler;
+use ecmascript_toy::tokenizer::Tokenizer;
+use ecmascript_toy::parser::Parser;
+use ecmascript_toy::pipeline::{self, CompileOptions};
+use ecmascript_toy::util::{self, GraphvizVisitor, JsonVisitor};
+use ecmascript_toy::vm::Vm;
+use ecmascript_toy::repl::Repl;
+use ecmascript_toy::sourcemap;
+use ecmascript_toy::disassembler;
+use ecmascript_toy::diagnostic;
+use ecmascript_toy::assembler::Encoding;
 
-use tokenizer::Tokenizer;
-use parser::Parser;
-use compiler::Compiler;
-use util::GraphvizVisitor;
+fn disasm(matches: &Matches) {
+  let mut bytecode = vec![];
+  File::open(Path::new(&matches.free[0]))
+    .unwrap()
+    .read_to_end(&mut bytecode).unwrap();
+
+  let text = disassembler::disassemble(&bytecode).join("\n") + "\n";
+
+  if let Some(path) = matches.opt_str("o") {
+    File::create(Path::new(&path)).unwrap().write_all(text.as_bytes()).unwrap()
+  } else {
+    print!("{}", text);
+  }
+}
+
+// The value of a numeric option, exits with a usage error if it isn't one
+fn number_opt(matches: &Matches, name: &str) -> Option<usize> {
+  matches.opt_str(name).map(|value| match value.parse() {
+    Ok(n) => n,
+    Err(_) => {
+      eprintln!("Wrong arguments: --{} expects a number, got '{}'", name, value);
+      std::process::exit(1);
+    }
+  })
+}
 
 fn process(matches: &Matches) {
+  if matches.opt_present("d") {
+    disasm(matches);
+    return;
+  }
+
   let source_path = matches.free[0].to_string();
 
   let mut text = String::new();
@@ -32,11 +61,19 @@ fn process(matches: &Matches) {
   
   let mut tokenizer = Tokenizer::new(&text);
 
-  let tokens = match &tokenizer.tokenize() {
-    &Ok(tokens) => tokens,
-    &Err(ref msg) => {
-      println!("Tokenizer error:\n{}", msg);
-      return;
+  let max_errors = number_opt(matches, "max-errors");
+
+  let tokens = match tokenizer.tokenize_all() {
+    Ok(tokens) => tokens,
+    Err(errors) => {
+      let errors = match max_errors {
+        Some(max) => diagnostic::limit(errors, max),
+        None => errors
+      };
+      for err in errors.iter() {
+        eprintln!("{}", err.clone().in_file(&source_path).format(&text));
+      }
+      std::process::exit(1);
     }
   };
 
@@ -56,43 +93,173 @@ fn process(matches: &Matches) {
     return;
   }
 
-  let mut parser = Parser::new(tokens);
-  let mut ast = parser.parse();
+  if let Some(mode) = matches.opt_str("emit") {
+    if mode != "highlight" {
+      println!("Unknown emit mode: {}", mode);
+      return;
+    }
 
-  if matches.opt_present("p") {
-    let mut graphviz = GraphvizVisitor::new();
-    
-    graphviz.begin();
-    ast.visit(&mut graphviz);
-    graphviz.end();
+    for t in tokens.iter() {
+      println!("{:>3},{:>3} {:<12} {}", t.line, t.col, format!("{:?}", t.category()), t.text);
+    }
 
-    let text = format!("// Source: {}\n{}", source_path, graphviz.text());
+    return;
+  }
+
+  if matches.opt_present("p") || matches.opt_present("j") || matches.opt_present("sexpr") || matches.opt_present("format") {
+    let mut parser = Parser::new(tokens);
+    parser.set_asi(matches.opt_present("asi"));
+
+    let mut ast = match parser.try_parse() {
+      Ok(ast) => ast,
+      Err(err) => {
+        eprintln!("{}", err.in_file(&source_path).format(&text));
+        std::process::exit(1);
+      }
+    };
+
+    let text = if matches.opt_present("format") {
+      util::format(&ast)
+    } else if matches.opt_present("sexpr") {
+      util::to_sexpr(&ast) + "\n"
+    } else if matches.opt_present("j") {
+      let mut json = JsonVisitor::new();
+      ast.visit(&mut json);
+      json.text()
+    } else {
+      let mut graphviz = GraphvizVisitor::new();
+
+      graphviz.begin();
+      ast.visit(&mut graphviz);
+      graphviz.end();
+
+      format!("// Source: {}\n{}\n", source_path, graphviz.text())
+    };
 
     if let Some(path) = matches.opt_str("o") {
       File::create(Path::new(&path)).unwrap().write_all(text.as_bytes()).unwrap()
     } else {
-      println!("{}", text);
+      print!("{}", text);
     }
 
     return;
   }
 
-  let bin_path = if let Some(path) = matches.opt_str("o") {
+  let asm_target = match matches.opt_str("target").as_ref().map(|t| t.as_str()) {
+    None | Some("bin") => false,
+    Some("asm") => true,
+    Some(target) => {
+      println!("Unknown target: {}", target);
+      return;
+    }
+  };
+
+  let out_path = if let Some(path) = matches.opt_str("o") {
     path
   } else {
     let stem = Path::new(&source_path).file_stem().unwrap();
-    stem.to_str().unwrap().to_string() + ".bin"
+    stem.to_str().unwrap().to_string() + if asm_target { ".s" } else { ".bin" }
   };
 
-  let asm_file = if let Some(asm_path) = matches.opt_str("s") {
-    Some(File::create(Path::new(&asm_path)).unwrap())
-  } else {
-    None
+  let encoding = match matches.opt_str("encoding") {
+    None => Encoding::default(),
+    Some(name) => match Encoding::from_name(&name) {
+      Some(encoding) => encoding,
+      None => {
+        println!("Unknown encoding: {}", name);
+        return;
+      }
+    }
   };
+
+  let asm_path = if asm_target { Some(out_path.clone()) } else { matches.opt_str("s") };
+  let asm_file = asm_path.map(|path| File::create(Path::new(&path)).unwrap());
   
-  let mut f = File::create(bin_path).unwrap();
-  let mut compiler = Compiler::new(&mut f, asm_file);
-  compiler.compile(&mut ast);
+  let options = CompileOptions {
+    warnings: matches.opt_present("W"),
+    warn_empty_bodies: matches.opt_present("warn-empty-bodies"),
+    strict: matches.opt_present("strict"),
+    max_token_len: number_opt(matches, "max-token-len"),
+    asi: matches.opt_present("asi"),
+    optimize: matches.opt_present("O"),
+    max_errors: max_errors,
+    encoding: encoding
+  };
+
+  let result = match pipeline::compile_file(Path::new(&source_path), &options, asm_file) {
+    Ok(result) => result,
+    Err(errors) => {
+      for err in errors.iter() {
+        eprintln!("{}", err.format(&text));
+      }
+      std::process::exit(1);
+    }
+  };
+
+  for warning in result.warnings.iter() {
+    println!("{}", warning.format(&text));
+  }
+
+  if !asm_target {
+    File::create(&out_path).unwrap().write_all(&result.bytecode).unwrap();
+  }
+
+  if matches.opt_present("g") {
+    let dbg_path = Path::new(&out_path).with_extension("dbg");
+    let table = sourcemap::line_table(&result.source_map);
+    File::create(&dbg_path).unwrap()
+      .write_all(sourcemap::line_table_text(&table).as_bytes()).unwrap();
+  }
+
+  if let Some(map_path) = matches.opt_str("sourcemap") {
+    File::create(Path::new(&map_path)).unwrap()
+      .write_all(sourcemap::to_json(&result.source_map).as_bytes()).unwrap();
+  }
+
+  if matches.opt_present("r") || matches.opt_present("debug-run") {
+    let mut vm = Vm::new(result.bytecode);
+
+    let run = if matches.opt_present("debug-run") {
+      disassembler::debug_run(&mut vm, &result.source_map, &mut std::io::stdout())
+    } else {
+      vm.run()
+    };
+
+    if let Err(err) = run {
+      std::io::stdout().flush().unwrap();
+      eprintln!("Runtime error:\n{}", err);
+      std::process::exit(1);
+    } else if let Some(code) = vm.exit_code() {
+      std::io::stdout().flush().unwrap();
+      std::process::exit(code);
+    }
+  }
+}
+
+fn interactive() {
+  let mut repl = Repl::new();
+  let stdin = std::io::stdin();
+
+  loop {
+    print!("> ");
+    std::io::stdout().flush().unwrap();
+
+    let mut line = String::new();
+    if stdin.read_line(&mut line).unwrap() == 0 {
+      break;
+    }
+
+    // keep the session alive after an internal error
+    match panic::catch_unwind(AssertUnwindSafe(|| repl.eval(&line))) {
+      Ok(Ok(output)) => print!("{}", output),
+      Ok(Err(err)) => println!("{}", err),
+      Err(_) => {}
+    }
+
+    if repl.exited() {
+      break;
+    }
+  }
 }
 
 fn main() {
@@ -100,11 +267,30 @@ fn main() {
 
   let mut opts = Options::new();
   opts.optflag("c", "compile", "compile source file");
+  opts.optflag("r", "run", "compile and run source file");
+  opts.optflag("", "debug-run", "run printing each instruction, the stack and source lines");
+  opts.optflag("d", "disasm", "disassemble a compiled file");
   opts.optflag("p", "parse", "parse source file to AST");
+  opts.optflag("j", "json", "parse source file to AST in JSON");
+  opts.optflag("", "sexpr", "parse source file to AST as an S-expression");
+  opts.optflag("", "format", "parse source file and print it formatted");
   opts.optflag("t", "tokenize", "tokenize source file");
+  opts.optflag("i", "interactive", "start an interactive session");
+  opts.optflag("W", "warnings", "report compile-time warnings");
+  opts.optflag("", "warn-empty-bodies", "with -W, also warn about empty loop and if bodies");
+  opts.optflag("", "strict", "warn about reassigned parameters captured by closures");
+  opts.optflag("", "asi", "allow statements to end at a line break without ';'");
+  opts.optflag("O", "optimize", "fold constant expressions, reuse repeated subexpressions and drop redundant instructions");
+  opts.optflag("g", "debug-info", "write the offsets of source lines to a .dbg file");
   opts.optflag("h", "help", "show usage");
   opts.optopt("o", "output", "output file", "OUT_FILE");
   opts.optopt("s", "assembly", "assembly output file", "ASM_OUT_FILE");
+  opts.optopt("", "emit", "print tokens with their categories (highlight)", "MODE");
+  opts.optopt("", "sourcemap", "source map output file (JSON)", "MAP_FILE");
+  opts.optopt("", "target", "output format: bin (default) or asm", "TARGET");
+  opts.optopt("", "encoding", "bytecode encoding: le32 (default), be32, le16 or be16", "ENC");
+  opts.optopt("", "max-token-len", "maximum length of a token", "LEN");
+  opts.optopt("", "max-errors", "stop reporting errors after N of them", "N");
 
   let brief = format!("Usage: {} FILE [options]", &args[0]);
 
@@ -122,6 +308,11 @@ fn main() {
     return;
   }
 
+  if matches.opt_present("i") {
+    interactive();
+    return;
+  }
+
   if matches.free.len() == 0 {
       print!("{}", opts.usage(&brief));
       println!("\nWrong arguments: source file not specified");
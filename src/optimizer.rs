@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+
+use syntax_tree::{Node, NodeType, OpType, TransformVisitor};
+
+// Evaluates constant subexpressions at compile time: arithmetic and
+// comparisons of numbers (`2 + 3 * 4`, `1 < 2`), string concatenation and
+// equality (`'a' + 'b'`, `'a' == 'b'`) and lengths of literals (`'abc'.length`)
+pub fn fold_constants(ast: &mut Node) {
+  ast.transform(&mut FoldPass);
+}
+
+struct FoldPass;
+
+impl FoldPass {
+  fn replace(node: &Node, type_: NodeType) -> Option<Node> {
+    let mut folded = Node::new(type_);
+    folded.span = node.span;
+    Some(folded)
+  }
+}
+
+// Length of a literal as `.length` yields it at runtime, strings count characters.
+// Array elements are dropped by folding, so only literal elements are allowed.
+fn literal_length(node: &Node) -> Option<usize> {
+  match node.type_ {
+    NodeType::String(ref s) => Some(s.chars().count()),
+    NodeType::Array if node.body.iter().all(is_literal) => Some(node.body.len()),
+    _ => None
+  }
+}
+
+// Value of an arithmetic or logical operator on numbers, computed in f32 as
+// the VM does. Division by zero yields no literal, the compiler pushes its value.
+fn fold_numbers(op: OpType, a: f32, b: f32) -> Option<f32> {
+  match op {
+    OpType::OpPlus => Some(a + b),
+    OpType::OpMinus => Some(a - b),
+    OpType::OpMul => Some(a * b),
+    OpType::OpDiv | OpType::OpMod if b == 0.0 => None,
+    OpType::OpDiv => Some(a / b),
+    OpType::OpMod => Some(a % b),
+    // the deciding operand, as in the VM
+    OpType::OpAnd => Some(if a != 0.0 && !a.is_nan() { b } else { a }),
+    OpType::OpOr => Some(if a != 0.0 && !a.is_nan() { a } else { b }),
+    _ => None
+  }
+}
+
+// Result of a comparison of numbers, false for NaN except for `!=`
+fn compare_numbers(op: OpType, a: f32, b: f32) -> Option<bool> {
+  match op {
+    OpType::OpLs => Some(a < b),
+    OpType::OpGt => Some(a > b),
+    OpType::OpLsEq => Some(a <= b),
+    OpType::OpGtEq => Some(a >= b),
+    OpType::OpEq => Some(a == b),
+    OpType::OpNotEq => Some(a != b),
+    _ => None
+  }
+}
+
+fn is_literal(node: &Node) -> bool {
+  match node.type_ {
+    NodeType::Number(_) | NodeType::String(_) => true,
+    _ => false
+  }
+}
+
+impl TransformVisitor for FoldPass {
+  fn transform_expr(&mut self, node: &mut Node) -> Option<Node> {
+    let op = match node.type_ {
+      NodeType::Op(op) => op,
+      _ => return None
+    };
+
+    if node.body.len() == 1 {
+      return match (op, &node.body[0].type_) {
+        (OpType::OpMinus, &NodeType::Number(a)) => FoldPass::replace(node, NodeType::Number(-a)),
+        _ => None
+      };
+    }
+
+    if node.body.len() != 2 {
+      return None;
+    }
+
+    match (op, &node.body[0].type_, &node.body[1].type_) {
+      (OpType::OpPlus, &NodeType::String(ref a), &NodeType::String(ref b)) =>
+        FoldPass::replace(node, NodeType::String(a.clone() + b)),
+      // the VM compares strings by their characters
+      (OpType::OpEq, &NodeType::String(ref a), &NodeType::String(ref b)) =>
+        FoldPass::replace(node, NodeType::Boolean(a == b)),
+      (OpType::OpNotEq, &NodeType::String(ref a), &NodeType::String(ref b)) =>
+        FoldPass::replace(node, NodeType::Boolean(a != b)),
+      (_, &NodeType::Number(a), &NodeType::Number(b)) => match compare_numbers(op, a, b) {
+        Some(value) => FoldPass::replace(node, NodeType::Boolean(value)),
+        None => fold_numbers(op, a, b).and_then(|n| FoldPass::replace(node, NodeType::Number(n)))
+      },
+      _ => None
+    }
+  }
+
+  fn transform(&mut self, node: &mut Node) -> Option<Node> {
+    match (&node.type_, &node.body.get(0).map(|n| &n.type_)) {
+      (&NodeType::Member, &Some(&NodeType::Symbol(ref key))) |
+      (&NodeType::Index, &Some(&NodeType::String(ref key))) if key == "length" =>
+        literal_length(&node.body[1]).and_then(|n| FoldPass::replace(node, NodeType::Number(n as f32))),
+      _ => None
+    }
+  }
+}
+
+// Subexpressions occurring more than once in the expression, outermost
+// first, which the compiler may evaluate once and reuse. Nothing is returned
+// for expressions with side effects or values which must not be shared:
+// calls, assignments, short-circuit operators and new objects.
+pub fn common_subexpressions(expr: &Node) -> Vec<Node> {
+  if !is_pure(expr) {
+    return vec![];
+  }
+
+  let mut counts = HashMap::new();
+  count_subtrees(expr, &mut counts);
+
+  let mut candidates: HashSet<&Node> = counts.into_iter()
+    .filter(|&(node, count)| count > 1 && is_reusable(node))
+    .map(|(node, _)| node)
+    .collect();
+
+  // occurrences inside a reused expression don't count, drop the
+  // candidates which are left with a single use until nothing changes
+  loop {
+    let mut uses = vec![];
+    collect_uses(expr, &candidates, &mut uses);
+
+    let reused: HashSet<&Node> = candidates.iter().cloned()
+      .filter(|c| uses.iter().filter(|&u| u == c).count() > 1)
+      .collect();
+
+    if reused.len() == candidates.len() {
+      let mut result: Vec<Node> = vec![];
+      for node in uses {
+        if !result.contains(node) {
+          result.push(node.clone());
+        }
+      }
+      return result;
+    }
+
+    candidates = reused;
+  }
+}
+
+fn is_pure(node: &Node) -> bool {
+  let pure = match node.type_ {
+    NodeType::Call | NodeType::Assign | NodeType::Update { .. } | NodeType::Function | NodeType::BlockExpr |
+    NodeType::OptMember | NodeType::Op(OpType::OpNullish) |
+    NodeType::Op(OpType::OpAnd) | NodeType::Op(OpType::OpOr) | NodeType::Ternary |
+    NodeType::Dict | NodeType::Array => false,
+    _ => true
+  };
+
+  pure && node.body.iter().all(is_pure)
+}
+
+fn is_reusable(node: &Node) -> bool {
+  match node.type_ {
+    NodeType::Member | NodeType::Index | NodeType::Op(_) => true,
+    _ => false
+  }
+}
+
+fn count_subtrees<'a>(node: &'a Node, counts: &mut HashMap<&'a Node, usize>) {
+  *counts.entry(node).or_insert(0) += 1;
+
+  for child in node.body.iter() {
+    count_subtrees(child, counts);
+  }
+}
+
+fn collect_uses<'a>(node: &'a Node, candidates: &HashSet<&Node>, uses: &mut Vec<&'a Node>) {
+  if candidates.contains(node) {
+    uses.push(node);
+    return;
+  }
+
+  for child in node.body.iter() {
+    collect_uses(child, candidates, uses);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokenizer::Tokenizer;
+  use parser::Parser;
+
+  fn fold(text: &str) -> Node {
+    let mut ast = Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse();
+    fold_constants(&mut ast);
+    ast.body.remove(0).body.remove(1)
+  }
+
+  #[test]
+  fn test_fold_strings() {
+    assert_eq!(fold("var a = 'a' + 'b' + 'c';").type_, NodeType::String("abc".to_string()));
+    assert_eq!(fold("var a = 'a' + 1;").type_, NodeType::Op(OpType::OpPlus));
+    assert_eq!(fold("var a = 'a' + b;").type_, NodeType::Op(OpType::OpPlus));
+
+    assert_eq!(fold("var a = 'abc' == 'a' + 'bc';").type_, NodeType::Boolean(true));
+    assert_eq!(fold("var a = 'abc' != 'abc';").type_, NodeType::Boolean(false));
+    assert_eq!(fold("var a = 'a' == b;").type_, NodeType::Op(OpType::OpEq));
+    assert_eq!(fold("var a = 'a' < 'b';").type_, NodeType::Op(OpType::OpLs));
+  }
+
+  #[test]
+  fn test_fold_numbers() {
+    assert_eq!(fold("var a = 2 + 3 * 4;").type_, NodeType::Number(14.0));
+    assert_eq!(fold("var a = (10 - 4) / 4;").type_, NodeType::Number(1.5));
+    assert_eq!(fold("var a = 7 % 4;").type_, NodeType::Number(3.0));
+    assert_eq!(fold("var a = 2 * -3;").type_, NodeType::Number(-6.0));
+    assert_eq!(fold("var a = 0 && 5;").type_, NodeType::Number(0.0));
+    assert_eq!(fold("var a = 0 || 5;").type_, NodeType::Number(5.0));
+    assert_eq!(fold("var a = 1 / 0;").type_, NodeType::Op(OpType::OpDiv));
+    assert_eq!(fold("var a = 1 % (2 - 2);").type_, NodeType::Op(OpType::OpMod));
+    assert_eq!(fold("var a = 1 < 2;").type_, NodeType::Boolean(true));
+    assert_eq!(fold("var a = 2 * 2 <= 3;").type_, NodeType::Boolean(false));
+    assert_eq!(fold("var a = 1 == 1.0;").type_, NodeType::Boolean(true));
+    assert_eq!(fold("var a = (0 % 1) != -0;").type_, NodeType::Boolean(false));
+    assert_eq!(fold("var a = 1 + b * 2;").type_, NodeType::Op(OpType::OpPlus));
+    assert_eq!(fold("var a = 1 + '2';").type_, NodeType::Op(OpType::OpPlus));
+  }
+
+  #[test]
+  fn test_fold_length() {
+    assert_eq!(fold("var a = 'abc'.length;").type_, NodeType::Number(3.0));
+    assert_eq!(fold("var a = ('ab' + 'cd').length;").type_, NodeType::Number(4.0));
+    assert_eq!(fold("var a = 'привет'.length;").type_, NodeType::Number(6.0));
+    assert_eq!(fold("var a = [1, 'x'].length;").type_, NodeType::Number(2.0));
+    assert_eq!(fold("var a = 'abc'['length'];").type_, NodeType::Number(3.0));
+    assert_eq!(fold("var a = [f()].length;").type_, NodeType::Member);
+    assert_eq!(fold("var a = 'abc'.size;").type_, NodeType::Member);
+  }
+
+  #[test]
+  fn test_common_subexpressions() {
+    let common = |text: &str| {
+      let mut ast = Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse();
+      common_subexpressions(&ast.body.remove(0).body.remove(1)).len()
+    };
+
+    assert_eq!(common("var x = a[i] * a[i];"), 1);
+    assert_eq!(common("var x = a[i] * a[i] + a[i] * a[i];"), 1);
+    assert_eq!(common("var x = a[i] * a[i] + a[i] * a[i] + a[i];"), 1);
+    assert_eq!(common("var x = a[i] * a[i] + a[i] * a[i] + a[i] + a[i];"), 2);
+    assert_eq!(common("var x = a.b + a.c + (a.b + 1);"), 1);
+    assert_eq!(common("var x = a[i] + a[j];"), 0);
+    assert_eq!(common("var x = a[f()] + a[f()];"), 0);
+    assert_eq!(common("var x = [1].length + [1].length;"), 0);
+    assert_eq!(common("var x = a && a.b + a.b;"), 0);
+    assert_eq!(common("var x = a ? a.b + a.b : 0;"), 0);
+  }
+}
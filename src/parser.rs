@@ -1,38 +1,165 @@
-use std::collections::LinkedList;
-
 use tokenizer::Token;
 use tokenizer::TokenType;
+use tokenizer::{Keyword, TokenCategory};
+use tokenizer::{number_value, string_value};
 use syntax_tree::Node;
 use syntax_tree::NodeType;
 use syntax_tree::OpType;
+use diagnostic::{Diagnostic, Position};
+
+// Compound assignment operators, `a op= b` is parsed as `a = a op b`
+const ASSIGN_OPS: [(TokenType, OpType); 10] = [
+  (TokenType::AssignPlus,   OpType::OpPlus),
+  (TokenType::AssignMinus,  OpType::OpMinus),
+  (TokenType::AssignMul,    OpType::OpMul),
+  (TokenType::AssignDiv,    OpType::OpDiv),
+  (TokenType::AssignMod,    OpType::OpMod),
+  (TokenType::AssignBitAnd, OpType::OpBitAnd),
+  (TokenType::AssignBitOr,  OpType::OpBitOr),
+  (TokenType::AssignBitXor, OpType::OpBitXor),
+  (TokenType::AssignShl,    OpType::OpShl),
+  (TokenType::AssignShr,    OpType::OpShr),
+];
 
 pub struct Parser<'a> {
-  stream: LinkedList<Token<'a>>,
+  // tokens are read from `source` on demand
+  source: Box<dyn Iterator<Item = Token<'a>> + 'a>,
+  tokens: Vec<Token<'a>>,
+  // index of the token following the current one
+  next: usize,
   token: Token<'a>,
-  prev_token: Token<'a>
+  prev_token: Token<'a>,
+  // automatic semicolon insertion
+  asi: bool
 }
 
 impl<'a> Parser<'a> {
-  pub fn new(tokens: &LinkedList<Token<'a>>) -> Parser<'a> {
+  pub fn new<I>(tokens: I) -> Parser<'a> where I: IntoIterator<Item = Token<'a>>, I::IntoIter: 'a {
     Parser {
-      stream: tokens.clone(),
+      source: Box::new(tokens.into_iter()),
+      tokens: vec![],
+      next: 0,
       token: Token::new_empty(),
-      prev_token: Token::new_empty()
+      prev_token: Token::new_empty(),
+      asi: false
     }
   }
 
+  // Lets statements end without `;` at a line break, before `}` or at the
+  // end of the text, as in JS. `return` followed by a line break returns
+  // no value.
+  pub fn set_asi(&mut self, enabled: bool) {
+    self.asi = enabled;
+  }
+
+  // Panics on syntax errors, see `try_parse`
   pub fn parse(&mut self) -> Node {
+    match self.parse_program() {
+      Ok(ast) => ast,
+      Err(err) => panic!("{}", err)
+    }
+  }
+
+  pub fn try_parse(&mut self) -> Result<Node, Diagnostic> {
     self.parse_program()
   }
 
-  fn parse_fun(&mut self, parent: &mut Node) {
+  // Parses the program one top-level statement at a time, a braced block
+  // yields all of its statements in a Block. Function declarations are only
+  // hoisted within the block. Returns None at the end of the program.
+  // The tokens of the statement are dropped once it is parsed.
+  pub fn parse_next(&mut self) -> Result<Option<Node>, Diagnostic> {
+    if self.token.type_ == TokenType::Empty {
+      self.token_next();
+    }
+
+    if self.token.type_ == TokenType::Eof {
+      return Ok(None);
+    }
+
+    let mut block = self.node_create(NodeType::Block);
+    self.parse_block(&mut block)?;
+
+    // the previous and the current token are kept for `token_revert`
+    if self.next > 2 {
+      let consumed = self.next - 2;
+      self.tokens.drain(..consumed);
+      self.next -= consumed;
+    }
+
+    Ok(Some(block))
+  }
+
+  // Parses the whole token stream as a single expression with an optional
+  // trailing `;`. Returns None if any tokens are left, e.g. for statements.
+  pub fn parse_single_expression(&mut self) -> Option<Node> {
+    self.token_next();
+
+    let mut node = self.node_create(NodeType::Empty);
+    if self.parse_condition(&mut node).is_err() {
+      return None;
+    }
+    self.token_accept(&TokenType::End);
+
+    if self.token.type_ == TokenType::Eof {
+      node.body.pop()
+    } else {
+      None
+    }
+  }
+
+  fn parse_fun(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
     let mut node = self.node_create(NodeType::Function);
     let mut args = self.node_create(NodeType::Block);
     let mut body = self.node_create(NodeType::Block);
     
+    // skip the keyword (or the name of a declared function)
     self.token_next();
+
+    self.parse_fun_args(&mut args)?;
+    self.parse_block(&mut body)?;
     
-    self.token_expect(&TokenType::LPar);
+    node.body.push(args);
+    node.body.push(body);
+    parent.body.push(node);
+
+    Ok(())
+  }
+
+  // `x => expr`, `(a, b) => expr` or `(a, b) => { ... }`, an expression body
+  // is returned
+  fn parse_arrow(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    let mut node = self.node_create(NodeType::Function);
+    let mut args = self.node_create(NodeType::Block);
+    let mut body = self.node_create(NodeType::Block);
+
+    if self.token.type_ == TokenType::Sym {
+      args.body.push(self.node_create(NodeType::Symbol(self.token.text.to_string())));
+      self.token_next();
+    } else {
+      self.parse_fun_args(&mut args)?;
+    }
+
+    self.token_expect(&TokenType::Arrow)?;
+
+    if self.token.type_ == TokenType::LBlock {
+      self.parse_block(&mut body)?;
+    } else {
+      let mut ret = self.node_create(NodeType::StmtReturn);
+      self.parse_condition(&mut ret)?;
+      body.body.push(ret);
+    }
+
+    node.body.push(args);
+    node.body.push(body);
+    parent.body.push(node);
+
+    Ok(())
+  }
+
+  // `(a, b, ...)`
+  fn parse_fun_args(&mut self, args: &mut Node) -> Result<(), Diagnostic> {
+    self.token_expect(&TokenType::LPar)?;
     
     if self.token.type_ != TokenType::RPar {
       loop {
@@ -40,138 +167,201 @@ impl<'a> Parser<'a> {
           args.body.push(self.node_create(NodeType::Symbol(self.token.text.to_string())));
           self.token_next();
         } else {
-          self.die("function argument", &self.token);
+          return Err(self.die("function argument", &self.token));
         };
         
         if !self.token_accept(&TokenType::Comma) { break; }
       } 
     }
     
-    self.token_expect(&TokenType::RPar);
-    self.parse_block(&mut body);
-    
-    node.body.push(args);
-    node.body.push(body);
-    parent.body.push(node);
+    self.token_expect(&TokenType::RPar)
   }
 
-  fn parse_factor(&mut self, parent: &mut Node) {
-    if self.token.type_ == TokenType::Sym {
-      let s = self.token.text;
+  fn parse_factor(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    if self.token_starts_arrow() {
+      self.parse_arrow(parent)?;
+    }
+    else if self.token.type_ == TokenType::Sym {
+      let sym = self.node_create(NodeType::Symbol(self.token.text.to_string()));
       self.token_next();
-
-      if s == "fn" || s == "function" {
-        self.token_revert();
-        self.parse_fun(parent);
-      }
-      else {
-        let sym = self.node_create(NodeType::Symbol(s.to_string()));
-        parent.body.push(sym);
-      }
+      parent.body.push(sym);
+    }
+    else if self.token.is_keyword(Keyword::Fn) || self.token.is_keyword(Keyword::Function) {
+      self.parse_fun(parent)?;
     }
     else if self.token.type_ == TokenType::Num {
-      let x = self.token.text;
+      let node = self.node_create(NodeType::Number(number_value(self.token.text)));
+      self.token_next();
+      parent.body.push(node);
+    }
+    else if self.token.is_keyword(Keyword::Null) || self.token.is_keyword(Keyword::Undefined) {
+      let type_ = if self.token.is_keyword(Keyword::Null) { NodeType::Null } else { NodeType::Undefined };
+      let node = self.node_create(type_);
       self.token_next();
-
-      let node = self.node_create(NodeType::Number(x.parse::<f32>().unwrap()));
       parent.body.push(node);
     }
     else if self.token.type_ == TokenType::Str {
       let x = self.token.text;
       self.token_next();
 
-      let string = x.trim_matches('\'').to_string();
+      let string = string_value(x);
       let node = self.node_create(NodeType::String(string));
       parent.body.push(node);
     }
     else if self.token.type_ == TokenType::LPar {
       self.token_next();
-      self.parse_condition(parent);
-      self.token_expect(&TokenType::RPar);
+      self.parse_condition(parent)?;
+      self.token_expect(&TokenType::RPar)?;
     }
     else if self.token.type_ == TokenType::LBr {
       self.token_next();
       let mut node = self.node_create(NodeType::Array);
       if self.token.type_ != TokenType::RBr {
-        self.parse_list(&mut node);
+        self.parse_list(&mut node)?;
       }
       parent.body.push(node);
-      self.token_expect(&TokenType::RBr);
+      self.token_expect(&TokenType::RBr)?;
+    }
+    else if self.token.type_ == TokenType::LBlock && !self.token_starts_dict() {
+      self.parse_block_expr(parent)?;
     }
     else if self.token.type_ == TokenType::LBlock {
       self.token_next();
       let mut node = self.node_create(NodeType::Dict);
       if self.token.type_ != TokenType::RBlock {
-        self.parse_dict(&mut node);
+        self.parse_dict(&mut node)?;
       }
       parent.body.push(node);
-      self.token_expect(&TokenType::RBlock);
+      self.token_expect(&TokenType::RBlock)?;
     }
     else {
-      self.die("function call or expression", &self.token);
+      return Err(self.die("function call or expression", &self.token));
     }
+
+    Ok(())
   }
 
-  fn parse_unary(&mut self, parent: &mut Node) {
+  fn parse_unary(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
     let node = match self.token.type_ {
       TokenType::OpPlus  => Some(self.node_create(NodeType::Op(OpType::OpPlus))),
       TokenType::OpMinus => Some(self.node_create(NodeType::Op(OpType::OpMinus))),
       TokenType::OpNot   => Some(self.node_create(NodeType::Op(OpType::OpNot))),
+      TokenType::OpBitNot => Some(self.node_create(NodeType::Op(OpType::OpBitNot))),
+      TokenType::Keyword(Keyword::Void) => Some(self.node_create(NodeType::Op(OpType::OpVoid))),
+      TokenType::Keyword(Keyword::Typeof) => Some(self.node_create(NodeType::Op(OpType::OpTypeof))),
       _ => None
     };
 
     if let Some(mut n) = node {
       self.token_next();
-      self.parse_unary(&mut n);
+      self.parse_unary(&mut n)?;
       parent.body.push(n);
+    } else if let Some(op) = self.token_update_op() {
+      let mut node = self.node_create(NodeType::Update { op: op, postfix: false });
+      let token = self.token.clone();
+      self.token_next();
+
+      self.parse_unary(&mut node)?;
+      if !Parser::is_update_target(&node.body[0]) {
+        return Err(self.error(format!("Invalid operand of '{}'", token.text), &token));
+      }
+
+      parent.body.push(node);
     } else {
-      self.parse_call(parent);
+      self.parse_call(parent)?;
+    }
+
+    Ok(())
+  }
+
+  // `++` adds one, `--` subtracts it
+  fn token_update_op(&self) -> Option<OpType> {
+    match self.token.type_ {
+      TokenType::OpInc => Some(OpType::OpPlus),
+      TokenType::OpDec => Some(OpType::OpMinus),
+      _ => None
+    }
+  }
+
+  fn is_update_target(node: &Node) -> bool {
+    match node.type_ {
+      NodeType::Symbol(_) | NodeType::Member | NodeType::Index => true,
+      _ => false
     }
   }
 
-  fn parse_list(&mut self, parent: &mut Node) {
-    self.parse_condition(parent);
+  fn parse_list(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    self.parse_condition(parent)?;
 
     while self.token_accept(&TokenType::Comma) {
-      self.parse_condition(parent);
+      self.parse_condition(parent)?;
     }
+
+    Ok(())
   }
 
-  fn parse_pair(&mut self, parent: &mut Node) {
-    if self.token.type_ == TokenType::Num  {
-      parent.body.push(self.node_create(NodeType::Number(self.token.text.parse::<f32>().unwrap())));
-    } else if self.token.type_ == TokenType::Sym {
+  fn parse_pair(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    // method shorthand, `name(args) { ... }` is `name: fn(args) { ... }`
+    if self.token.type_ == TokenType::Sym && self.token_peek(&TokenType::LPar) {
+      parent.body.push(self.node_create(NodeType::Symbol(self.token.text.to_string())));
+      return self.parse_fun(parent);
+    }
+
+    // property shorthand, `{x}` is `{x: x}`
+    if self.token.type_ == TokenType::Sym && (self.token_peek(&TokenType::Comma) || self.token_peek(&TokenType::RBlock)) {
+      let key = self.node_create(NodeType::Symbol(self.token.text.to_string()));
+      parent.body.push(key.clone());
+      parent.body.push(key);
+      self.token_next();
+      return Ok(());
+    }
+
+    if self.token.type_ == TokenType::LBr {
+      let mut key = self.node_create(NodeType::ComputedKey);
+      self.token_next();
+      self.parse_condition(&mut key)?;
+      if self.token.type_ != TokenType::RBr {
+        return Err(self.die("']'", &self.token));
+      }
+      parent.body.push(key);
+    } else if self.token.type_ == TokenType::Num  {
+      parent.body.push(self.node_create(NodeType::Number(number_value(self.token.text))));
+    } else if self.token.type_ == TokenType::Sym || self.token.category() == TokenCategory::Keyword {
       parent.body.push(self.node_create(NodeType::Symbol(self.token.text.to_string())));
     } else if self.token.type_ == TokenType::Str {
-      let string = self.token.text.trim_matches('\'').to_string();
+      let string = string_value(self.token.text);
       parent.body.push(self.node_create(NodeType::String(string)));
     } else {
-      self.die("symbol or number", &self.token);
+      return Err(self.die("symbol or number", &self.token));
     }
 
     self.token_next();
-    self.token_expect(&TokenType::Colon);
+    self.token_expect(&TokenType::Colon)?;
 
-    self.parse_condition(parent);
+    self.parse_condition(parent)?;
+
+    Ok(())
   }
   
-  fn parse_dict(&mut self, parent: &mut Node) {
-    self.parse_pair(parent);
+  fn parse_dict(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    self.parse_pair(parent)?;
 
     while self.token_accept(&TokenType::Comma) {
-      self.parse_pair(parent);
+      self.parse_pair(parent)?;
     }
+
+    Ok(())
   }
 
-  fn parse_accessor(&mut self, parent: &mut Node) {
+  fn parse_accessor(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
     let mut node = self.node_create(NodeType::Empty);
-    self.parse_factor(&mut node);
+    self.parse_factor(&mut node)?;
 
     loop {
       if self.token_accept(&TokenType::LBr) {
         let mut member = self.node_create(NodeType::Index);
 
-        self.parse_condition(&mut member);
+        self.parse_condition(&mut member)?;
 
         if node.type_ == NodeType::Empty {
           member.body.append(&mut node.body);
@@ -179,11 +369,18 @@ impl<'a> Parser<'a> {
           member.body.push(node);
         }
 
-        self.token_expect(&TokenType::RBr);
+        self.token_expect(&TokenType::RBr)?;
         node = member;
-      } else if self.token_accept(&TokenType::Dot) {
-        if self.token.type_ == TokenType::Sym {
-          let mut member = self.node_create(NodeType::Member);
+      } else if self.token.type_ == TokenType::Dot || self.token.type_ == TokenType::OptDot {
+        let type_ = if self.token.type_ == TokenType::Dot {
+          NodeType::Member
+        } else {
+          NodeType::OptMember
+        };
+        self.token_next();
+
+        if self.token.type_ == TokenType::Sym || self.token.category() == TokenCategory::Keyword {
+          let mut member = self.node_create(type_);
           let sym_node = self.node_create(NodeType::Symbol(self.token.text.to_string()));
           member.body.push(sym_node);
 
@@ -196,7 +393,7 @@ impl<'a> Parser<'a> {
           node = member;
           self.token_next();
         } else {
-          self.die("symbol", &self.token);
+          return Err(self.die("symbol", &self.token));
         }
       } else {
         break;
@@ -208,11 +405,13 @@ impl<'a> Parser<'a> {
     } else {
       parent.body.push(node);
     }
+
+    Ok(())
   }
   
-  fn parse_call(&mut self, parent: &mut Node) {
+  fn parse_call(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
     let mut node = self.node_create(NodeType::Empty);
-    self.parse_accessor(&mut node);
+    self.parse_accessor(&mut node)?;
 
     loop {
       if self.token_accept(&TokenType::LPar) {
@@ -225,15 +424,22 @@ impl<'a> Parser<'a> {
 
         let mut args = self.node_create(NodeType::Block);
         if self.token.type_ != TokenType::RPar {
-          self.parse_list(&mut args);
+          self.parse_list(&mut args)?;
         }
         call.body.push(args);
 
         node = call;
-        self.token_expect(&TokenType::RPar);
-      } else if self.token_accept(&TokenType::Dot) {
-        if self.token.type_ == TokenType::Sym {
-          let mut member = self.node_create(NodeType::Member);
+        self.token_expect(&TokenType::RPar)?;
+      } else if self.token.type_ == TokenType::Dot || self.token.type_ == TokenType::OptDot {
+        let type_ = if self.token.type_ == TokenType::Dot {
+          NodeType::Member
+        } else {
+          NodeType::OptMember
+        };
+        self.token_next();
+
+        if self.token.type_ == TokenType::Sym || self.token.category() == TokenCategory::Keyword {
+          let mut member = self.node_create(type_);
           let sym_node = self.node_create(NodeType::Symbol(self.token.text.to_string()));
           member.body.push(sym_node);
 
@@ -246,8 +452,20 @@ impl<'a> Parser<'a> {
           node = member;
           self.token_next();
         } else {
-          self.die("symbol", &self.token);
+          return Err(self.die("symbol", &self.token));
+        }
+      } else if self.token_accept(&TokenType::OpNot) {
+        // postfix non-null assertion (x!) is a no-op
+      } else if let Some(op) = self.token_update_op() {
+        let target = if node.type_ == NodeType::Empty { node.body.pop().unwrap() } else { node };
+        if !Parser::is_update_target(&target) {
+          return Err(self.error(format!("Invalid operand of '{}'", self.token.text), &self.token));
         }
+
+        node = self.node_create(NodeType::Update { op: op, postfix: true });
+        node.body.push(target);
+        self.token_next();
+        break;
       } else {
         break;
       }
@@ -258,12 +476,14 @@ impl<'a> Parser<'a> {
     } else {
       parent.body.push(node);
     }
+
+    Ok(())
   }
 
-  fn parse_term(&mut self, mut parent: &mut Node) {
+  fn parse_term(&mut self, mut parent: &mut Node) -> Result<(), Diagnostic> {
     loop {
       let mut fac = self.node_create(NodeType::Empty);
-      self.parse_unary(&mut fac);
+      self.parse_unary(&mut fac)?;
       
       fac.type_ = if self.token.type_ == TokenType::OpMul {
         NodeType::Op(OpType::OpMul)
@@ -282,11 +502,13 @@ impl<'a> Parser<'a> {
       
       self.token_next();
     }
+
+    Ok(())
   }
 
-  fn parse_expression(&mut self, mut parent: &mut Node) {
+  fn parse_expression(&mut self, mut parent: &mut Node) -> Result<(), Diagnostic> {
     let mut term = self.node_create(NodeType::Empty);
-    self.parse_term(&mut term);
+    self.parse_term(&mut term)?;
     let mut term = term.body.drain(0..).next().unwrap();
 
     loop {
@@ -303,15 +525,44 @@ impl<'a> Parser<'a> {
       self.token_next();
 
       new_term.body.push(term);
-      self.parse_term(&mut new_term);
+      self.parse_term(&mut new_term)?;
 
       term = new_term;
     }
+
+    Ok(())
   }
 
-  fn parse_condition_cmp(&mut self, mut parent: &mut Node) {
+  fn parse_shift(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
     let mut expr = self.node_create(NodeType::Empty);
-    self.parse_expression(&mut expr);
+    self.parse_expression(&mut expr)?;
+    let mut expr = expr.body.drain(0..).next().unwrap();
+
+    loop {
+      let type_ = match self.token.type_ {
+        TokenType::OpShl => NodeType::Op(OpType::OpShl),
+        TokenType::OpShr => NodeType::Op(OpType::OpShr),
+        _ => {
+          parent.body.push(expr);
+          break;
+        }
+      };
+
+      self.token_next();
+
+      let mut new_expr = self.node_create(type_);
+      new_expr.body.push(expr);
+      self.parse_expression(&mut new_expr)?;
+
+      expr = new_expr;
+    }
+
+    Ok(())
+  }
+
+  fn parse_condition_cmp(&mut self, mut parent: &mut Node) -> Result<(), Diagnostic> {
+    let mut expr = self.node_create(NodeType::Empty);
+    self.parse_shift(&mut expr)?;
     let mut expr = expr.body.drain(0..).next().unwrap();
 
     loop {
@@ -332,15 +583,96 @@ impl<'a> Parser<'a> {
 
       let mut new_expr = self.node_create(type_);
       new_expr.body.push(expr);
-      self.parse_expression(&mut new_expr);
+      self.parse_shift(&mut new_expr)?;
 
       expr = new_expr;
     }
+
+    Ok(())
   }
   
-  fn parse_condition_and(&mut self, mut parent: &mut Node) {
+  // Bitwise operators bind looser than comparisons, as in JS, shifts tighter
+  fn parse_bit_and(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    let mut expr = self.node_create(NodeType::Empty);
+    self.parse_condition_cmp(&mut expr)?;
+    let mut expr = expr.body.drain(0..).next().unwrap();
+
+    loop {
+      let type_ = match self.token.type_ {
+        TokenType::OpBitAnd => NodeType::Op(OpType::OpBitAnd),
+        _ => {
+          parent.body.push(expr);
+          break;
+        }
+      };
+
+      self.token_next();
+
+      let mut new_expr = self.node_create(type_);
+      new_expr.body.push(expr);
+      self.parse_condition_cmp(&mut new_expr)?;
+
+      expr = new_expr;
+    }
+
+    Ok(())
+  }
+
+  fn parse_bit_xor(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    let mut expr = self.node_create(NodeType::Empty);
+    self.parse_bit_and(&mut expr)?;
+    let mut expr = expr.body.drain(0..).next().unwrap();
+
+    loop {
+      let type_ = match self.token.type_ {
+        TokenType::OpBitXor => NodeType::Op(OpType::OpBitXor),
+        _ => {
+          parent.body.push(expr);
+          break;
+        }
+      };
+
+      self.token_next();
+
+      let mut new_expr = self.node_create(type_);
+      new_expr.body.push(expr);
+      self.parse_bit_and(&mut new_expr)?;
+
+      expr = new_expr;
+    }
+
+    Ok(())
+  }
+
+  fn parse_bit_or(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
     let mut expr = self.node_create(NodeType::Empty);
-    self.parse_condition_cmp(&mut expr);
+    self.parse_bit_xor(&mut expr)?;
+    let mut expr = expr.body.drain(0..).next().unwrap();
+
+    loop {
+      let type_ = match self.token.type_ {
+        TokenType::OpBitOr => NodeType::Op(OpType::OpBitOr),
+        _ => {
+          parent.body.push(expr);
+          break;
+        }
+      };
+
+      self.token_next();
+
+      let mut new_expr = self.node_create(type_);
+      new_expr.body.push(expr);
+      self.parse_bit_xor(&mut new_expr)?;
+
+      expr = new_expr;
+    }
+
+    Ok(())
+  }
+
+  fn parse_condition_and(&mut self, mut parent: &mut Node) -> Result<(), Diagnostic> {
+    let mut expr = self.node_create(NodeType::Empty);
+    self.parse_bit_or(&mut expr)?;
     let mut expr = expr.body.drain(0..).next().unwrap();
 
     loop {
@@ -356,20 +688,22 @@ impl<'a> Parser<'a> {
 
       let mut new_expr = self.node_create(type_);
       new_expr.body.push(expr);
-      self.parse_condition_cmp(&mut new_expr);
+      self.parse_bit_or(&mut new_expr)?;
 
       expr = new_expr;
     }
+
+    Ok(())
   }
   
-  fn parse_condition(&mut self, mut parent: &mut Node) {
+  fn parse_condition_nullish(&mut self, mut parent: &mut Node) -> Result<(), Diagnostic> {
     let mut expr = self.node_create(NodeType::Empty);
-    self.parse_condition_and(&mut expr);
+    self.parse_condition_and(&mut expr)?;
     let mut expr = expr.body.drain(0..).next().unwrap();
 
     loop {
       let type_ = match self.token.type_ {
-        TokenType::OpAnd => NodeType::Op(OpType::OpOr),
+        TokenType::OpNullish => NodeType::Op(OpType::OpNullish),
         _ => {
           parent.body.push(expr);
           break;
@@ -380,145 +714,612 @@ impl<'a> Parser<'a> {
 
       let mut new_expr = self.node_create(type_);
       new_expr.body.push(expr);
-      self.parse_condition_and(&mut new_expr);
+      self.parse_condition_and(&mut new_expr)?;
 
       expr = new_expr;
     }
+
+    Ok(())
+  }
+
+  fn parse_condition(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    self.parse_ternary(parent)
+  }
+
+  // `a ? b : c ? d : e` is `a ? b : (c ? d : e)`
+  fn parse_ternary(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    let mut node = self.node_create(NodeType::Ternary);
+    self.parse_condition_or(&mut node)?;
+
+    if self.token_accept(&TokenType::Question) {
+      self.parse_ternary(&mut node)?;
+      self.token_expect(&TokenType::Colon)?;
+      self.parse_ternary(&mut node)?;
+      parent.body.push(node);
+    } else {
+      parent.body.append(&mut node.body);
+    }
+
+    Ok(())
   }
 
-  fn parse_assignment(&mut self, parent: &mut Node) {
+  fn parse_condition_or(&mut self, mut parent: &mut Node) -> Result<(), Diagnostic> {
+    let mut expr = self.node_create(NodeType::Empty);
+    self.parse_condition_nullish(&mut expr)?;
+    let mut expr = expr.body.drain(0..).next().unwrap();
+
+    loop {
+      let type_ = match self.token.type_ {
+        TokenType::OpOr => NodeType::Op(OpType::OpOr),
+        _ => {
+          parent.body.push(expr);
+          break;
+        }
+      };
+
+      self.token_next();
+
+      let mut new_expr = self.node_create(type_);
+      new_expr.body.push(expr);
+      self.parse_condition_nullish(&mut new_expr)?;
+
+      expr = new_expr;
+    }
+
+    Ok(())
+  }
+
+  // A block expression evaluates to its final expression, which is written
+  // without a semicolon. A block ending with a statement evaluates to null.
+  fn parse_block_expr(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    let mut node = self.node_create(NodeType::BlockExpr);
+    let mut block = self.node_create(NodeType::Block);
+    let mut value = None;
+
+    self.token_expect(&TokenType::LBlock)?;
+
+    while self.token.type_ != TokenType::RBlock {
+      if self.token_starts_statement() {
+        self.parse_block(&mut block)?;
+        continue;
+      }
+
+      let mut expr = self.node_create(NodeType::Assign);
+      self.parse_condition(&mut expr)?;
+
+      if self.token.type_ == TokenType::RBlock {
+        value = expr.body.pop();
+      } else {
+        self.parse_assignment_rest(expr, &mut block)?;
+      }
+    }
+
+    self.token_expect(&TokenType::RBlock)?;
+    Parser::hoist_functions(&mut block);
+
+    let value = value.unwrap_or_else(|| self.node_create(NodeType::Empty));
+    node.body.push(block);
+    node.body.push(value);
+    parent.body.push(node);
+
+    Ok(())
+  }
+
+  fn parse_assignment(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
     let mut node = self.node_create(NodeType::Assign);
-    self.parse_condition(&mut node);
+    self.parse_condition(&mut node)?;
+    self.parse_assignment_rest(node, parent)?;
+
+    Ok(())
+  }
+
+  // Finishes an assignment (or expression) statement after its first expression
+  fn parse_assignment_rest(&mut self, node: Node, parent: &mut Node) -> Result<(), Diagnostic> {
+    self.parse_assignment_value(node, parent)?;
+    self.token_expect_end()?;
+
+    Ok(())
+  }
+
+  // Finishes an assignment (or expression) without the terminating `;`
+  fn parse_assignment_value(&mut self, mut node: Node, parent: &mut Node) -> Result<(), Diagnostic> {
+    let assign_op = ASSIGN_OPS.iter()
+      .find(|&&(ref t, _)| *t == self.token.type_)
+      .map(|&(_, op)| op);
 
     if self.token_accept(&TokenType::Assign) {
-      self.parse_condition(&mut node);
+      self.parse_condition(&mut node)?;
+      parent.body.push(node);
+    } else if let Some(op) = assign_op {
+      self.token_next();
+
+      // the target is duplicated, so its subexpressions are evaluated twice:
+      // `a[f()] += 1` calls `f` once for the store and once for the value
+      let mut value = self.node_create(NodeType::Op(op));
+      value.body.push(node.body[0].clone());
+      self.parse_condition(&mut value)?;
+
+      node.body.push(value);
       parent.body.push(node);
     } else {
       parent.body.append(&mut node.body);
     }
 
-    self.token_expect(&TokenType::End);
+    Ok(())
   }
 
-  fn parse_statement(&mut self, parent: &mut Node) {
-    let sym = if self.token.type_ == TokenType::Sym {
-      self.token.text
+  // Parses the rest of `for (init; cond; step) body` after the opening parenthesis
+  fn parse_for(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    let mut node = self.node_create(NodeType::StmtFor);
+    let mut block = self.node_create(NodeType::Block);
+
+    if self.token_accept(&TokenType::End) {
+      node.body.push(self.node_create(NodeType::Empty));
+    } else if self.token.is_keyword(Keyword::Var) {
+      // several declarations are kept together in a block
+      let mut init = self.node_create(NodeType::Block);
+      self.parse_statement(&mut init)?;
+      node.body.push(if init.body.len() == 1 { init.body.pop().unwrap() } else { init });
     } else {
-      self.parse_assignment(parent);
-      return;
+      self.parse_assignment(&mut node)?;
+    }
+
+    if self.token.type_ == TokenType::End {
+      node.body.push(self.node_create(NodeType::Empty));
+    } else {
+      self.parse_condition(&mut node)?;
+    }
+    self.token_expect(&TokenType::End)?;
+
+    if self.token.type_ == TokenType::RPar {
+      node.body.push(self.node_create(NodeType::Empty));
+    } else {
+      let mut step = self.node_create(NodeType::Assign);
+      self.parse_condition(&mut step)?;
+      self.parse_assignment_value(step, &mut node)?;
+    }
+    self.token_expect(&TokenType::RPar)?;
+
+    self.parse_block(&mut block)?;
+
+    node.body.push(block);
+    parent.body.push(node);
+
+    Ok(())
+  }
+
+  fn parse_statement(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
+    let sym = self.token.text;
+    let keyword = match self.token.type_ {
+      TokenType::Keyword(keyword) => Some(keyword),
+      TokenType::Sym => None,
+      _ => {
+        self.parse_assignment(parent)?;
+        return Ok(());
+      }
     };
 
-    if sym == "var" {
+    if keyword == Some(Keyword::Var) || keyword == Some(Keyword::Const) {
+      let (keyword, type_) = if self.token.is_keyword(Keyword::Var) {
+        ("var", NodeType::StmtVar)
+      } else {
+        ("const", NodeType::StmtConst)
+      };
       self.token_next();
 
-      let name = if let Some(s) = self.token.as_sym() {
-        s.to_string()
-      } else { 
-        self.die("variable name", &self.token); String::new()
-      };
+      // `var a = 1, b = 2;` declares each of the variables in turn
+      loop {
+        let name = if let Some(s) = self.token.as_sym() {
+          s.to_string()
+        } else { 
+          let msg = format!("'{}' requires a simple variable name, found '{}'", keyword, self.token.text);
+          return Err(self.error(msg, &self.token));
+        };
 
-      self.token_next();
-      self.token_expect(&TokenType::Assign);
+        let sym = self.node_create(NodeType::Symbol(name));
+        self.token_next();
 
-      let mut node = self.node_create(NodeType::StmtVar);
+        match self.token.type_ {
+          TokenType::Dot | TokenType::OptDot | TokenType::LBr => {
+            let msg = format!("'{}' requires a simple variable name, \
+                               members and indices can only be assigned to (found '{}')", keyword, self.token.text);
+            return Err(self.error(msg, &self.token));
+          },
+          _ => {}
+        }
 
-      let sym = self.node_create(NodeType::Symbol(name));
-      node.body.push(sym);
-      
-      self.parse_condition(&mut node);
-      self.token_expect(&TokenType::End);
-      
-      parent.body.push(node);
+        self.token_expect(&TokenType::Assign)?;
+
+        let mut node = self.node_create(type_.clone());
+        node.body.push(sym);
+
+        self.parse_condition(&mut node)?;
+        parent.body.push(node);
+
+        if !self.token_accept(&TokenType::Comma) {
+          break;
+        }
+      }
+
+      self.token_expect_end()?;
     }
-    else if sym == "if" { 
+    else if keyword == Some(Keyword::If) { 
       let mut node = self.node_create(NodeType::StmtIf);
       let mut if_block = self.node_create(NodeType::Block);
 
       self.token_next();
-      self.token_expect(&TokenType::LPar);
-      self.parse_condition(&mut node);
-      self.token_expect(&TokenType::RPar);
-      self.parse_block(&mut if_block);
+      self.token_expect(&TokenType::LPar)?;
+      self.parse_condition(&mut node)?;
+      self.token_expect(&TokenType::RPar)?;
+      self.parse_block(&mut if_block)?;
 
       node.body.push(if_block);
 
-      if let Some("else") = self.token.as_sym() {
+      if self.token.is_keyword(Keyword::Else) {
         node.type_ = NodeType::StmtIfElse;
 
         let mut else_block = self.node_create(NodeType::Block);
         self.token_next();
-        self.parse_block(&mut else_block);
+
+        // `else if` chains nest, the else block holds the next if statement
+        if self.token.is_keyword(Keyword::If) {
+          self.parse_statement(&mut else_block)?;
+        } else {
+          self.parse_block(&mut else_block)?;
+        }
 
         node.body.push(else_block);
       }
 
       parent.body.push(node);
     }
-    else if sym == "while" { 
+    else if keyword == Some(Keyword::While) { 
       let mut node = self.node_create(NodeType::StmtWhile);
       let mut block = self.node_create(NodeType::Block);
       
       self.token_next();
-      self.token_expect(&TokenType::LPar);
-      self.parse_condition(&mut node);
-      self.token_expect(&TokenType::RPar);
-      self.parse_block(&mut block);
+      self.token_expect(&TokenType::LPar)?;
+      self.parse_condition(&mut node)?;
+      self.token_expect(&TokenType::RPar)?;
+      self.parse_block(&mut block)?;
+
+      node.body.push(block);
+      parent.body.push(node);
+    }
+    else if keyword == Some(Keyword::Do) {
+      let mut node = self.node_create(NodeType::StmtDoWhile);
+      let mut block = self.node_create(NodeType::Block);
 
+      self.token_next();
+      self.parse_block(&mut block)?;
       node.body.push(block);
+
+      if !self.token.is_keyword(Keyword::While) {
+        return Err(self.die("'while'", &self.token));
+      }
+      self.token_next();
+      self.token_expect(&TokenType::LPar)?;
+      self.parse_condition(&mut node)?;
+      self.token_expect(&TokenType::RPar)?;
+      self.token_expect_end()?;
+
+      parent.body.push(node);
+    }
+    else if keyword == Some(Keyword::Switch) {
+      let mut node = self.node_create(NodeType::StmtSwitch);
+
+      self.token_next();
+      self.token_expect(&TokenType::LPar)?;
+      self.parse_condition(&mut node)?;
+      self.token_expect(&TokenType::RPar)?;
+      self.token_expect(&TokenType::LBlock)?;
+
+      let mut has_default = false;
+      while !self.token_accept(&TokenType::RBlock) {
+        let mut case = self.node_create(NodeType::SwitchCase);
+
+        if self.token.is_keyword(Keyword::Case) {
+          self.token_next();
+          self.parse_condition(&mut case)?;
+        }
+        else if self.token.is_keyword(Keyword::Default) {
+          if has_default {
+            return Err(self.error("multiple 'default' clauses in switch".to_string(), &self.token));
+          }
+          has_default = true;
+          case.body.push(self.node_create(NodeType::Empty));
+          self.token_next();
+        }
+        else {
+          return Err(self.die("'case' or 'default'", &self.token));
+        }
+        self.token_expect(&TokenType::Colon)?;
+
+        // the body runs up to the next clause
+        let mut block = self.node_create(NodeType::Block);
+        while !self.token.is_keyword(Keyword::Case) && !self.token.is_keyword(Keyword::Default) &&
+              self.token.type_ != TokenType::RBlock {
+          self.parse_block(&mut block)?;
+        }
+        Parser::hoist_functions(&mut block);
+
+        case.body.push(block);
+        node.body.push(case);
+      }
+
       parent.body.push(node);
     }
-    else if sym == "return" {
+    else if (keyword == Some(Keyword::Fn) || keyword == Some(Keyword::Function)) && self.token_peek_sym() {
+      let mut node = self.node_create(NodeType::StmtFunction);
+
+      self.token_next();
+      node.body.push(self.node_create(NodeType::Symbol(self.token.text.to_string())));
+      self.parse_fun(&mut node)?;
+      self.token_accept(&TokenType::End);
+
+      parent.body.push(node);
+    }
+    else if keyword == Some(Keyword::For) {
+      self.token_next();
+      self.token_expect(&TokenType::LPar)?;
+
+      if !self.token_starts_for_in() {
+        return self.parse_for(parent);
+      }
+
+      let mut node = self.node_create(NodeType::StmtForIn);
+      let mut block = self.node_create(NodeType::Block);
+
+      let mut target = if self.token.is_keyword(Keyword::Var) {
+        self.token_next();
+        self.node_create(NodeType::StmtVar)
+      } else {
+        self.node_create(NodeType::Assign)
+      };
+
+      if let Some(name) = self.token.as_sym() {
+        target.body.push(self.node_create(NodeType::Symbol(name.to_string())));
+      } else {
+        return Err(self.die("variable name", &self.token));
+      }
+      target.body.push(self.node_create(NodeType::Empty));
+      node.body.push(target);
+
+      self.token_next();
+      if !self.token.is_keyword(Keyword::In) {
+        return Err(self.die("'in'", &self.token));
+      }
+      self.token_next();
+
+      self.parse_condition(&mut node)?;
+      self.token_expect(&TokenType::RPar)?;
+      self.parse_block(&mut block)?;
+
+      node.body.push(block);
+      parent.body.push(node);
+    }
+    else if keyword == Some(Keyword::Return) {
       self.token_next();
 
       let mut node = self.node_create(NodeType::StmtReturn);
 
-      self.parse_condition(&mut node);
+      if self.token.type_ != TokenType::End && !self.token_implies_end() {
+        self.parse_condition(&mut node)?;
+      }
 
       parent.body.push(node);
 
-      self.token_expect(&TokenType::End);
+      self.token_expect_end()?;
+    }
+    else if keyword == Some(Keyword::Break) || keyword == Some(Keyword::Continue) {
+      let type_ = if keyword == Some(Keyword::Break) { NodeType::StmtBreak } else { NodeType::StmtContinue };
+      let mut node = self.node_create(type_);
+
+      self.token_next();
+
+      // without a label the innermost loop is the target
+      if self.token.type_ != TokenType::End && !self.token_implies_end() {
+        if let Some(label) = self.token.as_sym() {
+          node.body.push(self.node_create(NodeType::Symbol(label.to_string())));
+        } else {
+          return Err(self.die("label or ';'", &self.token));
+        }
+
+        self.token_next();
+      }
+
+      self.token_expect_end()?;
+
+      parent.body.push(node);
     }
     else {
-      self.parse_assignment(parent);
+      self.token_next();
+
+      if keyword.is_none() && self.token_accept(&TokenType::Colon) {
+        let mut node = self.node_create(NodeType::StmtLabel);
+        node.body.push(self.node_create(NodeType::Symbol(sym.to_string())));
+
+        // a braced statement is kept as a block to tell it apart from a labeled loop
+        if self.token.type_ == TokenType::LBlock {
+          let mut block = self.node_create(NodeType::Block);
+          self.parse_block(&mut block)?;
+          node.body.push(block);
+        } else {
+          self.parse_statement(&mut node)?;
+        }
+
+        parent.body.push(node);
+      } else {
+        self.token_revert();
+        self.parse_assignment(parent)?;
+      }
     }
+
+    Ok(())
   }
 
-  fn parse_block(&mut self, parent: &mut Node) {
+  fn parse_block(&mut self, parent: &mut Node) -> Result<(), Diagnostic> {
     if self.token_accept(&TokenType::LBlock) {
       while self.token.type_ != TokenType::RBlock {
-        self.parse_block(parent);
+        self.parse_block(parent)?;
       }
-      self.token_expect(&TokenType::RBlock);
+      self.token_expect(&TokenType::RBlock)?;
+      Parser::hoist_functions(parent);
     }
     else {
-      self.parse_statement(parent);
+      self.parse_statement(parent)?;
     }
+
+    Ok(())
   }
 
-  fn parse_program(&mut self) -> Node {
+  fn parse_program(&mut self) -> Result<Node, Diagnostic> {
     self.token_next();
 
     let mut root = self.node_create(NodeType::Block); 
 
     while self.token.type_ != TokenType::Eof {
-      self.parse_block(&mut root);
+      self.parse_block(&mut root)?;
+    }
+
+    self.token_expect(&TokenType::Eof)?;
+    Parser::hoist_functions(&mut root);
+
+    Ok(root)
+  }
+
+  // Moves function declarations to the top of the block, keeping their order,
+  // so functions can be called before the declaration
+  fn hoist_functions(block: &mut Node) {
+    let (mut funs, rest): (Vec<Node>, Vec<Node>) = block.body.drain(..)
+      .partition(|n| n.type_ == NodeType::StmtFunction);
+    funs.extend(rest);
+    block.body = funs;
+  }
+
+  // `{}`, `{key: ...`, `{key, ...`, `{key}` and `{[expr]: ...` are dicts, other
+  // braces in expressions are blocks. `{x}` is the shorthand `{x: x}`, a block
+  // expression yielding just a name has no use.
+  fn token_starts_dict(&mut self) -> bool {
+    if self.token_peek(&TokenType::LBr) {
+      // a computed key is followed by ':' past the matching bracket
+      let mut depth = 0;
+      let mut n = 0;
+      while let Some(type_) = self.peek(n) {
+        match type_ {
+          TokenType::LBr => depth += 1,
+          TokenType::RBr => depth -= 1,
+          _ => {}
+        }
+        n += 1;
+        if depth == 0 {
+          return self.peek(n) == Some(TokenType::Colon);
+        }
+      }
+      return false;
+    }
+
+    match (self.peek(0), self.peek(1)) {
+      (Some(TokenType::RBlock), _) => true,
+      (Some(TokenType::Sym), Some(TokenType::Colon)) |
+      (Some(TokenType::Sym), Some(TokenType::Comma)) |
+      (Some(TokenType::Sym), Some(TokenType::RBlock)) |
+      (Some(TokenType::Keyword(_)), Some(TokenType::Colon)) |
+      (Some(TokenType::Str), Some(TokenType::Colon)) |
+      (Some(TokenType::Num), Some(TokenType::Colon)) => true,
+      _ => false
+    }
+  }
+
+  // `x =>` or a list of names in parentheses followed by `=>`
+  fn token_starts_arrow(&mut self) -> bool {
+    match self.token.type_ {
+      TokenType::Sym => return self.token_peek(&TokenType::Arrow),
+      TokenType::LPar => {},
+      _ => return false
     }
 
-    self.token_expect(&TokenType::Eof);
+    let mut n = 0;
+    loop {
+      match self.peek(n) {
+        Some(TokenType::Sym) | Some(TokenType::Comma) => n += 1,
+        Some(TokenType::RPar) => return self.peek(n + 1) == Some(TokenType::Arrow),
+        _ => return false
+      }
+    }
+  }
+
+  // `for (var k in` or `for (k in`, the opening parenthesis is skipped
+  fn token_starts_for_in(&mut self) -> bool {
+    match (self.token.type_.clone(), self.peek(0), self.peek(1)) {
+      (TokenType::Keyword(Keyword::Var), Some(TokenType::Sym), Some(TokenType::Keyword(Keyword::In))) |
+      (TokenType::Sym, Some(TokenType::Keyword(Keyword::In)), _) => true,
+      _ => false
+    }
+  }
+
+  fn token_starts_statement(&mut self) -> bool {
+    match self.token.type_ {
+      TokenType::Keyword(Keyword::Var) | TokenType::Keyword(Keyword::Const) |
+      TokenType::Keyword(Keyword::If) | TokenType::Keyword(Keyword::While) |
+      TokenType::Keyword(Keyword::Do) | TokenType::Keyword(Keyword::For) |
+      TokenType::Keyword(Keyword::Switch) |
+      TokenType::Keyword(Keyword::Return) | TokenType::Keyword(Keyword::Break) |
+      TokenType::Keyword(Keyword::Continue) => true,
+      TokenType::Keyword(Keyword::Fn) | TokenType::Keyword(Keyword::Function) => self.token_peek_sym(),
+      // labeled statement
+      TokenType::Sym => self.token_peek(&TokenType::Colon),
+      _ => self.token.type_ == TokenType::LBlock
+    }
+  }
+
+  fn token_peek_sym(&mut self) -> bool {
+    self.token_peek(&TokenType::Sym)
+  }
+
+  fn token_peek(&mut self, type_: &TokenType) -> bool {
+    self.peek(0).as_ref() == Some(type_)
+  }
+
+  // The type of the token `n` positions past the current one, `peek(0)` is
+  // the next token
+  fn peek(&mut self, n: usize) -> Option<TokenType> {
+    self.fill(n);
+    self.tokens.get(self.next + n).map(|t| t.type_.clone())
+  }
 
-    root
+  // Reads the source up to the token `n` positions past the current one
+  fn fill(&mut self, n: usize) {
+    while self.tokens.len() <= self.next + n {
+      match self.source.next() {
+        Some(t) => self.tokens.push(t),
+        None => break
+      }
+    }
   }
 
+  // The last token (Eof) stays current at the end of the stream
   fn token_next(&mut self) {
     self.prev_token = self.token.clone();
-    if let Some(t) = self.stream.pop_front() {
-      self.token = t;
-    };
+    self.fill(0);
+    if let Some(t) = self.tokens.get(self.next) {
+      self.token = t.clone();
+      self.next += 1;
+    }
   }
 
+  // Steps back to the previous token, can be repeated up to the first one
   fn token_revert(&mut self) {
-    self.stream.push_front(self.token.clone());
+    if self.next == 0 {
+      return;
+    }
+
+    self.next -= 1;
     self.token = self.prev_token.clone();
+    self.prev_token = match self.next {
+      0 | 1 => Token::new_empty(),
+      n => self.tokens[n - 2].clone()
+    };
   }
 
   fn token_accept(&mut self, token: &TokenType) -> bool {
@@ -531,19 +1332,557 @@ impl<'a> Parser<'a> {
     accepted
   }
 
-  fn token_expect(&mut self, token: &TokenType) {
+  fn token_expect(&mut self, token: &TokenType) -> Result<(), Diagnostic> {
     if !self.token_accept(token) {
-      self.die(&format!("token type '{:?}'", token), &self.token);
+      return Err(self.die(&format!("token type '{:?}'", token), &self.token));
     }
+
+    Ok(())
+  }
+
+  // In ASI mode a missing `;` is implied before a token on the next line,
+  // before `}` and at the end of the text
+  fn token_implies_end(&self) -> bool {
+    self.asi && (self.token.line > self.prev_token.end_line ||
+                 self.token.type_ == TokenType::RBlock || self.token.type_ == TokenType::Eof)
+  }
+
+  fn token_expect_end(&mut self) -> Result<(), Diagnostic> {
+    if self.token.type_ != TokenType::End && self.token_implies_end() {
+      return Ok(());
+    }
+
+    self.token_expect(&TokenType::End)
+  }
+
+  fn die(&self, expected: &str, token: &Token) -> Diagnostic {
+    self.error(format!("Unexpected token '{}' (expected {})", token.text, expected), token)
   }
 
-  fn die(&self, expected: &str, token: &Token) {
-    panic!(format!("Unexpected token '{}' at {},{} (expected {})",
-                   token.text, token.line, token.col, expected));
+  fn error(&self, message: String, token: &Token) -> Diagnostic {
+    Diagnostic::error(message, Some(Position { line: token.line, col: token.col }))
   }
 
   fn node_create(&mut self, type_: NodeType) -> Node {
-    Node::new(type_)
+    Node::new_at(type_, self.token.span())
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokenizer::Tokenizer;
+  use util::JsonVisitor;
+
+  fn parse(text: &str) -> Node {
+    Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse()
+  }
+
+  // Compares the JSON form of the parsed text with the expected snapshot
+  // (leading indentation of the snapshot is ignored), panicking with a
+  // line diff on mismatch
+  fn assert_ast(text: &str, expected: &str) {
+    let mut json = JsonVisitor::new();
+    parse(text).visit(&mut json);
+
+    let actual = json.text();
+    let actual: Vec<&str> = actual.lines().collect();
+    let expected: Vec<&str> = expected.lines()
+      .map(|l| l.trim_start_matches(' ').trim_start_matches('|'))
+      .filter(|l| !l.is_empty())
+      .collect();
+
+    if actual != expected {
+      panic!("AST of {:?} doesn't match the snapshot (-expected +actual):\n{}",
+             text, diff(&expected, &actual));
+    }
+  }
+
+  // Line diff based on the longest common subsequence
+  fn diff(a: &[&str], b: &[&str]) -> String {
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+      for j in (0..b.len()).rev() {
+        lcs[i][j] = if a[i] == b[j] {
+          lcs[i + 1][j + 1] + 1
+        } else {
+          lcs[i + 1][j].max(lcs[i][j + 1])
+        };
+      }
+    }
+
+    let (mut i, mut j) = (0, 0);
+    let mut text = String::new();
+
+    while i < a.len() || j < b.len() {
+      if i < a.len() && j < b.len() && a[i] == b[j] {
+        text += &format!("  {}\n", a[i]);
+        i += 1;
+        j += 1;
+      } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+        text += &format!("- {}\n", a[i]);
+        i += 1;
+      } else {
+        text += &format!("+ {}\n", b[j]);
+        j += 1;
+      }
+    }
+
+    text
+  }
+
+  #[test]
+  fn test_assign_ops() {
+    let ast = parse("a %= 3;");
+    assert_eq!(ast.body[0].type_, NodeType::Assign);
+    assert_eq!(ast.body[0].body[0].type_, NodeType::Symbol("a".to_string()));
+    assert_eq!(ast.body[0].body[1].type_, NodeType::Op(OpType::OpMod));
+    assert_eq!(ast.body[0].body[1].body[0].type_, NodeType::Symbol("a".to_string()));
+    assert_eq!(ast.body[0].body[1].body[1].type_, NodeType::Number(3.0));
+
+    assert_eq!(parse("x += 1;"), parse("x = x + 1;"));
+    assert_eq!(parse("a[i].b *= c;"), parse("a[i].b = a[i].b * c;"));
+    assert_eq!(parse("a <<= 2;"), parse("a = a << 2;"));
+    assert_eq!(parse("a >>= b + 1;"), parse("a = a >> (b + 1);"));
+    assert_eq!(parse("a.b &= 6;"), parse("a.b = a.b & 6;"));
+    assert_eq!(parse("a[0] |= b | c;"), parse("a[0] = a[0] | (b | c);"));
+    assert_eq!(parse("a ^= 1;"), parse("a = a ^ 1;"));
+
+    let ast = parse("a.b -= 1 + 2;");
+    assert_eq!(ast.body[0].body[1].type_, NodeType::Op(OpType::OpMinus));
+    assert_eq!(ast.body[0].body[1].body[0].type_, NodeType::Member);
+    assert_eq!(ast.body[0].body[1].body[1].type_, NodeType::Op(OpType::OpPlus));
+  }
+
+  #[test]
+  fn test_var_name_error() {
+    let parse_err = |text| Parser::new(Tokenizer::new(text).tokenize().unwrap()).try_parse().err().unwrap();
+
+    let err = parse_err("var a.b = 1;");
+    assert_eq!(err.message, "'var' requires a simple variable name, \
+                             members and indices can only be assigned to (found '.')");
+    assert_eq!(err.pos, Some(Position { line: 1, col: 5 }));
+
+    assert_eq!(parse_err("var 1 = 1;").message, "'var' requires a simple variable name, found '1'");
+  }
+
+  #[test]
+  fn test_keyword_names() {
+    assert!(Parser::new(Tokenizer::new("var if = 1;").tokenize().unwrap()).try_parse().is_err());
+    assert!(Parser::new(Tokenizer::new("if: while (1) {}").tokenize().unwrap()).try_parse().is_err());
+
+    // keywords can still name dict keys and members
+    let ast = parse("var iffy = {if: 1, in: 2}; iffy.in = iffy.if;");
+    assert_eq!(ast.body[1].body[0].body[0].type_, NodeType::Symbol("in".to_string()));
+  }
+
+  #[test]
+  fn test_method_shorthand() {
+    assert_eq!(parse("var o = {n: 1, get(a, b) { return this.n; }};"),
+               parse("var o = {n: 1, get: fn(a, b) { return this.n; }};"));
+  }
+
+  #[test]
+  fn test_arrow_functions() {
+    // single argument, with or without parentheses
+    assert_eq!(parse("var f = x => x + 1;"), parse("var f = fn(x) { return x + 1; };"));
+    assert_eq!(parse("var f = (x) => x * 2;"), parse("var f = fn(x) { return x * 2; };"));
+
+    // multiple and no arguments
+    assert_eq!(parse("f((a, b) => a < b ? a : b);"), parse("f(fn(a, b) { return a < b ? a : b; });"));
+    assert_eq!(parse("var g = () => 0;"), parse("var g = fn() { return 0; };"));
+
+    // block bodies are kept as they are
+    assert_eq!(parse("var h = (a, b) => { var c = a; return c + b; };"),
+               parse("var h = fn(a, b) { var c = a; return c + b; };"));
+
+    // a parenthesized expression isn't an arrow function
+    assert_eq!(parse("var y = (a) + (b);").body[0].body[1].type_, NodeType::Op(OpType::OpPlus));
+  }
+
+  #[test]
+  fn test_property_shorthand() {
+    let ast = parse("var o = {a, b: 2};");
+    let dict = &ast.body[0].body[1];
+    assert_eq!(dict.type_, NodeType::Dict);
+    assert_eq!(dict.body.len(), 4);
+    assert_eq!(dict.body[0].type_, NodeType::Symbol("a".to_string()));
+    assert_eq!(dict.body[1].type_, NodeType::Symbol("a".to_string()));
+
+    assert_eq!(parse("var o = {x, f() { return 1; }, y};"),
+               parse("var o = {x: x, f: fn() { return 1; }, y: y};"));
+    assert_eq!(parse("var o = {x};"), parse("var o = {x: x};"));
+    assert_eq!(parse("f({ x });"), parse("f({x: x});"));
+  }
+
+  #[test]
+  fn test_computed_keys() {
+    let ast = parse("var o = { ['a'+'b']: 1, [k[0]]: 2 };");
+    let dict = &ast.body[0].body[1];
+    assert_eq!(dict.type_, NodeType::Dict);
+    assert_eq!(dict.body[0].type_, NodeType::ComputedKey);
+    assert_eq!(dict.body[0].body[0], parse("x = 'a' + 'b';").body[0].body[1]);
+    assert_eq!(dict.body[1].type_, NodeType::Number(1.0));
+    assert_eq!(dict.body[2].body[0].type_, NodeType::Index);
+
+    // a block yielding an array is not a dict
+    assert_eq!(parse("var o = {[1, 2]};").body[0].body[1].type_, NodeType::BlockExpr);
+  }
+
+  #[test]
+  fn test_logical_precedence() {
+    // a || b ?? c && d is a || (b ?? (c && d))
+    let ast = parse("x = a || b ?? c && d;");
+    let or = &ast.body[0].body[1];
+    assert_eq!(or.type_, NodeType::Op(OpType::OpOr));
+    assert_eq!(or.body[1].type_, NodeType::Op(OpType::OpNullish));
+    assert_eq!(or.body[1].body[1].type_, NodeType::Op(OpType::OpAnd));
+  }
+
+  #[test]
+  fn test_bitwise_precedence() {
+    // a | b ^ c & d == e << f is a | (b ^ (c & (d == (e << f))))
+    let ast = parse("x = a | b ^ c & d == e << f + 1 && ~g;");
+    let and = &ast.body[0].body[1];
+    assert_eq!(and.type_, NodeType::Op(OpType::OpAnd));
+    assert_eq!(and.body[1].type_, NodeType::Op(OpType::OpBitNot));
+
+    let or = &and.body[0];
+    assert_eq!(or.type_, NodeType::Op(OpType::OpBitOr));
+    assert_eq!(or.body[1].type_, NodeType::Op(OpType::OpBitXor));
+    assert_eq!(or.body[1].body[1].type_, NodeType::Op(OpType::OpBitAnd));
+    assert_eq!(or.body[1].body[1].body[1].type_, NodeType::Op(OpType::OpEq));
+    assert_eq!(or.body[1].body[1].body[1].body[1].type_, NodeType::Op(OpType::OpShl));
+    assert_eq!(or.body[1].body[1].body[1].body[1].body[1].type_, NodeType::Op(OpType::OpPlus));
+  }
+
+  #[test]
+  fn test_else_if() {
+    let ast = parse("if (a) { x = 1; } else if (b) { x = 2; } else if (c) { x = 3; } else { x = 4; }");
+    let mut node = &ast.body[0];
+
+    for _ in 0..2 {
+      assert_eq!(node.type_, NodeType::StmtIfElse);
+      assert_eq!(node.body.len(), 3);
+      assert_eq!(node.body[2].body.len(), 1);
+      node = &node.body[2].body[0];
+    }
+
+    assert_eq!(node.type_, NodeType::StmtIfElse);
+    assert_eq!(node.body[2].body[0].type_, NodeType::Assign);
+
+    let ast = parse("if (a) { x = 1; } else if (b) { x = 2; }");
+    assert_eq!(ast.body[0].body[2].body[0].type_, NodeType::StmtIf);
+    assert_eq!(ast.body[0].body[2].body[0].body.len(), 2);
+  }
+
+  #[test]
+  fn test_do_while() {
+    let ast = parse("do { x = x + 1; } while (x < 3); y = x;");
+    assert_eq!(ast.body.len(), 2);
+    assert_eq!(ast.body[0].type_, NodeType::StmtDoWhile);
+    assert_eq!(ast.body[0].body[0].type_, NodeType::Block);
+    assert_eq!(ast.body[0].body[1].type_, NodeType::Op(OpType::OpLs));
+
+    assert!(Parser::new(Tokenizer::new("do { x = 1; } (x < 3);").tokenize().unwrap()).try_parse().is_err());
+  }
+
+  #[test]
+  fn test_switch() {
+    let ast = parse("switch (x) { case 1: y = 1; break; case 'a': case 2: y = 2; default: y = 3; }");
+    let node = &ast.body[0];
+    assert_eq!(node.type_, NodeType::StmtSwitch);
+    assert_eq!(node.body.len(), 5);
+    assert_eq!(node.body[0].type_, NodeType::Symbol("x".to_string()));
+
+    let values: Vec<&NodeType> = node.body[1..].iter().map(|c| &c.body[0].type_).collect();
+    assert_eq!(values, vec![&NodeType::Number(1.0), &NodeType::String("a".to_string()),
+                            &NodeType::Number(2.0), &NodeType::Empty]);
+
+    let sizes: Vec<usize> = node.body[1..].iter().map(|c| c.body[1].body.len()).collect();
+    assert_eq!(sizes, vec![2, 0, 1, 1]);
+
+    let try_parse = |text: &str| Parser::new(Tokenizer::new(text).tokenize().unwrap()).try_parse();
+    assert!(try_parse("switch (x) { default: break; default: break; }").is_err());
+    assert!(try_parse("switch (x) { y = 1; }").is_err());
+  }
+
+  #[test]
+  fn test_ternary() {
+    let ast = parse("x = a || b ? c : d ? e + 1 : f;");
+    let node = &ast.body[0].body[1];
+    assert_eq!(node.type_, NodeType::Ternary);
+    assert_eq!(node.body[0].type_, NodeType::Op(OpType::OpOr));
+    assert_eq!(node.body[1].type_, NodeType::Symbol("c".to_string()));
+    assert_eq!(node.body[2].type_, NodeType::Ternary);
+    assert_eq!(node.body[2].body[1].type_, NodeType::Op(OpType::OpPlus));
+
+    // a conditional in the middle needs no parentheses
+    let ast = parse("var y = a ? b ? 1 : 2 : {k: c ? 3 : 4};");
+    let node = &ast.body[0].body[1];
+    assert_eq!(node.body[1].type_, NodeType::Ternary);
+    assert_eq!(node.body[2].body[1].type_, NodeType::Ternary);
+
+    assert!(Parser::new(Tokenizer::new("x = a ? b;").tokenize().unwrap()).try_parse().is_err());
+  }
+
+  #[test]
+  fn test_increments() {
+    let ast = parse("a = ++b[0] + c.d-- - -e++;");
+    let sum = &ast.body[0].body[1];
+    let prefix = &sum.body[0].body[0];
+    let postfix = &sum.body[0].body[1];
+    assert_eq!(prefix.type_, NodeType::Update { op: OpType::OpPlus, postfix: false });
+    assert_eq!(prefix.body[0].type_, NodeType::Index);
+    assert_eq!(postfix.type_, NodeType::Update { op: OpType::OpMinus, postfix: true });
+    assert_eq!(postfix.body[0].type_, NodeType::Member);
+    assert_eq!(sum.body[1].body[0].type_, NodeType::Update { op: OpType::OpPlus, postfix: true });
+
+    let parse_err = |text| Parser::new(Tokenizer::new(text).tokenize().unwrap()).try_parse().err().unwrap();
+    assert_eq!(parse_err("x = ++1;").message, "Invalid operand of '++'");
+    assert_eq!(parse_err("x = f()--;").message, "Invalid operand of '--'");
+  }
+
+  #[test]
+  fn test_postfix_not() {
+    let ast = parse("x!;");
+    assert_eq!(ast.body[0].type_, NodeType::Symbol("x".to_string()));
+    assert!(ast.body[0].body.is_empty());
+
+    let ast = parse("!x;");
+    assert_eq!(ast.body[0].type_, NodeType::Op(OpType::OpNot));
+    assert_eq!(ast.body[0].body[0].type_, NodeType::Symbol("x".to_string()));
+
+    let ast = parse("a.b!.c(y!)!;");
+    assert_eq!(ast.body[0].type_, NodeType::Call);
+    assert_eq!(ast.body[0].body[0].type_, NodeType::Member);
+    assert_eq!(ast.body[0].body[1].body[0].type_, NodeType::Symbol("y".to_string()));
+  }
+
+  #[test]
+  fn test_typeof() {
+    let ast = parse("var t = typeof x + 'y';");
+    let sum = &ast.body[0].body[1];
+    assert_eq!(sum.type_, NodeType::Op(OpType::OpPlus));
+    assert_eq!(sum.body[0].type_, NodeType::Op(OpType::OpTypeof));
+    assert_eq!(sum.body[0].body.len(), 1);
+    assert_eq!(sum.body[0].body[0].type_, NodeType::Symbol("x".to_string()));
+
+    assert_eq!(parse("var t = typeof typeof x;").body[0].body[1].body[0].type_, NodeType::Op(OpType::OpTypeof));
+  }
+
+  #[test]
+  fn test_asi() {
+    let parse_asi = |text| {
+      let mut tokenizer = Tokenizer::new(text);
+      let mut parser = Parser::new(tokenizer.tokenize().unwrap());
+      parser.set_asi(true);
+      parser.try_parse()
+    };
+
+    let text = "var a = 1, b = 2
+      a = a +
+        b
+      var f = fn() {
+        if (a) { return }
+        return
+          a
+      }
+      outer: while (1) { break outer }
+      f()";
+    let expected = parse("var a = 1, b = 2;
+      a = a + b;
+      var f = fn() {
+        if (a) { return; }
+        return;
+        a;
+      };
+      outer: while (1) { break outer; }
+      f();");
+    assert_eq!(parse_asi(text).unwrap(), expected);
+
+    let err = parse_asi("var a = 1\na b").err().unwrap();
+    assert_eq!(err.pos, Some(Position { line: 2, col: 2 }));
+    assert!(parse_asi("f() g()").is_err());
+
+    let strict = Parser::new(Tokenizer::new("var a = 1\nb = 2").tokenize().unwrap()).try_parse();
+    assert_eq!(strict.err().unwrap().pos, Some(Position { line: 2, col: 0 }));
+  }
+
+  #[test]
+  fn test_multiple_vars() {
+    use var_analyzer::build_frame_stack;
+
+    let mut ast = parse("var a = 1, b = f(2, 3), c = a + b;");
+    assert_eq!(ast.body.len(), 3);
+    assert_eq!(ast, parse("var a = 1; var b = f(2, 3); var c = a + b;"));
+
+    let mut fstack = build_frame_stack(&mut ast);
+    assert_eq!(fstack.frames()[0].var_offsets, vec!["this", "a", "b", "c"]);
+
+    let ast = parse("for (var i = 0, j = 2; i < j; i++) {}");
+    assert_eq!(ast.body[0].body[0].type_, NodeType::Block);
+    assert_eq!(ast.body[0].body[0].body, parse("var i = 0; var j = 2;").body);
+
+    let parse_err = |text| Parser::new(Tokenizer::new(text).tokenize().unwrap()).try_parse().err().unwrap();
+    assert_eq!(parse_err("var a = 1, 2;").message, "'var' requires a simple variable name, found '2'");
+    assert_eq!(parse_err("var a = 1, b;").pos, Some(Position { line: 1, col: 12 }));
+  }
+
+  #[test]
+  fn test_lookahead() {
+    let mut tokenizer = Tokenizer::new("for (var k in a) {}");
+    let mut parser = Parser::new(tokenizer.tokenize().unwrap());
+
+    parser.token_next();
+    parser.token_next();
+    assert_eq!(parser.token.type_, TokenType::LPar);
+    assert_eq!(parser.peek(1), Some(TokenType::Sym));
+    assert_eq!(parser.peek(2), Some(TokenType::Keyword(Keyword::In)));
+    assert_eq!(parser.peek(10), None);
+
+    // two tokens ahead tell a for-in loop apart
+    parser.token_next();
+    assert!(parser.token_starts_for_in());
+
+    // reverting more than one token
+    parser.token_revert();
+    parser.token_revert();
+    assert!(parser.token.is_keyword(Keyword::For));
+    assert_eq!(parser.prev_token.type_, TokenType::Empty);
+    parser.token_next();
+    assert_eq!(parser.prev_token.type_, TokenType::Keyword(Keyword::For));
+    assert_eq!(parser.peek(0), Some(TokenType::Keyword(Keyword::Var)));
+  }
+
+  #[test]
+  fn test_parse_next() {
+    let text = "var a = 1;\nvar b = [1, 2, 3, 4, 5, 6];\nvar c = 3;";
+    let mut parser = Parser::new(Tokenizer::new(text).map(Result::unwrap));
+
+    let mut statements = vec![];
+    while let Some(block) = parser.parse_next().unwrap() {
+      // tokens are read on demand and dropped past the statement
+      assert!(parser.tokens.len() <= 3);
+      statements.extend(block.body);
+    }
+    assert_eq!(statements, parse(text).body);
+  }
+
+  #[test]
+  fn test_symbol_span() {
+    let mut tokenizer = Tokenizer::new("var x = 1;\ny = foo + x;");
+    let tokens = tokenizer.tokenize().unwrap();
+    let foo = tokens.iter().find(|t| t.text == "foo").unwrap().span();
+
+    let ast = Parser::new(tokens).parse();
+    let sum = &ast.body[1].body[1];
+    assert_eq!(sum.body[0].type_, NodeType::Symbol("foo".to_string()));
+    assert_eq!(sum.body[0].span, Some(foo));
+    assert_eq!(foo.start, Position { line: 2, col: 4 });
+    assert_eq!(foo.end, Position { line: 2, col: 7 });
+  }
+
+  #[test]
+  fn test_snapshots() {
+    assert_ast("var sum = fn(n) { var s = 0; while (n > 0) { s += n; n = n - 1; } return s; };", r#"
+      |{"type": "Block", "children": [
+      |  {"type": "StmtVar", "children": [
+      |    {"type": "Symbol", "name": "sum", "children": []},
+      |    {"type": "Function", "children": [
+      |      {"type": "Block", "children": [
+      |        {"type": "Symbol", "name": "n", "children": []}
+      |      ]},
+      |      {"type": "Block", "children": [
+      |        {"type": "StmtVar", "children": [
+      |          {"type": "Symbol", "name": "s", "children": []},
+      |          {"type": "Number", "value": 0, "children": []}
+      |        ]},
+      |        {"type": "StmtWhile", "children": [
+      |          {"type": "Op", "op": ">", "children": [
+      |            {"type": "Symbol", "name": "n", "children": []},
+      |            {"type": "Number", "value": 0, "children": []}
+      |          ]},
+      |          {"type": "Block", "children": [
+      |            {"type": "Assign", "children": [
+      |              {"type": "Symbol", "name": "s", "children": []},
+      |              {"type": "Op", "op": "+", "children": [
+      |                {"type": "Symbol", "name": "s", "children": []},
+      |                {"type": "Symbol", "name": "n", "children": []}
+      |              ]}
+      |            ]},
+      |            {"type": "Assign", "children": [
+      |              {"type": "Symbol", "name": "n", "children": []},
+      |              {"type": "Op", "op": "-", "children": [
+      |                {"type": "Symbol", "name": "n", "children": []},
+      |                {"type": "Number", "value": 1, "children": []}
+      |              ]}
+      |            ]}
+      |          ]}
+      |        ]},
+      |        {"type": "StmtReturn", "children": [
+      |          {"type": "Symbol", "name": "s", "children": []}
+      |        ]}
+      |      ]}
+      |    ]}
+      |  ]}
+      |]}"#);
+
+    assert_ast("var d = {x: 1.5, 'y z': 'w', 2: [true]};", r#"
+      |{"type": "Block", "children": [
+      |  {"type": "StmtVar", "children": [
+      |    {"type": "Symbol", "name": "d", "children": []},
+      |    {"type": "Dict", "children": [
+      |      {"type": "Symbol", "name": "x", "children": []},
+      |      {"type": "Number", "value": 1.5, "children": []},
+      |      {"type": "String", "value": "y z", "children": []},
+      |      {"type": "String", "value": "w", "children": []},
+      |      {"type": "Number", "value": 2, "children": []},
+      |      {"type": "Array", "children": [
+      |        {"type": "Symbol", "name": "true", "children": []}
+      |      ]}
+      |    ]}
+      |  ]}
+      |]}"#);
+
+    assert_ast("std.io.println(a.b(1).c);", r#"
+      |{"type": "Block", "children": [
+      |  {"type": "Call", "children": [
+      |    {"type": "Member", "children": [
+      |      {"type": "Symbol", "name": "println", "children": []},
+      |      {"type": "Member", "children": [
+      |        {"type": "Symbol", "name": "io", "children": []},
+      |        {"type": "Symbol", "name": "std", "children": []}
+      |      ]}
+      |    ]},
+      |    {"type": "Block", "children": [
+      |      {"type": "Member", "children": [
+      |        {"type": "Symbol", "name": "c", "children": []},
+      |        {"type": "Call", "children": [
+      |          {"type": "Member", "children": [
+      |            {"type": "Symbol", "name": "b", "children": []},
+      |            {"type": "Symbol", "name": "a", "children": []}
+      |          ]},
+      |          {"type": "Block", "children": [
+      |            {"type": "Number", "value": 1, "children": []}
+      |          ]}
+      |        ]}
+      |      ]}
+      |    ]}
+      |  ]}
+      |]}"#);
+  }
+
+  #[test]
+  fn test_null() {
+    let ast = parse("var a = null; var b = [undefined, {null: 1}.null];");
+    assert_eq!(ast.body[0].body[1].type_, NodeType::Null);
+    assert_eq!(ast.body[1].body[1].body[0].type_, NodeType::Undefined);
+
+    // still a name after `.` and as a dict key
+    let member = &ast.body[1].body[1].body[1];
+    assert_eq!(member.body[0].type_, NodeType::Symbol("null".to_string()));
+    assert_eq!(member.body[1].body[0].type_, NodeType::Symbol("null".to_string()));
+  }
+}
@@ -0,0 +1,433 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use tokenizer::Tokenizer;
+use parser::Parser;
+use compiler::Compiler;
+use assembler::Encoding;
+use diagnostic::{self, Diagnostic};
+use sourcemap::SourceMapEntry;
+use syntax_tree::{Node, NodeType};
+use frame_stack::{Frame, FrameStackTree};
+use lint;
+use var_analyzer;
+use optimizer;
+
+#[derive(Default)]
+pub struct CompileOptions {
+  // report compile-time warnings
+  pub warnings: bool,
+  // also warn about empty loop and conditional bodies
+  pub warn_empty_bodies: bool,
+  // warn about reassigned parameters which closures capture
+  pub strict: bool,
+  // maximum length of symbol, number and string tokens
+  pub max_token_len: Option<usize>,
+  // statements may end at a line break without `;`
+  pub asi: bool,
+  // fold constant expressions and reuse repeated subexpressions
+  pub optimize: bool,
+  // number of errors reported before the rest are only counted
+  pub max_errors: Option<usize>,
+  // byte order and operand width of the bytecode
+  pub encoding: Encoding
+}
+
+pub struct CompileResult {
+  pub bytecode: Vec<u8>,
+  pub warnings: Vec<Diagnostic>,
+  pub source_map: Vec<SourceMapEntry>
+}
+
+// Tokenizes, parses and compiles the source text. Errors are returned
+// instead of being printed, the host decides how to present them.
+pub fn compile(text: &str, options: &CompileOptions, asm_file: Option<File>)
+               -> Result<CompileResult, Vec<Diagnostic>> {
+  let result = compile_text(text, options, asm_file);
+
+  match options.max_errors {
+    Some(max) => result.map_err(|errors| diagnostic::limit(errors, max)),
+    None => result
+  }
+}
+
+fn compile_text(text: &str, options: &CompileOptions, asm_file: Option<File>)
+                -> Result<CompileResult, Vec<Diagnostic>> {
+  let mut tokenizer = Tokenizer::new(text);
+
+  if let Some(len) = options.max_token_len {
+    tokenizer.set_max_token_len(len);
+  }
+
+  let mut ast = match tokenizer.tokenize_all() {
+    Ok(tokens) => {
+      let mut parser = Parser::new(tokens);
+      parser.set_asi(options.asi);
+      parser.try_parse().map_err(|err| vec![err])?
+    },
+    Err(errors) => return Err(errors)
+  };
+
+  let mut fstack = FrameStackTree::new();
+  var_analyzer::analyze_locals(&mut fstack, &mut ast);
+  fstack.reset();
+  let errors = var_analyzer::check_consts(&mut fstack, &mut ast);
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+
+  let mut warnings = vec![];
+
+  if options.warnings {
+    warnings.append(&mut lint::check_arity(&mut ast));
+    warnings.append(&mut lint::check_duplicate_keys(&mut ast));
+
+    if options.warn_empty_bodies {
+      warnings.append(&mut lint::check_empty_bodies(&mut ast));
+    }
+  }
+
+  if options.strict {
+    warnings.append(&mut lint::check_captured_writes(&mut ast));
+  }
+
+  if options.optimize {
+    optimizer::fold_constants(&mut ast);
+  }
+
+  let mut bytecode = vec![];
+
+  let source_map = {
+    let mut compiler = Compiler::with_encoding(&mut bytecode, asm_file.map(|f| Box::new(f) as Box<dyn Write>), options.encoding);
+    if options.optimize {
+      compiler.eliminate_common_subexpressions();
+      compiler.remove_redundant_instructions();
+    }
+    compiler.compile(&mut ast)?;
+    compiler.source_map().to_vec()
+  };
+
+  Ok(CompileResult {
+    bytecode: bytecode,
+    warnings: warnings,
+    source_map: source_map
+  })
+}
+
+// Like `compile`, for a program continuing one which has run in the same VM,
+// as the inputs of an interactive session: the code is appended to the code
+// of length `origin` and runs in the global frame left by the previous run.
+// `globals` holds the globals declared so far, the new ones are added to it
+// once the program compiles. Warnings are not reported.
+pub fn compile_continuation(text: &str, options: &CompileOptions, globals: &mut Frame, origin: u32)
+                            -> Result<CompileResult, Vec<Diagnostic>> {
+  let mut tokenizer = Tokenizer::new(text);
+
+  if let Some(len) = options.max_token_len {
+    tokenizer.set_max_token_len(len);
+  }
+
+  let mut parser = Parser::new(tokenizer.tokenize_all()?);
+  parser.set_asi(options.asi);
+  let mut ast = parser.try_parse().map_err(|err| vec![err])?;
+
+  let mut fstack = FrameStackTree::new();
+  *fstack.root_frame() = globals.clone();
+  var_analyzer::analyze_locals(&mut fstack, &mut ast);
+  fstack.reset();
+  let errors = var_analyzer::check_consts(&mut fstack, &mut ast);
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+  fstack.reset();
+  var_analyzer::analyze_globals(&mut fstack, &mut ast);
+  fstack.reset();
+
+  let declared = fstack.root_frame().clone();
+
+  if options.optimize {
+    optimizer::fold_constants(&mut ast);
+  }
+
+  let mut bytecode = vec![];
+
+  let source_map = {
+    let mut compiler = Compiler::with_encoding(&mut bytecode, None, options.encoding);
+    if options.optimize {
+      compiler.eliminate_common_subexpressions();
+      compiler.remove_redundant_instructions();
+    }
+    compiler.compile_continuation(&ast, fstack, origin)?;
+    compiler.source_map().to_vec()
+  };
+
+  *globals = declared;
+
+  Ok(CompileResult {
+    bytecode: bytecode,
+    warnings: vec![],
+    source_map: source_map
+  })
+}
+
+// Like `compile`, but only the tokens and the tree of a single top-level
+// statement are kept in memory. The text is tokenized and parsed again for
+// each var analysis pass and for the compiler, the output is the same as of
+// `compile`. Warnings are not reported.
+pub fn compile_streaming(text: &str, options: &CompileOptions, asm_file: Option<File>)
+                         -> Result<CompileResult, Vec<Diagnostic>> {
+  let result = compile_streaming_text(text, options, asm_file);
+
+  match options.max_errors {
+    Some(max) => result.map_err(|errors| diagnostic::limit(errors, max)),
+    None => result
+  }
+}
+
+fn compile_streaming_text(text: &str, options: &CompileOptions, asm_file: Option<File>)
+                          -> Result<CompileResult, Vec<Diagnostic>> {
+  let tokenizer = || {
+    let mut tokenizer = Tokenizer::new(text);
+    if let Some(len) = options.max_token_len {
+      tokenizer.set_max_token_len(len);
+    }
+    tokenizer
+  };
+
+  // the passes below skip nothing, all the lexical errors are reported here
+  tokenizer().check_all()?;
+
+  let mut fstack = FrameStackTree::new();
+  each_statement(&tokenizer, options.asi, |stmt| var_analyzer::analyze_locals(&mut fstack, stmt)).map_err(|err| vec![err])?;
+  fstack.reset();
+  let mut errors = vec![];
+  let mut consts = var_analyzer::ConstChecker::new();
+  each_statement(&tokenizer, options.asi, |stmt| errors.append(&mut consts.check(&mut fstack, stmt))).map_err(|err| vec![err])?;
+  fstack.reset();
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+  each_statement(&tokenizer, options.asi, |stmt| var_analyzer::analyze_globals(&mut fstack, stmt)).map_err(|err| vec![err])?;
+  fstack.reset();
+
+  let mut bytecode = vec![];
+
+  let source_map = {
+    let mut compiler = Compiler::with_encoding(&mut bytecode, asm_file.map(|f| Box::new(f) as Box<dyn Write>), options.encoding);
+    if options.optimize {
+      compiler.eliminate_common_subexpressions();
+      compiler.remove_redundant_instructions();
+    }
+
+    compiler.begin_streaming(fstack);
+    each_statement(&tokenizer, options.asi, |stmt| {
+      if options.optimize {
+        optimizer::fold_constants(stmt);
+      }
+      compiler.compile_statement(stmt);
+    }).map_err(|err| vec![err])?;
+    compiler.finish_streaming()?;
+
+    compiler.source_map().to_vec()
+  };
+
+  Ok(CompileResult {
+    bytecode: bytecode,
+    warnings: vec![],
+    source_map: source_map
+  })
+}
+
+// Parses the top-level statements one at a time. Function declarations are
+// hoisted to the top of the program, so they are all passed first.
+fn each_statement<'a, T, F>(tokens: &T, asi: bool, mut f: F) -> Result<(), Diagnostic>
+  where T: Fn() -> Tokenizer<'a>, F: FnMut(&mut Node) {
+  for &functions in [true, false].iter() {
+    let mut parser = Parser::new(tokens().filter_map(Result::ok));
+    parser.set_asi(asi);
+
+    while let Some(mut block) = parser.parse_next()? {
+      for stmt in block.body.iter_mut() {
+        if (stmt.type_ == NodeType::StmtFunction) == functions {
+          f(stmt);
+        }
+      }
+    }
+  }
+
+  Ok(())
+}
+
+// Reads and compiles a source file, every diagnostic is tagged with its path
+pub fn compile_file(path: &Path, options: &CompileOptions, asm_file: Option<File>)
+                    -> Result<CompileResult, Vec<Diagnostic>> {
+  let name = path.display().to_string();
+
+  let mut text = String::new();
+  if let Err(err) = File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+    let msg = format!("cannot read source file: {}", err);
+    return Err(vec![Diagnostic::error(msg, None).in_file(&name)]);
+  }
+
+  match compile(&text, options, asm_file) {
+    Ok(mut result) => {
+      result.warnings = result.warnings.into_iter().map(|w| w.in_file(&name)).collect();
+      Ok(result)
+    },
+    Err(errors) => Err(errors.into_iter().map(|e| e.in_file(&name)).collect())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use diagnostic::Severity;
+
+  #[test]
+  fn test_warnings() {
+    let text = "var f = fn(a, b) { return a; };
+      var g = fn() { return 1; };
+      f(1);
+      g(2);";
+    let options = CompileOptions { warnings: true, ..Default::default() };
+    let result = compile(text, &options, None).ok().unwrap();
+
+    assert!(!result.bytecode.is_empty());
+    assert_eq!(result.warnings.len(), 2);
+    assert!(result.warnings.iter().all(|w| w.severity == Severity::Warning));
+    assert!(result.warnings[0].message.contains("'f'"));
+    assert!(result.warnings[1].message.contains("'g'"));
+
+    let result = compile(text, &CompileOptions::default(), None).ok().unwrap();
+    assert!(result.warnings.is_empty());
+  }
+
+  #[test]
+  fn test_errors() {
+    let text = "var a = 1;\nvar b = @;";
+    for result in [compile(text, &CompileOptions::default(), None),
+                   compile_streaming(text, &CompileOptions::default(), None)].iter() {
+      let errors = result.as_ref().err().unwrap();
+      assert_eq!(errors.len(), 1);
+      assert_eq!(errors[0].severity, Severity::Error);
+      assert_eq!(errors[0].format(text),
+                 "error: Unknown character: @\n  --> 2:8\n  |\n2 | var b = @;\n  |         ^");
+    }
+
+    let text = "const a = 1;\nfn f() { a = 2; }";
+    for result in [compile(text, &CompileOptions::default(), None),
+                   compile_streaming(text, &CompileOptions::default(), None)].iter() {
+      let errors = result.as_ref().err().unwrap();
+      assert_eq!(errors[0].to_string(), "error at line 2 column 9: cannot assign to 'a', it is declared as a constant");
+    }
+
+    let text = "const a = 1;\nvar b = 2;\nvar a = 3;";
+    for result in [compile(text, &CompileOptions::default(), None),
+                   compile_streaming(text, &CompileOptions::default(), None)].iter() {
+      let errors = result.as_ref().err().unwrap();
+      assert_eq!(errors[0].to_string(), "error at line 3 column 4: cannot redeclare 'a', it is declared as a constant");
+    }
+
+    let text = "var a = x;\nvar b = y;";
+    for result in [compile(text, &CompileOptions::default(), None),
+                   compile_streaming(text, &CompileOptions::default(), None)].iter() {
+      let errors = result.as_ref().err().unwrap();
+      assert_eq!(errors.len(), 2);
+      assert_eq!(errors[1].to_string(), "error at line 2 column 8: No such variable: y");
+    }
+  }
+
+  #[test]
+  fn test_compile_file() {
+    let path = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/unknown_char.js"));
+    let errors = compile_file(path, &CompileOptions::default(), None).err().unwrap();
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].file, Some(path.display().to_string()));
+    assert_eq!(errors[0].to_string(),
+               format!("{}:3:10: error: Unknown character: #", path.display()));
+
+    let errors = compile_file(Path::new("missing.js"), &CompileOptions::default(), None)
+      .err().unwrap();
+    assert!(errors[0].to_string().starts_with("missing.js: error: cannot read source file"));
+  }
+
+  #[test]
+  fn test_deterministic_output() {
+    let text = concat!(include_str!("../samples/qsort.js"), "
+      var d = {b: 1.5, a: -0.1, 'ключ': [1000, 'x']};
+      outer: for (var k in d) { if (k == 'a') { break outer; } }
+      var x = d.b * d.b + d.b * d.b;");
+
+    for &optimize in [false, true].iter() {
+      let options = CompileOptions { optimize: optimize, ..Default::default() };
+      let first = compile(text, &options, None).ok().unwrap();
+      let second = compile(text, &options, None).ok().unwrap();
+      assert_eq!(first.bytecode, second.bytecode);
+      assert_eq!(first.source_map, second.source_map);
+    }
+
+    // operands are little-endian regardless of the host
+    let code = compile("var x = 1.5;", &CompileOptions::default(), None).ok().unwrap().bytecode;
+    assert!(code.windows(5).any(|w| w == [0x20, 0x00, 0x00, 0xc0, 0x3f]));
+  }
+
+  #[test]
+  fn test_streaming() {
+    let mut text = String::from(include_str!("../samples/qsort.js"));
+    for i in 0..200 {
+      text += &format!("
+        var v{0} = make({0});
+        {{ total = total + v{0}(); function make(n) {{ var k = n * 2; return fn() {{ return k; }}; }} }}
+        if (v{0}() > 100) {{ big{0} = 1; }}", i);
+    }
+    text += "
+      fn used_early() { return total; }
+      outer: while (1) { break outer; }
+      std.io.println(used_early(), v5(), {a: 'x'}.a);";
+    let text = text.replacen("var v0", "var total = 0; var v0", 1);
+
+    for &optimize in [false, true].iter() {
+      let options = CompileOptions { optimize: optimize, ..Default::default() };
+      let batch = compile(&text, &options, None).ok().unwrap();
+      let streaming = compile_streaming(&text, &options, None).ok().unwrap();
+      assert!(batch.bytecode == streaming.bytecode);
+      assert_eq!(batch.source_map, streaming.source_map);
+    }
+
+    let options = CompileOptions { asi: true, ..Default::default() };
+    let text = "var a = 1\nfn f() { return a }\nstd.io.print(f())";
+    assert!(compile(text, &options, None).ok().unwrap().bytecode ==
+            compile_streaming(text, &options, None).ok().unwrap().bytecode);
+    assert!(compile(text, &CompileOptions::default(), None).is_err());
+
+    let errors = compile_streaming("var a = 1;\nvar b = ;", &CompileOptions::default(), None).err().unwrap();
+    assert_eq!(errors[0].pos.unwrap().line, 2);
+  }
+
+  #[test]
+  fn test_max_errors() {
+    let text = "var a = @;\nvar b = #;\nvar c = $ + `;";
+    let options = CompileOptions { max_errors: Some(2), ..Default::default() };
+    let errors = compile(text, &options, None).err().unwrap();
+
+    assert_eq!(errors.len(), 3);
+    assert_eq!(errors[1].pos.map(|p| p.line), Some(2));
+    assert_eq!(errors[2].severity, Severity::Note);
+    assert_eq!(errors[2].to_string(), "note: ... and 2 more errors");
+
+    assert_eq!(compile(text, &CompileOptions::default(), None).err().unwrap().len(), 4);
+  }
+
+  #[test]
+  fn test_encoding_overflow() {
+    let text = "var a = 0;\n".to_string() + &"a = a + 1;\n".repeat(5000);
+    let options = CompileOptions { encoding: Encoding::from_name("le16").unwrap(), ..Default::default() };
+    let errors = compile(&text, &options, None).err().unwrap();
+    assert_eq!(errors[0].to_string(), "error: program too large for 16-bit encoding");
+
+    assert!(compile(&text, &CompileOptions::default(), None).is_ok());
+    assert!(compile("var a = 0;", &options, None).is_ok());
+  }
+}
@@ -0,0 +1,137 @@
+use tokenizer::{Keyword, Tokenizer, TokenType};
+use parser::Parser;
+use pipeline::{self, CompileOptions};
+use frame_stack::Frame;
+use vm::Vm;
+
+// Interactive session. Every input is compiled on its own against the globals
+// declared so far, its code is appended to the VM's and run in the same global
+// frame, so the values and the effects of the previous inputs are kept.
+pub struct Repl {
+  vm: Vm,
+  globals: Frame,
+  // length of the output already returned
+  shown: usize
+}
+
+impl Repl {
+  pub fn new() -> Repl {
+    // an empty program leaves the global frame on the stack
+    let result = pipeline::compile("", &CompileOptions::default(), None).ok().unwrap();
+    let mut vm = Vm::new(result.bytecode);
+    vm.capture_output();
+    vm.run().unwrap();
+
+    Repl {
+      vm: vm,
+      globals: Frame::new(),
+      shown: 0
+    }
+  }
+
+  // Whether an input called `std.exit`, nothing runs after that
+  pub fn exited(&self) -> bool {
+    self.vm.halted()
+  }
+
+  // Evaluates one line of input and returns the output it produced.
+  // Expressions are printed, statements don't need a trailing `;`.
+  pub fn eval(&mut self, input: &str) -> Result<String, String> {
+    let input = input.trim();
+
+    let stmt = {
+      let mut tokenizer = Tokenizer::new(input);
+      let tokens = tokenizer.tokenize().map_err(|err| err.format(input))?;
+
+      let types: Vec<&TokenType> = tokens.iter()
+        .map(|t| &t.type_)
+        .filter(|&t| *t != TokenType::Eof)
+        .collect();
+
+      if types.is_empty() {
+        return Ok(String::new());
+      }
+
+      if Parser::new(tokens.iter().cloned()).parse_single_expression().is_some() {
+        format!("std.io.println({});\n", input.trim_end_matches(';'))
+      } else {
+        // `if`, `while` and `switch` end with a block and must not be followed by `;`
+        let first = tokens.front().map(|t| &t.type_);
+        let needs_end = types.last() != Some(&&TokenType::End) &&
+          first != Some(&TokenType::Keyword(Keyword::If)) &&
+          first != Some(&TokenType::Keyword(Keyword::While)) &&
+          first != Some(&TokenType::Keyword(Keyword::Switch));
+
+        format!("{}{}\n", input, if needs_end { ";" } else { "" })
+      }
+    };
+
+    let origin = self.vm.code_len() as u32;
+    let result = pipeline::compile_continuation(&stmt, &CompileOptions::default(), &mut self.globals, origin)
+      .map_err(|errors| {
+        errors.iter().map(|err| err.format(&stmt)).collect::<Vec<_>>().join("\n")
+      })?;
+
+    self.vm.extend(&result.bytecode, self.globals.var_offsets.len());
+    let run = self.vm.run();
+
+    let output = self.vm.output()[self.shown..].to_string();
+    self.shown = self.vm.output().len();
+
+    // the output printed before a runtime error is shown with it
+    match run {
+      Ok(()) => Ok(output),
+      Err(err) => Err(output + &err.to_string())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_eval() {
+    let mut repl = Repl::new();
+
+    assert_eq!(repl.eval("1 + 2"), Ok("3\n".to_string()));
+    assert_eq!(repl.eval("var x = 3"), Ok("".to_string()));
+    assert_eq!(repl.eval("x * 2;"), Ok("6\n".to_string()));
+    assert_eq!(repl.eval("x = x + 1"), Ok("".to_string()));
+    assert_eq!(repl.eval("if (x > 3) { std.io.println('big'); }"), Ok("big\n".to_string()));
+    assert_eq!(repl.eval("var f = fn(a) { return a + x; }"), Ok("".to_string()));
+    assert_eq!(repl.eval("f(1)"), Ok("5\n".to_string()));
+    assert_eq!(repl.eval(""), Ok("".to_string()));
+
+    // failed inputs are not kept in the session
+    assert!(repl.eval("f(1)(2)").is_err());
+    assert_eq!(repl.eval("x"), Ok("4\n".to_string()));
+    assert!(repl.eval("var y = @").is_err());
+    assert!(repl.eval("y").is_err());
+  }
+
+  #[test]
+  fn test_effects_once() {
+    let mut repl = Repl::new();
+
+    // earlier inputs are not run again
+    assert_eq!(repl.eval("var n = 0"), Ok("".to_string()));
+    assert_eq!(repl.eval("std.io.println('hi'); n = n + 1"), Ok("hi\n".to_string()));
+    assert_eq!(repl.eval("n = n + 1"), Ok("".to_string()));
+    assert_eq!(repl.eval("n"), Ok("2\n".to_string()));
+
+    // closures keep the frames of the earlier inputs
+    assert_eq!(repl.eval("var count = fn() { n = n + 1; return n; }"), Ok("".to_string()));
+    assert_eq!(repl.eval("count() + count()"), Ok("7\n".to_string()));
+    assert_eq!(repl.eval("function twice(x) { return 2 * x; }"), Ok("".to_string()));
+    assert_eq!(repl.eval("twice(n)"), Ok("8\n".to_string()));
+
+    // a runtime error keeps what ran before it
+    assert_eq!(repl.eval("std.io.println(n); n = n + 1; n(1)").map_err(|e| e.starts_with("4\n")), Err(true));
+    assert_eq!(repl.eval("n"), Ok("5\n".to_string()));
+
+    assert!(!repl.exited());
+    assert_eq!(repl.eval("std.exit(3)"), Ok("".to_string()));
+    assert!(repl.exited());
+  }
+}
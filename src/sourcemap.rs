@@ -0,0 +1,82 @@
+use diagnostic::Position;
+use util::json_string;
+
+// Source position an instruction was compiled from
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceMapEntry {
+  pub ip: u32,
+  pub pos: Position,
+  // name of the containing function when it's bound to a variable
+  pub function: Option<String>
+}
+
+// Formats the map as a JSON array, one instruction per line:
+// {"offset": 10, "line": 2, "col": 5, "function": "foo"}
+pub fn to_json(entries: &[SourceMapEntry]) -> String {
+  let lines: Vec<String> = entries.iter().map(|e| {
+    let function = match e.function {
+      Some(ref name) => format!(", \"function\": {}", json_string(name)),
+      None => String::new()
+    };
+    format!("  {{\"offset\": {}, \"line\": {}, \"col\": {}{}}}", e.ip, e.pos.line, e.pos.col, function)
+  }).collect();
+
+  format!("[\n{}\n]\n", lines.join(",\n"))
+}
+
+// Offsets of the instructions starting a new source line, with the line:
+// the instructions up to the next offset belong to it
+pub fn line_table(entries: &[SourceMapEntry]) -> Vec<(u32, usize)> {
+  let mut table: Vec<(u32, usize)> = vec![];
+
+  for e in entries.iter() {
+    if table.last().map(|&(_, line)| line) != Some(e.pos.line) {
+      table.push((e.ip, e.pos.line));
+    }
+  }
+
+  table
+}
+
+// Formats the line table as text, one `offset line` pair per line
+pub fn line_table_text(table: &[(u32, usize)]) -> String {
+  table.iter().map(|&(ip, line)| format!("{:05} {}\n", ip, line)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pipeline::{compile, CompileOptions};
+  use assembler::OpCode;
+
+  #[test]
+  fn test_source_map() {
+    let text = "var a = 1;\nvar f = fn() {\n  return 'x';\n};";
+    let result = compile(text, &CompileOptions::default(), None).ok().unwrap();
+
+    // instruction pushing the returned string
+    let entry = result.source_map.iter()
+      .find(|e| result.bytecode[e.ip as usize] == OpCode::PushStr as u8)
+      .unwrap();
+    assert_eq!(entry.pos.line, 3);
+    assert_eq!(entry.function, Some("f".to_string()));
+
+    let json = to_json(&result.source_map);
+    let line = format!("{{\"offset\": {}, \"line\": 3, \"col\": {}, \"function\": \"f\"}}",
+                       entry.ip, entry.pos.col);
+    assert!(json.contains(&line));
+    assert!(json.starts_with("[\n  {\"offset\": ") && json.contains("\"line\": 1,"));
+  }
+
+  #[test]
+  fn test_line_table() {
+    let result = compile("var a = 1;\nvar b = a + 2;", &CompileOptions::default(), None).ok().unwrap();
+    let table = line_table(&result.source_map);
+
+    assert_eq!(table.len(), 2);
+    assert_eq!(table[0].1, 1);
+    assert_eq!(table[1].1, 2);
+    assert!(table[0].0 < table[1].0);
+    assert_eq!(line_table_text(&table), format!("{:05} 1\n{:05} 2\n", table[0].0, table[1].0));
+  }
+}
@@ -1,6 +1,10 @@
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
 
-#[derive(Copy, Clone, PartialEq)]
+use diagnostic::Span;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub enum OpType {
   OpPlus,
   OpMinus,
@@ -15,38 +19,166 @@ pub enum OpType {
   OpLsEq,
   OpGtEq,
   OpEq,
-  OpNotEq
+  OpNotEq,
+  OpVoid,
+  OpNullish,
+  OpTypeof,
+  OpBitAnd,
+  OpBitOr,
+  OpBitXor,
+  OpBitNot,
+  OpShl,
+  OpShr
 }
 
 impl fmt::Debug for OpType {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-    let names = [ "+", "-", "*", "/", "%", "||", "&&", "!", "<", ">", "<=", ">=", "==", "!=" ];
+    let names = [ "+", "-", "*", "/", "%", "||", "&&", "!", "<", ">", "<=", ">=", "==", "!=", "void", "??", "typeof",
+                  "&", "|", "^", "~", "<<", ">>" ];
     write!(f, "{}", names[*self as usize])
   }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug)]
 pub enum NodeType {
   Number(f32),
   String(String),
+  // `null` and `undefined` are the same value, the VM's Null
+  Null,
+  Undefined,
+  // result of a comparison folded at compile time, there is no literal in the source
+  Boolean(bool),
   Symbol(String),
   Function,
   Call,
+  // [key, value, key, value, ...], keys are Symbol, String or Number
+  // nodes, or ComputedKey for `[expr]: value`
   Dict,
+  // key of a dict pair evaluated at runtime: [expression]
+  ComputedKey,
   Array,
   StmtVar, StmtIf, StmtIfElse, StmtWhile, StmtReturn,
+  // `const x = ...`, a var which can't be assigned to later
+  StmtConst,
+  // named function declaration, hoisted to the top of its block
+  StmtFunction,
+  StmtLabel, StmtBreak, StmtContinue,
+  // `do ... while (cond);`: [Block, condition]
+  StmtDoWhile,
+  // `switch (x) { ... }`: [discriminant, SwitchCase...]
+  StmtSwitch,
+  // `case value: ...`: [value, Block], `default:` has an Empty value
+  SwitchCase,
+  // `for (init; cond; step) ...`: [StmtVar (Block of them if there are
+  // several), Assign or expression, condition, step expression, Block],
+  // missing parts are Empty
+  StmtFor,
+  // `for (var k in x) ...`: [StmtVar or Assign with the target, container, Block]
+  StmtForIn,
   Member,
+  // member access yielding null for a null object (`a?.b`)
+  OptMember,
   Index,
   Op(OpType),
+  // `cond ? a : b`: [condition, value if true, value if false]
+  Ternary,
+  // `++x`, `x--`: [target], adds (OpPlus) or subtracts (OpMinus) one
+  // and yields the new value, or the old one for a postfix update
+  Update { op: OpType, postfix: bool },
   Assign,
   Block,
+  // block in expression position: [Block, value or Empty]
+  BlockExpr,
   Empty
 }
 
+// Numbers are compared by their bits, so a NaN equals itself and 0 is
+// told apart from -0, which folding must not mix up (1 / -0 is -Infinity)
+impl PartialEq for NodeType {
+  fn eq(&self, other: &NodeType) -> bool {
+    match (self, other) {
+      (&NodeType::Number(a), &NodeType::Number(b)) => a.to_bits() == b.to_bits(),
+      (&NodeType::String(ref a), &NodeType::String(ref b)) |
+      (&NodeType::Symbol(ref a), &NodeType::Symbol(ref b)) => a == b,
+      (&NodeType::Boolean(a), &NodeType::Boolean(b)) => a == b,
+      (&NodeType::Op(a), &NodeType::Op(b)) => a == b,
+      (&NodeType::Update { op, postfix }, &NodeType::Update { op: other_op, postfix: other_postfix }) =>
+        op == other_op && postfix == other_postfix,
+      _ => mem::discriminant(self) == mem::discriminant(other)
+    }
+  }
+}
+
+impl Hash for NodeType {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    mem::discriminant(self).hash(state);
+
+    match self {
+      &NodeType::Number(n) => n.to_bits().hash(state),
+      &NodeType::String(ref s) | &NodeType::Symbol(ref s) => s.hash(state),
+      &NodeType::Boolean(b) => b.hash(state),
+      &NodeType::Op(op) => op.hash(state),
+      &NodeType::Update { op, postfix } => (op, postfix).hash(state),
+      _ => {}
+    }
+  }
+}
+
+// Renders terms and operators as they are written in the source, other
+// nodes by their keyword or a short name
+impl fmt::Display for NodeType {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let name = match *self {
+      NodeType::Number(n) => return write!(f, "{}", n),
+      NodeType::String(ref s) => return write!(f, "'{}'", s.replace('\\', "\\\\").replace('\'', "\\'")),
+      NodeType::Symbol(ref s) => return write!(f, "{}", s),
+      NodeType::Null => "null",
+      NodeType::Undefined => "undefined",
+      NodeType::Boolean(true) => "true",
+      NodeType::Boolean(false) => "false",
+      NodeType::Op(op) => return write!(f, "{:?}", op),
+      NodeType::Update { op, postfix: false } => return write!(f, "{:?}{:?}x", op, op),
+      NodeType::Update { op, postfix: true } => return write!(f, "x{:?}{:?}", op, op),
+      NodeType::Function => "fn",
+      NodeType::Call => "call",
+      NodeType::Dict => "dict",
+      NodeType::ComputedKey => "[key]",
+      NodeType::Array => "array",
+      NodeType::StmtVar => "var",
+      NodeType::StmtConst => "const",
+      NodeType::StmtFunction => "function",
+      NodeType::StmtIf => "if",
+      NodeType::StmtIfElse => "if else",
+      NodeType::StmtWhile => "while",
+      NodeType::StmtDoWhile => "do while",
+      NodeType::StmtSwitch => "switch",
+      NodeType::SwitchCase => "case",
+      NodeType::StmtFor => "for",
+      NodeType::StmtForIn => "for in",
+      NodeType::StmtReturn => "return",
+      NodeType::StmtLabel => "label",
+      NodeType::StmtBreak => "break",
+      NodeType::StmtContinue => "continue",
+      NodeType::Member => ".",
+      NodeType::OptMember => "?.",
+      NodeType::Index => "[]",
+      NodeType::Ternary => "?:",
+      NodeType::Assign => "=",
+      NodeType::Block => "block",
+      NodeType::BlockExpr => "block expr",
+      NodeType::Empty => "empty"
+    };
+
+    write!(f, "{}", name)
+  }
+}
+
 #[derive(Clone, Debug)]
 pub struct Node {
   pub type_: NodeType,
   pub body: Vec<Node>,
+  // location in the source, if the node comes from the parser
+  pub span: Option<Span>
 }
 
 #[allow(unused_variables)]
@@ -76,9 +208,65 @@ pub trait Visitor {
   fn visit(&mut self, node: &mut Node) {}
 }
 
+// Bottom-up tree rewriting: each method is called after the node's children
+// have been transformed and may return a node to replace the current one.
+// Node-specific methods take precedence over the generic `transform`.
+#[allow(unused_variables)]
+pub trait TransformVisitor {
+  fn transform_term(&mut self, node: &mut Node) -> Option<Node> { None }
+  fn transform_fun(&mut self, node: &mut Node) -> Option<Node> { None }
+  fn transform_call(&mut self, node: &mut Node) -> Option<Node> { None }
+  fn transform_var(&mut self, node: &mut Node) -> Option<Node> { None }
+  fn transform_if(&mut self, node: &mut Node) -> Option<Node> { None }
+  fn transform_while(&mut self, node: &mut Node) -> Option<Node> { None }
+  fn transform_return(&mut self, node: &mut Node) -> Option<Node> { None }
+  fn transform_expr(&mut self, node: &mut Node) -> Option<Node> { None }
+  fn transform_assign(&mut self, node: &mut Node) -> Option<Node> { None }
+  fn transform_block(&mut self, node: &mut Node) -> Option<Node> { None }
+
+  fn transform(&mut self, node: &mut Node) -> Option<Node> { None }
+}
+
+// Structural equality, nodes parsed from different places are equal
+// if their subtrees are
+impl PartialEq for Node {
+  fn eq(&self, other: &Node) -> bool {
+    self.type_ == other.type_ && self.body == other.body
+  }
+}
+
+// Numbers are compared by their bits, so equality is reflexive even for NaN
+impl Eq for Node {}
+
+impl Hash for Node {
+  fn hash<H: Hasher>(&self, state: &mut H) {
+    self.type_.hash(state);
+    self.body.hash(state);
+  }
+}
+
 impl Node {
   pub fn new(type_: NodeType) -> Node {
-    Node { type_: type_, body: vec![] }
+    Node { type_: type_, body: vec![], span: None }
+  }
+
+  pub fn new_at(type_: NodeType, span: Span) -> Node {
+    Node { type_: type_, body: vec![], span: Some(span) }
+  }
+
+  // Number of nodes in the tree, the span of its indices in visiting order
+  pub fn tree_size(&self) -> usize {
+    1 + self.body.iter().map(Node::tree_size).sum::<usize>()
+  }
+
+  // Indices of the children in visiting (pre-)order, the node's own being `index`
+  pub fn child_indices(&self, index: usize) -> Vec<usize> {
+    let mut next = index + 1;
+    self.body.iter().map(|child| {
+      let child_index = next;
+      next += child.tree_size();
+      child_index
+    }).collect()
   }
 
   pub fn visit(&mut self, visitor: &mut Visitor) {
@@ -91,7 +279,7 @@ impl Node {
         visitor.enter_fun(self),
       NodeType::Call =>
         visitor.enter_call(self),
-      NodeType::StmtVar =>
+      NodeType::StmtVar | NodeType::StmtConst | NodeType::StmtFunction =>
         visitor.enter_var(self),
       NodeType::StmtIf | NodeType::StmtIfElse =>
         visitor.enter_if(self),
@@ -123,7 +311,7 @@ impl Node {
         visitor.exit_fun(self),
       NodeType::Call =>
         visitor.exit_call(self),
-      NodeType::StmtVar =>
+      NodeType::StmtVar | NodeType::StmtConst | NodeType::StmtFunction =>
         visitor.exit_var(self),
       NodeType::StmtIf | NodeType::StmtIfElse =>
         visitor.exit_if(self),
@@ -140,5 +328,123 @@ impl Node {
       _ => {}
     }
   }
+
+  pub fn transform(&mut self, visitor: &mut dyn TransformVisitor) {
+    for ch in self.body.iter_mut() {
+      ch.transform(visitor);
+    }
+
+    let replacement = match self.type_ {
+      NodeType::Number(_) |
+      NodeType::String(_) |
+      NodeType::Symbol(_) =>
+        visitor.transform_term(self),
+      NodeType::Function =>
+        visitor.transform_fun(self),
+      NodeType::Call =>
+        visitor.transform_call(self),
+      NodeType::StmtVar | NodeType::StmtConst | NodeType::StmtFunction =>
+        visitor.transform_var(self),
+      NodeType::StmtIf | NodeType::StmtIfElse =>
+        visitor.transform_if(self),
+      NodeType::StmtWhile =>
+        visitor.transform_while(self),
+      NodeType::StmtReturn =>
+        visitor.transform_return(self),
+      NodeType::Op(_) =>
+        visitor.transform_expr(self),
+      NodeType::Assign =>
+        visitor.transform_assign(self),
+      NodeType::Block =>
+        visitor.transform_block(self),
+      _ => None
+    };
+
+    let replacement = replacement.or_else(|| visitor.transform(self));
+
+    if let Some(node) = replacement {
+      *self = node;
+    }
+  }
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokenizer::Tokenizer;
+  use parser::Parser;
+
+  struct Increment;
+
+  impl TransformVisitor for Increment {
+    fn transform_term(&mut self, node: &mut Node) -> Option<Node> {
+      match node.type_ {
+        NodeType::Number(n) => Some(Node::new(NodeType::Number(n + 1.0))),
+        _ => None
+      }
+    }
+  }
+
+  struct Numbers(Vec<f32>);
+
+  impl Visitor for Numbers {
+    fn enter_term(&mut self, node: &mut Node) {
+      if let NodeType::Number(n) = node.type_ {
+        self.0.push(n);
+      }
+    }
+  }
+
+  #[test]
+  fn test_transform() {
+    let mut ast = Parser::new(Tokenizer::new("var a = [1, 2 * 3]; f(a[0] + 10);")
+                              .tokenize().unwrap()).parse();
+    ast.transform(&mut Increment);
+
+    let mut numbers = Numbers(vec![]);
+    ast.visit(&mut numbers);
+    assert_eq!(numbers.0, vec![2.0, 3.0, 4.0, 1.0, 11.0]);
+  }
+
+  #[test]
+  fn test_display() {
+    assert_eq!(NodeType::Number(3.0).to_string(), "3");
+    assert_eq!(NodeType::Number(0.5).to_string(), "0.5");
+    assert_eq!(NodeType::String("it's".to_string()).to_string(), "'it\\'s'");
+    assert_eq!(NodeType::Symbol("a".to_string()).to_string(), "a");
+    assert_eq!(NodeType::Op(OpType::OpPlus).to_string(), "+");
+    assert_eq!(NodeType::Op(OpType::OpLsEq).to_string(), "<=");
+    assert_eq!(NodeType::Update { op: OpType::OpMinus, postfix: true }.to_string(), "x--");
+    assert_eq!(NodeType::StmtWhile.to_string(), "while");
+    assert_eq!(NodeType::Index.to_string(), "[]");
+
+    // Debug still names the variant
+    assert_eq!(format!("{:?}", NodeType::Symbol("a".to_string())), "Symbol(\"a\")");
+  }
+
+  #[test]
+  fn test_structural_eq() {
+    use std::collections::hash_map::DefaultHasher;
+
+    let parse = |text: &str| Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse();
+    let hash = |node: &Node| {
+      let mut hasher = DefaultHasher::new();
+      node.hash(&mut hasher);
+      hasher.finish()
+    };
+
+    let a = parse("a + b * c;");
+    let b = parse("\n  a +\n b*c;");
+    assert!(a.body[0].span != b.body[0].span);
+    assert_eq!(a, b);
+    assert_eq!(hash(&a), hash(&b));
+
+    assert!(a != parse("a + b * d;"));
+    assert!(a != parse("(a + b) * c;"));
+
+    let nan = Node::new(NodeType::Number(::std::f32::NAN));
+    assert_eq!(nan, nan.clone());
+    assert_eq!(hash(&nan), hash(&nan.clone()));
+    assert!(Node::new(NodeType::Number(0.0)) != Node::new(NodeType::Number(-0.0)));
+  }
+}
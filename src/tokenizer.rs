@@ -1,55 +1,215 @@
-use std::collections::LinkedList;
+use std::collections::{LinkedList, VecDeque};
 use std::iter::Peekable;
+use std::mem;
 use std::str::CharIndices;
 
+use diagnostic::{Diagnostic, Position, Span};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Keyword {
+  Var, Const, Fn, Function, If, Else, While, Do, For, In,
+  Switch, Case, Default, Return, Break, Continue, Void, Typeof, Null, Undefined
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum TokenType {
-  Sym, Str, Num,
-  OpPlus, OpMinus, OpMul, OpDiv, OpMod,
-  OpOr, OpAnd, OpNot, OpLs, OpGt, OpLsEq, OpGtEq, OpEq, OpNotEq,
+  Sym, Keyword(Keyword), Str, Num,
+  OpPlus, OpMinus, OpMul, OpDiv, OpMod, OpInc, OpDec,
+  OpOr, OpAnd, OpNullish, OpNot, OpLs, OpGt, OpLsEq, OpGtEq, OpEq, OpNotEq,
+  OpBitAnd, OpBitOr, OpBitXor, OpBitNot, OpShl, OpShr,
   Assign,
+  AssignPlus, AssignMinus, AssignMul, AssignDiv, AssignMod,
+  AssignBitAnd, AssignBitOr, AssignBitXor, AssignShl, AssignShr,
   Comma,
-  Dot,
-  Colon,
+  Dot, OptDot,
+  Colon, Question, Arrow,
   End,
   LBr, RBr,
   LBlock, RBlock,
   LPar, RPar,
   Comment,
+  BlockComment,
   Empty,
   Eof
 }
 
+// Token classes for syntax highlighting
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TokenCategory {
+  Keyword,
+  Identifier,
+  Literal,
+  Operator,
+  Punctuation,
+  Comment,
+  Other
+}
+
+// Reserved words, which can't be used as variable names
+pub const KEYWORDS: [(&'static str, Keyword); 20] = [
+  ("var", Keyword::Var), ("const", Keyword::Const), ("fn", Keyword::Fn), ("function", Keyword::Function),
+  ("if", Keyword::If), ("else", Keyword::Else), ("while", Keyword::While), ("do", Keyword::Do),
+  ("for", Keyword::For), ("in", Keyword::In), ("switch", Keyword::Switch),
+  ("case", Keyword::Case), ("default", Keyword::Default), ("return", Keyword::Return),
+  ("break", Keyword::Break), ("continue", Keyword::Continue),
+  ("void", Keyword::Void), ("typeof", Keyword::Typeof),
+  ("null", Keyword::Null), ("undefined", Keyword::Undefined)
+];
+
+impl Keyword {
+  pub fn from_name(name: &str) -> Option<Keyword> {
+    KEYWORDS.iter().find(|&&(n, _)| n == name).map(|&(_, k)| k)
+  }
+}
+
+impl TokenType {
+  pub fn category(&self) -> TokenCategory {
+    match *self {
+      TokenType::Sym => TokenCategory::Identifier,
+      TokenType::Keyword(_) => TokenCategory::Keyword,
+      TokenType::Str | TokenType::Num => TokenCategory::Literal,
+      TokenType::OpPlus | TokenType::OpMinus | TokenType::OpMul | TokenType::OpDiv |
+      TokenType::OpMod | TokenType::OpInc | TokenType::OpDec |
+      TokenType::OpOr | TokenType::OpAnd | TokenType::OpNullish |
+      TokenType::OpNot | TokenType::OpLs | TokenType::OpGt | TokenType::OpLsEq |
+      TokenType::OpGtEq | TokenType::OpEq | TokenType::OpNotEq |
+      TokenType::OpBitAnd | TokenType::OpBitOr | TokenType::OpBitXor | TokenType::OpBitNot |
+      TokenType::OpShl | TokenType::OpShr |
+      TokenType::Assign | TokenType::AssignPlus | TokenType::AssignMinus |
+      TokenType::AssignMul | TokenType::AssignDiv | TokenType::AssignMod |
+      TokenType::AssignBitAnd | TokenType::AssignBitOr | TokenType::AssignBitXor |
+      TokenType::AssignShl | TokenType::AssignShr |
+      TokenType::Dot | TokenType::OptDot | TokenType::Question | TokenType::Arrow => TokenCategory::Operator,
+      TokenType::Comma | TokenType::Colon | TokenType::End |
+      TokenType::LBr | TokenType::RBr | TokenType::LBlock | TokenType::RBlock |
+      TokenType::LPar | TokenType::RPar => TokenCategory::Punctuation,
+      TokenType::Comment | TokenType::BlockComment => TokenCategory::Comment,
+      TokenType::Empty | TokenType::Eof => TokenCategory::Other
+    }
+  }
+}
+
 #[derive(Clone)]
 pub struct Token<'a> {
   pub type_: TokenType,
   pub text: &'a str,
   pub line: usize,
   pub col: usize,
+  // position right past the last character
+  pub end_line: usize,
+  pub end_col: usize
 }
 
 impl<'a> Token<'a> {
+  // The token is empty until the tokenizer commits it and sets the end
   pub fn new(t: TokenType, text: &'a str, line: usize, col: usize) -> Token<'a> {
     Token {
       type_: t,
       text: text,
       line: line,
-      col: col
+      col: col,
+      end_line: line,
+      end_col: col
     }
   }
 
   pub fn new_empty() -> Token<'a> {
-    Token {
-      type_: TokenType::Empty,
-      text: "", 
-      line: 0,
-      col: 0
+    Token::new(TokenType::Empty, "", 0, 0)
+  }
+
+  pub fn span(&self) -> Span {
+    Span {
+      start: Position { line: self.line, col: self.col },
+      end: Position { line: self.end_line, col: self.end_col }
     }
   }
 
   pub fn as_sym(&self) -> Option<&str> {
     if self.type_ == TokenType::Sym { Some(self.text) } else { None }
   }
+
+  pub fn category(&self) -> TokenCategory {
+    self.type_.category()
+  }
+
+  pub fn is_keyword(&self, keyword: Keyword) -> bool {
+    self.type_ == TokenType::Keyword(keyword)
+  }
+}
+
+// Escape sequences in strings besides \uXXXX, and the characters they stand for
+const ESCAPES: [(char, char); 4] = [('n', '\n'), ('t', '\t'), ('\'', '\''), ('\\', '\\')];
+
+// Value of a string token: the quotes are stripped and escapes decoded.
+// The escapes are expected to be valid, as checked by the tokenizer.
+pub fn string_value(text: &str) -> String {
+  let mut value = String::new();
+  let mut chars = text[1..text.len() - 1].chars();
+
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      value.push(c);
+      continue;
+    }
+
+    match chars.next() {
+      Some('u') => {
+        let code: String = chars.by_ref().take(4).collect();
+        value.extend(u32::from_str_radix(&code, 16).ok().and_then(char::from_u32));
+      },
+      Some(e) => value.extend(ESCAPES.iter().find(|&&(c, _)| c == e).map(|&(_, c)| c)),
+      None => {}
+    }
+  }
+
+  value
+}
+
+// Radix of a number token, given by the 0x, 0o or 0b prefix
+fn number_radix(text: &str) -> u32 {
+  match text.get(..2) {
+    Some("0x") => 16,
+    Some("0o") => 8,
+    Some("0b") => 2,
+    _ => 10
+  }
+}
+
+// Value of a number token, checked by the tokenizer. Digit separators are skipped.
+pub fn number_value(text: &str) -> f32 {
+  let digits = text.replace('_', "");
+
+  match number_radix(&digits) {
+    10 => digits.parse::<f32>().unwrap(),
+    radix => u32::from_str_radix(&digits[2..], radix).unwrap() as f32
+  }
+}
+
+// Token which doesn't borrow the source text
+#[derive(Clone, Debug, PartialEq)]
+pub struct OwnedToken {
+  pub type_: TokenType,
+  pub text: String,
+  pub line: usize,
+  pub col: usize
+}
+
+impl<'a, 'b> From<&'b Token<'a>> for OwnedToken {
+  fn from(t: &'b Token<'a>) -> OwnedToken {
+    OwnedToken {
+      type_: t.type_.clone(),
+      text: t.text.to_string(),
+      line: t.line,
+      col: t.col
+    }
+  }
+}
+
+// Tokenizes the source into tokens which can outlive it
+pub fn tokenize_owned(src: &str) -> Result<Vec<OwnedToken>, Diagnostic> {
+  let mut tokenizer = Tokenizer::new(src);
+  let tokens = tokenizer.tokenize()?;
+  Ok(tokens.iter().map(OwnedToken::from).collect())
 }
 
 pub struct Tokenizer<'a> {
@@ -59,7 +219,17 @@ pub struct Tokenizer<'a> {
   line: usize,
   col: usize,
   start: usize,
-  token: Token<'a>
+  token: Token<'a>,
+  max_token_len: Option<usize>,
+  // unknown characters reported so far, when errors are collected
+  errors: Option<Vec<Diagnostic>>,
+  // the run of unknown characters ending at `stray_end`
+  stray: String,
+  stray_end: Option<Position>,
+  // committed tokens not yet returned by `next_token`
+  pending: VecDeque<Token<'a>>,
+  // set after Eof or an error
+  done: bool
 }
 
 impl<'a> Tokenizer<'a> {
@@ -71,16 +241,90 @@ impl<'a> Tokenizer<'a> {
       text: text,
       it: text.char_indices().peekable(),
       start: 0,
-      token: Token::new_empty()
+      token: Token::new_empty(),
+      max_token_len: None,
+      errors: None,
+      stray: String::new(),
+      stray_end: None,
+      pending: VecDeque::new(),
+      done: false
+    }
+  }
+
+  // Limits the length (in bytes) of symbols, numbers and strings,
+  // longer tokens are reported as errors. Unlimited by default.
+  pub fn set_max_token_len(&mut self, len: usize) {
+    self.max_token_len = Some(len);
+  }
+
+  // Like `tokenize`, but unknown characters are skipped and all of them
+  // are reported, a run of consecutive ones as a single error. Other
+  // errors still stop the tokenizer and come last.
+  pub fn tokenize_all(&mut self) -> Result<LinkedList<Token<'a>>, Vec<Diagnostic>> {
+    self.errors = Some(vec![]);
+
+    let result = self.tokenize();
+    let mut errors = self.errors.take().unwrap();
+
+    match result {
+      Ok(tokens) if errors.is_empty() => Ok(tokens),
+      Ok(tokens) => {
+        self.tokens = tokens;
+        Err(errors)
+      },
+      Err(fatal) => {
+        errors.push(fatal);
+        Err(errors)
+      }
+    }
+  }
+
+  // Like `tokenize_all`, but the tokens are dropped as they are read
+  pub fn check_all(&mut self) -> Result<(), Vec<Diagnostic>> {
+    self.errors = Some(vec![]);
+
+    let fatal = self.filter_map(Result::err).next();
+    let mut errors = self.errors.take().unwrap();
+    errors.extend(fatal);
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(errors)
     }
   }
 
-  pub fn tokenize(&mut self) -> Result<&LinkedList<Token>, String> {
-    loop {
+  // Collects all the tokens from `next_token`. On errors the tokens read
+  // so far are left in `tokens`.
+  pub fn tokenize(&mut self) -> Result<LinkedList<Token<'a>>, Diagnostic> {
+    while let Some(token) = self.next_token() {
+      self.tokens.push_back(token?);
+    }
+
+    Ok(mem::replace(&mut self.tokens, LinkedList::new()))
+  }
+
+  // Returns the next token, ending with Eof. Nothing follows Eof or an error.
+  pub fn next_token(&mut self) -> Option<Result<Token<'a>, Diagnostic>> {
+    if self.pending.is_empty() && !self.done {
+      if let Err(err) = self.scan() {
+        self.done = true;
+        return Some(Err(err));
+      }
+    }
+
+    self.pending.pop_front().map(Ok)
+  }
+
+  // Reads the text until at least one token is committed
+  fn scan(&mut self) -> Result<(), Diagnostic> {
+    while self.pending.is_empty() {
       let c = match self.peek_char() {
         Some(ch) => ch,
         None => break
       };
+
+      self.check_token_len()?;
       
       match self.token.type_ {
         TokenType::Sym => {
@@ -93,19 +337,36 @@ impl<'a> Tokenizer<'a> {
         },
         TokenType::Num => {
           let cur = self.cur_text();
+          let radix = number_radix(cur);
+
+          let is_valid_num = if radix == 10 {
+            let exponent = cur.contains(|c: char| c == 'e' || c == 'E');
 
-          let is_valid_num =
             c >= '0' && c <= '9' ||
-            c == '.' && !cur.contains(".");
+            c == '.' && !cur.contains(".") && !exponent ||
+            (c == 'e' || c == 'E') && !exponent ||
+            (c == '+' || c == '-') && cur.ends_with(|c: char| c == 'e' || c == 'E') ||
+            cur == "0" && (c == 'x' || c == 'o' || c == 'b')
+          } else {
+            c.is_digit(radix)
+          };
 
-          if is_valid_num {
+          if c == '_' {
+            self.digit_separator(radix)?;
+          } else if is_valid_num {
             self.next();
+          } else if radix != 10 && (c.is_alphanumeric() || c == '_') {
+            let pos = Position { line: self.line, col: self.col };
+            return Err(Diagnostic::error(format!("Invalid digit '{}' in number literal", c), Some(pos)));
           } else {
+            self.check_number()?;
             self.commit();
           }
         },
         TokenType::Str => {
-          if c == '\'' {
+          if c == '\\' {
+            self.escape()?;
+          } else if c == '\'' {
             self.next();
             self.commit();
           } else {
@@ -120,30 +381,55 @@ impl<'a> Tokenizer<'a> {
             self.next();
           }
         },
+        TokenType::BlockComment => {
+          self.next();
+
+          if c == '*' && self.peek_char() == Some('/') {
+            self.next();
+            self.reset();
+          }
+        },
         _ => {
-          if c >= 'A' && c <= 'Z' || c >= 'a' && c <= 'z' {
+          if c >= 'A' && c <= 'Z' || c >= 'a' && c <= 'z' || c == '_' {
             self.new_token(TokenType::Sym);
             self.next();
           }
           else if c == '/' {
+            self.new_token(TokenType::OpDiv);
             self.next();
             if let Some('/') = self.peek_char() {
               self.next();
-              self.new_token(TokenType::Comment);
+              self.token.type_ = TokenType::Comment;
+            } else if let Some('*') = self.peek_char() {
+              self.next();
+              self.token.type_ = TokenType::BlockComment;
             } else {
-              self.new_token(TokenType::OpDiv);
-              self.commit();
+              self.commit_assign_op(TokenType::AssignDiv);
             }
           }
           else if c == '+' {
             self.new_token(TokenType::OpPlus);
             self.next();
-            self.commit();
+
+            if let Some('+') = self.peek_char() {
+              self.next();
+              self.token.type_ = TokenType::OpInc;
+              self.commit();
+            } else {
+              self.commit_assign_op(TokenType::AssignPlus);
+            }
           }
           else if c == '-' {
             self.new_token(TokenType::OpMinus);
             self.next();
-            self.commit();
+
+            if let Some('-') = self.peek_char() {
+              self.next();
+              self.token.type_ = TokenType::OpDec;
+              self.commit();
+            } else {
+              self.commit_assign_op(TokenType::AssignMinus);
+            }
           }
           else if c >= '0' && c <= '9' {
             self.new_token(TokenType::Num);
@@ -159,7 +445,12 @@ impl<'a> Tokenizer<'a> {
               
             if let Some('=') = self.peek_char() {
               self.next();
-              self.new_token(TokenType::OpEq);
+              self.token.type_ = TokenType::OpEq;
+              self.commit();
+            }
+            else if let Some('>') = self.peek_char() {
+              self.next();
+              self.token.type_ = TokenType::Arrow;
               self.commit();
             }
             else {
@@ -188,11 +479,34 @@ impl<'a> Tokenizer<'a> {
             self.next();
             self.commit();
           }
+          else if c == '.' && self.next_is_digit() {
+            self.new_token(TokenType::Num);
+            self.next();
+          }
           else if c == '.' {
             self.new_token(TokenType::Dot);
             self.next();
             self.commit();
           }
+          else if c == '?' {
+            self.new_token(TokenType::Question);
+            self.next();
+
+            // `a ?.5 : b` is a conditional with a number, not optional chaining
+            match self.peek_char() {
+              Some('.') if !self.next_is_digit() => {
+                self.next();
+                self.token.type_ = TokenType::OptDot;
+              },
+              Some('?') => {
+                self.next();
+                self.token.type_ = TokenType::OpNullish;
+              },
+              _ => {}
+            }
+
+            self.commit();
+          }
           else if c == '{' {
             self.new_token(TokenType::LBlock);
             self.next();
@@ -221,12 +535,12 @@ impl<'a> Tokenizer<'a> {
           else if c == '*' { 
             self.new_token(TokenType::OpMul);
             self.next();
-            self.commit();
+            self.commit_assign_op(TokenType::AssignMul);
           }
           else if c == '%' { 
             self.new_token(TokenType::OpMod);
             self.next();
-            self.commit();
+            self.commit_assign_op(TokenType::AssignMod);
           }
           else if c == '!' { 
             self.new_token(TokenType::OpNot);
@@ -234,42 +548,58 @@ impl<'a> Tokenizer<'a> {
             
             if let Some('=') = self.peek_char() {
               self.next();
-              self.new_token(TokenType::OpNotEq);
+              self.token.type_ = TokenType::OpNotEq;
               self.commit();
             } else {
               self.commit();
             }
           }
           else if c == '|' {
+            self.new_token(TokenType::OpBitOr);
             self.next();
             
             if let Some('|') = self.peek_char() {
               self.next();
-              self.new_token(TokenType::OpOr);
+              self.token.type_ = TokenType::OpOr;
               self.commit();
             } else {
-              return Err(self.error());
+              self.commit_assign_op(TokenType::AssignBitOr);
             }
           }
           else if c == '&' {
+            self.new_token(TokenType::OpBitAnd);
             self.next();
             
             if let Some('&') = self.peek_char() {
               self.next();
-              self.new_token(TokenType::OpAnd);
+              self.token.type_ = TokenType::OpAnd;
               self.commit();
             } else {
-              return Err(self.error()); 
+              self.commit_assign_op(TokenType::AssignBitAnd);
             }
           }
+          else if c == '^' {
+            self.new_token(TokenType::OpBitXor);
+            self.next();
+            self.commit_assign_op(TokenType::AssignBitXor);
+          }
+          else if c == '~' {
+            self.new_token(TokenType::OpBitNot);
+            self.next();
+            self.commit();
+          }
           else if c == '<' { 
             self.new_token(TokenType::OpLs);
             self.next();
             
             if let Some('=') = self.peek_char() {
               self.next();
-              self.new_token(TokenType::OpLsEq);
+              self.token.type_ = TokenType::OpLsEq;
               self.commit();
+            } else if let Some('<') = self.peek_char() {
+              self.next();
+              self.token.type_ = TokenType::OpShl;
+              self.commit_assign_op(TokenType::AssignShl);
             } else {
               self.commit();
             }
@@ -280,8 +610,12 @@ impl<'a> Tokenizer<'a> {
             
             if let Some('=') = self.peek_char() {
               self.next();
-              self.new_token(TokenType::OpGtEq);
+              self.token.type_ = TokenType::OpGtEq;
               self.commit();
+            } else if let Some('>') = self.peek_char() {
+              self.next();
+              self.token.type_ = TokenType::OpShr;
+              self.commit_assign_op(TokenType::AssignShr);
             } else {
               self.commit();
             }
@@ -291,20 +625,44 @@ impl<'a> Tokenizer<'a> {
             self.reset();
           }
           else {
-            return Err(self.error()); 
+            self.unknown_char()?;
           }
         }
       }
     }
 
+    if !self.pending.is_empty() {
+      return Ok(());
+    }
+
+    // the last token ends at the end of the text, as does a line comment
+    match self.token.type_ {
+      TokenType::Sym | TokenType::Num => {
+        self.check_token_len()?;
+        self.check_number()?;
+        self.commit();
+      },
+      TokenType::Str => {
+        let pos = Position { line: self.token.line, col: self.token.col };
+        return Err(Diagnostic::error("Unterminated string".to_string(), Some(pos)));
+      },
+      TokenType::BlockComment => {
+        let pos = Position { line: self.token.line, col: self.token.col };
+        let msg = format!("Unterminated block comment opened at {}:{}", pos.line, pos.col);
+        return Err(Diagnostic::error(msg, Some(pos)));
+      },
+      _ => {}
+    }
+
     self.new_token(TokenType::Eof);
     self.commit();
-    
-    Ok(&self.tokens)
+    self.done = true;
+
+    Ok(())
   }
 
   fn cur_text(&mut self) -> &'a str { 
-    let &(offset, _) = self.it.peek().unwrap_or(&(self.start, '\0'));
+    let &(offset, _) = self.it.peek().unwrap_or(&(self.text.len(), '\0'));
     
     &self.text[self.start..offset]
   }
@@ -325,16 +683,42 @@ impl<'a> Tokenizer<'a> {
     }
   }
 
+  // Whether a digit follows the next char, as in `.5`
+  fn next_is_digit(&mut self) -> bool {
+    match self.peek_pos() {
+      Some(pos) => self.text[pos..].chars().nth(1).map_or(false, |c| c >= '0' && c <= '9'),
+      None => false
+    }
+  }
+
   fn new_token(&mut self, t: TokenType) {
     self.token = Token::new(t, "", self.line, self.col);
   }
   
   fn commit(&mut self) {
     self.token.text = self.cur_text();
-    self.tokens.push_back(self.token.clone());
+    self.token.end_line = self.line;
+    self.token.end_col = self.col;
+
+    if self.token.type_ == TokenType::Sym {
+      if let Some(keyword) = Keyword::from_name(self.token.text) {
+        self.token.type_ = TokenType::Keyword(keyword);
+      }
+    }
+
+    self.pending.push_back(self.token.clone());
     self.reset();
   }
 
+  // Commits either the current operator or its compound assignment form (op=)
+  fn commit_assign_op(&mut self, t: TokenType) {
+    if let Some('=') = self.peek_char() {
+      self.next();
+      self.token.type_ = t;
+    }
+    self.commit();
+  }
+
   fn reset(&mut self) {
     self.token = Token::new_empty();
     self.start = self.peek_pos().unwrap_or(self.text.len()); 
@@ -351,13 +735,443 @@ impl<'a> Tokenizer<'a> {
     self.it.next();
   }
 
-  fn error(&mut self) -> String {
+  fn check_token_len(&mut self) -> Result<(), Diagnostic> {
+    let max = match (&self.token.type_, self.max_token_len) {
+      (&TokenType::Sym, Some(max)) |
+      (&TokenType::Num, Some(max)) |
+      (&TokenType::Str, Some(max)) => max,
+      _ => return Ok(())
+    };
+
+    if self.cur_text().len() > max {
+      let pos = Position { line: self.token.line, col: self.token.col };
+      Err(Diagnostic::error(format!("Token exceeds maximum length of {}", max), Some(pos)))
+    } else {
+      Ok(())
+    }
+  }
+
+  // Prefixed literals need digits and must fit into 32 bits, an exponent needs digits
+  fn check_number(&mut self) -> Result<(), Diagnostic> {
+    let text = self.cur_text();
+
+    let valid = match number_radix(text) {
+      10 => !text.ends_with(|c: char| c == 'e' || c == 'E' || c == '+' || c == '-'),
+      radix => u32::from_str_radix(&text[2..].replace('_', ""), radix).is_ok()
+    };
+
+    if self.token.type_ == TokenType::Num && !valid {
+      let pos = Position { line: self.token.line, col: self.token.col };
+      Err(Diagnostic::error(format!("Invalid number literal: {}", text), Some(pos)))
+    } else {
+      Ok(())
+    }
+  }
+
+  // Skips an underscore between two digits of a number
+  fn digit_separator(&mut self, radix: u32) -> Result<(), Diagnostic> {
+    let pos = Position { line: self.line, col: self.col };
+    let is_digit = |c: Option<char>| c.map_or(false, |c| c.is_digit(radix));
+
+    let prev = self.cur_text().chars().last();
+    let next = self.text[self.peek_pos().unwrap() + 1..].chars().next();
+
+    // a doubled separator is reported at the second one
+    if is_digit(prev) && (is_digit(next) || next == Some('_')) {
+      self.next();
+      Ok(())
+    } else {
+      Err(Diagnostic::error("Invalid digit separator in number literal".to_string(), Some(pos)))
+    }
+  }
+
+  // Skips an escape sequence in a string, the backslash is the next char
+  fn escape(&mut self) -> Result<(), Diagnostic> {
+    let pos = Position { line: self.line, col: self.col };
+    let start = self.peek_pos().unwrap();
+    self.next();
+
+    let valid = match self.peek_char() {
+      Some('u') => {
+        self.next();
+
+        let mut code = String::new();
+        while code.len() < 4 {
+          match self.peek_char() {
+            Some(h) if h.is_digit(16) => { code.push(h); self.next(); },
+            _ => break
+          }
+        }
+
+        code.len() == 4 && char::from_u32(u32::from_str_radix(&code, 16).unwrap()).is_some()
+      },
+      Some(c) => {
+        self.next();
+        ESCAPES.iter().any(|&(e, _)| e == c)
+      },
+      // the string is unterminated
+      None => true
+    };
+
+    if valid {
+      Ok(())
+    } else {
+      let end = self.peek_pos().unwrap_or(self.text.len());
+      let msg = format!("Invalid escape sequence: {}", &self.text[start..end]);
+      Err(Diagnostic::error(msg, Some(pos)))
+    }
+  }
+
+  fn unknown_char(&mut self) -> Result<(), Diagnostic> {
+    let pos = Position { line: self.line, col: self.col };
+    let ch = self.peek_char().unwrap();
+
+    if self.errors.is_some() && self.stray_end == Some(pos) {
+      self.stray.push(ch);
+      let message = format!("Unknown characters: {}", self.stray);
+      self.errors.as_mut().unwrap().last_mut().unwrap().message = message;
+    } else {
+      let err = self.error();
+      if self.errors.is_none() {
+        return Err(err);
+      }
+
+      self.stray = ch.to_string();
+      self.errors.as_mut().unwrap().push(err);
+    }
+
+    self.next();
+    self.reset();
+    self.stray_end = Some(Position { line: self.line, col: self.col });
+    Ok(())
+  }
+
+  fn error(&mut self) -> Diagnostic {
     let ch = if let Some(ch) = self.peek_char() {
       ch.to_string()
     } else {
       "EOF".to_string()
     };
-    return format!("Unknown character at line {} column {}: {}", self.line, self.col, ch); 
+    let pos = Position { line: self.line, col: self.col };
+    Diagnostic::error(format!("Unknown character: {}", ch), Some(pos))
   }
 }
 
+impl<'a> Iterator for Tokenizer<'a> {
+  type Item = Result<Token<'a>, Diagnostic>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.next_token()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use parser::Parser;
+  use syntax_tree::NodeType;
+
+  fn types(text: &str) -> Vec<TokenType> {
+    Tokenizer::new(text).tokenize().unwrap().iter().map(|t| t.type_.clone()).collect()
+  }
+
+  #[test]
+  fn test_assign_ops() {
+    assert_eq!(types("a %= 3 / 2; b /= -c; d += e * f;"), vec![
+      TokenType::Sym, TokenType::AssignMod, TokenType::Num, TokenType::OpDiv, TokenType::Num, TokenType::End,
+      TokenType::Sym, TokenType::AssignDiv, TokenType::OpMinus, TokenType::Sym, TokenType::End,
+      TokenType::Sym, TokenType::AssignPlus, TokenType::Sym, TokenType::OpMul, TokenType::Sym, TokenType::End,
+      TokenType::Eof
+    ]);
+
+    // the longest operator is taken
+    assert_eq!(types("a &= b | c; a |= b && c; a ^= ~b;"), vec![
+      TokenType::Sym, TokenType::AssignBitAnd, TokenType::Sym, TokenType::OpBitOr, TokenType::Sym, TokenType::End,
+      TokenType::Sym, TokenType::AssignBitOr, TokenType::Sym, TokenType::OpAnd, TokenType::Sym, TokenType::End,
+      TokenType::Sym, TokenType::AssignBitXor, TokenType::OpBitNot, TokenType::Sym, TokenType::End,
+      TokenType::Eof
+    ]);
+    assert_eq!(types("a <<= b << c <= d; a >>= b >> c >= d;"), vec![
+      TokenType::Sym, TokenType::AssignShl, TokenType::Sym, TokenType::OpShl, TokenType::Sym,
+      TokenType::OpLsEq, TokenType::Sym, TokenType::End,
+      TokenType::Sym, TokenType::AssignShr, TokenType::Sym, TokenType::OpShr, TokenType::Sym,
+      TokenType::OpGtEq, TokenType::Sym, TokenType::End,
+      TokenType::Eof
+    ]);
+  }
+
+  #[test]
+  fn test_increments() {
+    assert_eq!(types("a++ + ++b - --c-- -d"), vec![
+      TokenType::Sym, TokenType::OpInc, TokenType::OpPlus, TokenType::OpInc, TokenType::Sym,
+      TokenType::OpMinus, TokenType::OpDec, TokenType::Sym, TokenType::OpDec, TokenType::OpMinus,
+      TokenType::Sym, TokenType::Eof
+    ]);
+    assert_eq!(types("a+=+b"), vec![TokenType::Sym, TokenType::AssignPlus, TokenType::OpPlus, TokenType::Sym, TokenType::Eof]);
+  }
+
+  #[test]
+  fn test_spans() {
+    let mut tokenizer = Tokenizer::new("if (a >= 10) {\n  b = 'x\ny';\n}");
+    let spans: Vec<((usize, usize), (usize, usize))> = tokenizer.tokenize().unwrap().iter()
+      .map(|t| t.span())
+      .map(|s| ((s.start.line, s.start.col), (s.end.line, s.end.col)))
+      .collect();
+
+    assert_eq!(spans[3], ((1, 6), (1, 8)));
+    assert_eq!(spans[4], ((1, 9), (1, 11)));
+    assert_eq!(spans[0], ((1, 0), (1, 2)));
+    // a string with a line break ends on the next line
+    assert_eq!(spans[9], ((2, 6), (3, 2)));
+    assert_eq!(spans[12], ((4, 1), (4, 1)));
+  }
+
+  #[test]
+  fn test_streaming() {
+    let text = "var x = /* c */ 0x1F + y1; // end\nf('s', x >>= 2) abc";
+    let key = |t: &Token| (t.type_.clone(), t.text.to_string(), t.line, t.col, t.end_line, t.end_col);
+
+    let mut tokenizer = Tokenizer::new(text);
+    let batch: Vec<_> = tokenizer.tokenize().unwrap().iter().map(&key).collect();
+    let streamed: Vec<_> = Tokenizer::new(text).map(|t| key(&t.unwrap())).collect();
+    assert_eq!(streamed, batch);
+    assert_eq!(streamed.last().unwrap().0, TokenType::Eof);
+
+    // tokens before an error are still yielded, nothing after it
+    let results: Vec<_> = Tokenizer::new("a = 'b").collect();
+    assert_eq!(results.len(), 3);
+    assert!(results[1].is_ok() && results[2].is_err());
+  }
+
+  #[test]
+  fn test_max_token_len() {
+    let text = "var abc = 'short';\nvar b = 'long string';";
+
+    let mut tokenizer = Tokenizer::new(text);
+    tokenizer.set_max_token_len(8);
+    let err = tokenizer.tokenize().err().unwrap();
+    assert_eq!(err.pos, Some(Position { line: 2, col: 8 }));
+
+    let mut tokenizer = Tokenizer::new(text);
+    tokenizer.set_max_token_len(15);
+    assert!(tokenizer.tokenize().is_ok());
+
+    assert!(Tokenizer::new(text).tokenize().is_ok());
+  }
+
+  #[test]
+  fn test_tokenize_owned() {
+    let tokens = {
+      let src = String::from("var a = 'x';\n");
+      tokenize_owned(&src).unwrap()
+    };
+
+    assert_eq!(tokens.len(), 6);
+    assert_eq!(tokens[3], OwnedToken { type_: TokenType::Str, text: "'x'".to_string(), line: 1, col: 8 });
+    assert_eq!(tokens[5].type_, TokenType::Eof);
+  }
+
+  #[test]
+  fn test_comments() {
+    assert_eq!(types("a = b + // note\n c; // trailing"), vec![
+      TokenType::Sym, TokenType::Assign, TokenType::Sym, TokenType::OpPlus, TokenType::Sym,
+      TokenType::End, TokenType::Eof
+    ]);
+    assert_eq!(types("// only a comment"), vec![TokenType::Eof]);
+  }
+
+  #[test]
+  fn test_block_comments() {
+    let mut tokenizer = Tokenizer::new("a = 1; /* first\n * second ** /\n*/ b = a /* c */ / 2;\nc;");
+    let tokens: Vec<(&str, usize)> = tokenizer.tokenize().unwrap().iter().map(|t| (t.text, t.line)).collect();
+    assert_eq!(tokens, vec![
+      ("a", 1), ("=", 1), ("1", 1), (";", 1),
+      ("b", 3), ("=", 3), ("a", 3), ("/", 3), ("2", 3), (";", 3),
+      ("c", 4), (";", 4), ("", 4)
+    ]);
+
+    assert_eq!(types("/**/x/***/"), vec![TokenType::Sym, TokenType::Eof]);
+
+    let err = Tokenizer::new("a;\n  /* open *").tokenize().err().unwrap();
+    assert_eq!(err.message, "Unterminated block comment opened at 2:2");
+    assert_eq!(err.pos, Some(Position { line: 2, col: 2 }));
+  }
+
+  #[test]
+  fn test_categories() {
+    let mut tokenizer = Tokenizer::new("while (x >= 'a') { y = 1; }");
+    let categories: Vec<TokenCategory> = tokenizer.tokenize().unwrap().iter()
+      .map(|t| t.category()).collect();
+    assert_eq!(categories, vec![
+      TokenCategory::Keyword, TokenCategory::Punctuation, TokenCategory::Identifier,
+      TokenCategory::Operator, TokenCategory::Literal, TokenCategory::Punctuation,
+      TokenCategory::Punctuation, TokenCategory::Identifier, TokenCategory::Operator,
+      TokenCategory::Literal, TokenCategory::Punctuation, TokenCategory::Punctuation,
+      TokenCategory::Other
+    ]);
+    assert_eq!(TokenType::Comment.category(), TokenCategory::Comment);
+  }
+
+  #[test]
+  fn test_token_at_eof() {
+    let mut tokenizer = Tokenizer::new("x = 12");
+    let texts: Vec<&str> = tokenizer.tokenize().unwrap().iter().map(|t| t.text).collect();
+    assert_eq!(texts, vec!["x", "=", "12", ""]);
+
+    let err = Tokenizer::new("x = 'abc").tokenize().err().unwrap();
+    assert_eq!(err.message, "Unterminated string");
+    assert_eq!(err.pos, Some(Position { line: 1, col: 4 }));
+  }
+
+  #[test]
+  fn test_tokenize_all() {
+    let errors = Tokenizer::new("a = @ 1;\nb = c @ d;").tokenize_all().err().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].message, "Unknown character: @");
+    assert_eq!(errors[0].pos, Some(Position { line: 1, col: 4 }));
+    assert_eq!(errors[1].pos, Some(Position { line: 2, col: 6 }));
+
+    let errors = Tokenizer::new("a = @#@ b $ c;").tokenize_all().err().unwrap();
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].message, "Unknown characters: @#@");
+    assert_eq!(errors[1].pos, Some(Position { line: 1, col: 10 }));
+
+    let mut tokenizer = Tokenizer::new("a = @ b;");
+    let texts: Vec<&str> = match tokenizer.tokenize_all() {
+      Err(_) => tokenizer.tokens.iter().map(|t| t.text).collect(),
+      Ok(_) => vec![]
+    };
+    assert_eq!(texts, vec!["a", "=", "b", ";", ""]);
+
+    assert!(Tokenizer::new("a = b;").tokenize_all().is_ok());
+  }
+
+  #[test]
+  fn test_string_escapes() {
+    let strings = |text: &str| -> Vec<String> {
+      Tokenizer::new(text).tokenize().unwrap().iter()
+        .filter(|t| t.type_ == TokenType::Str)
+        .map(|t| string_value(t.text))
+        .collect()
+    };
+
+    assert_eq!(strings("var a = 'it\\'s'; var b = '\\\\';"), vec!["it's", "\\"]);
+    assert_eq!(strings("'a\\nb\\tc'"), vec!["a\nb\tc"]);
+    assert_eq!(strings("'\\u00e9\\u0041'"), vec!["\u{e9}A"]);
+
+    let err = Tokenizer::new("var a = 1;\nvar b = 'x\\qy';").tokenize().err().unwrap();
+    assert_eq!(err.message, "Invalid escape sequence: \\q");
+    assert_eq!(err.pos, Some(Position { line: 2, col: 10 }));
+
+    let err = Tokenizer::new("'\\u12'").tokenize().err().unwrap();
+    assert_eq!(err.message, "Invalid escape sequence: \\u12");
+
+    let err = Tokenizer::new("'abc\\'").tokenize().err().unwrap();
+    assert_eq!(err.message, "Unterminated string");
+  }
+
+  #[test]
+  fn test_radix_literals() {
+    let numbers = |text: &str| -> Vec<f32> {
+      Tokenizer::new(text).tokenize().unwrap().iter()
+        .filter(|t| t.type_ == TokenType::Num)
+        .map(|t| number_value(t.text))
+        .collect()
+    };
+
+    assert_eq!(numbers("0x1F + 0o17 - 0b1010 * 0xff;"), vec![31.0, 15.0, 10.0, 255.0]);
+    assert_eq!(numbers("0 0.5 10 0x0 0xFFFFFFFF"), vec![0.0, 0.5, 10.0, 0.0, 4294967295.0]);
+
+    let err = Tokenizer::new("var a = 0x1G;").tokenize().err().unwrap();
+    assert_eq!(err.message, "Invalid digit 'G' in number literal");
+    assert_eq!(err.pos, Some(Position { line: 1, col: 11 }));
+
+    let err = Tokenizer::new("var a = 0b102;").tokenize().err().unwrap();
+    assert_eq!(err.message, "Invalid digit '2' in number literal");
+
+    let err = Tokenizer::new("var a = 0x;").tokenize().err().unwrap();
+    assert_eq!(err.message, "Invalid number literal: 0x");
+    assert_eq!(err.pos, Some(Position { line: 1, col: 8 }));
+
+    assert!(Tokenizer::new("0x100000000").tokenize().is_err());
+    assert!(Tokenizer::new("0o").tokenize().is_err());
+  }
+
+  #[test]
+  fn test_exponent_literals() {
+    let numbers = |text: &str| -> Vec<f32> {
+      Tokenizer::new(text).tokenize().unwrap().iter()
+        .filter(|t| t.type_ == TokenType::Num)
+        .map(|t| number_value(t.text))
+        .collect()
+    };
+
+    assert_eq!(numbers("1e3 1.5E-2 2e+2 0.5"), vec![1000.0, 0.015, 200.0, 0.5]);
+    // a dot after a complete number starts the next one, as in `.5`
+    assert_eq!(numbers("1e3.5"), vec![1000.0, 0.5]);
+    assert_eq!(numbers("1.5.2"), vec![1.5, 0.2]);
+    assert_eq!(types("a.b1"), vec![TokenType::Sym, TokenType::Dot, TokenType::Sym, TokenType::Eof]);
+    assert_eq!(types("2e-1-1"), vec![TokenType::Num, TokenType::OpMinus, TokenType::Num, TokenType::Eof]);
+
+    let err = Tokenizer::new("var a = 1e+;").tokenize().err().unwrap();
+    assert_eq!(err.message, "Invalid number literal: 1e+");
+    assert_eq!(err.pos, Some(Position { line: 1, col: 8 }));
+
+    assert!(Tokenizer::new("1e").tokenize().is_err());
+    assert!(Tokenizer::new("1E-").tokenize().is_err());
+  }
+
+  #[test]
+  fn test_digit_separators() {
+    let mut tokenizer = Tokenizer::new("var a = [1_000_000, 0xFF_FF, 1_0.2_5, 1e1_0];");
+    let tokens = tokenizer.tokenize().unwrap();
+    let texts: Vec<&str> = tokens.iter().filter(|t| t.type_ == TokenType::Num).map(|t| t.text).collect();
+    assert_eq!(texts, vec!["1_000_000", "0xFF_FF", "1_0.2_5", "1e1_0"]);
+
+    let values: Vec<NodeType> = Parser::new(tokens).parse().body[0].body[1].body.iter()
+      .map(|n| n.type_.clone())
+      .collect();
+    assert_eq!(values, vec![NodeType::Number(1000000.0), NodeType::Number(65535.0),
+                            NodeType::Number(10.25), NodeType::Number(1e10)]);
+
+    let error_at = |text: &str| Tokenizer::new(text).tokenize().err().unwrap().pos.unwrap().col;
+    assert_eq!(error_at("a = 1__0;"), 6);
+    assert_eq!(error_at("a = 1_;"), 5);
+    assert_eq!(error_at("a = 1_.5;"), 5);
+    assert_eq!(error_at("a = 1._5;"), 6);
+    assert_eq!(error_at("a = 0x_1;"), 6);
+    assert_eq!(error_at("a = 1_"), 5);
+  }
+
+  #[test]
+  fn test_question() {
+    assert_eq!(types("a ? b?.c : d ?? e"), vec![
+      TokenType::Sym, TokenType::Question, TokenType::Sym, TokenType::OptDot, TokenType::Sym,
+      TokenType::Colon, TokenType::Sym, TokenType::OpNullish, TokenType::Sym, TokenType::Eof
+    ]);
+    assert_eq!(types("a ?.5 : b?.c"), vec![
+      TokenType::Sym, TokenType::Question, TokenType::Num, TokenType::Colon,
+      TokenType::Sym, TokenType::OptDot, TokenType::Sym, TokenType::Eof
+    ]);
+    let mut tokenizer = Tokenizer::new("a ?.5 : b");
+    let texts: Vec<&str> = tokenizer.tokenize().unwrap().iter().map(|t| t.text).collect();
+    assert_eq!(texts, vec!["a", "?", ".5", ":", "b", ""]);
+
+    let mut tokenizer = Tokenizer::new("x ?? y");
+    let tokens: Vec<(&str, usize)> = tokenizer.tokenize().unwrap().iter().map(|t| (t.text, t.col)).collect();
+    assert_eq!(tokens[1], ("??", 2));
+  }
+
+  #[test]
+  fn test_keywords() {
+    assert_eq!(types("if iffy fn fnord typeof _in in"), vec![
+      TokenType::Keyword(Keyword::If), TokenType::Sym, TokenType::Keyword(Keyword::Fn), TokenType::Sym,
+      TokenType::Keyword(Keyword::Typeof), TokenType::Sym, TokenType::Keyword(Keyword::In), TokenType::Eof
+    ]);
+
+    let mut tokenizer = Tokenizer::new("while iffy");
+    let tokens = tokenizer.tokenize().unwrap();
+    let syms: Vec<Option<&str>> = tokens.iter().map(|t| t.as_sym()).collect();
+    assert_eq!(syms, vec![None, Some("iffy"), None]);
+  }
+}
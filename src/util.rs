@@ -1,14 +1,19 @@
 use syntax_tree::Visitor;
 use syntax_tree::Node;
+use syntax_tree::NodeType;
+use syntax_tree::OpType;
 
 pub struct GraphvizVisitor {
-  text: String
+  text: String,
+  // nodes are numbered in visiting order, addresses differ between runs
+  visited: usize
 }
 
 impl GraphvizVisitor {
   pub fn new() -> GraphvizVisitor {
     GraphvizVisitor {
-      text: String::new()
+      text: String::new(),
+      visited: 0
     }
   }
 
@@ -29,15 +34,429 @@ impl GraphvizVisitor {
 
 impl Visitor for GraphvizVisitor {
   fn visit(&mut self, node: &mut Node) {
-    let this_id = node as *const Node;
+    let this_id = self.visited;
+    self.visited += 1;
     
-    let node_type = format!("{:?}", node.type_).replace("\"", "\\\"");
-    self.text += &format!("\tnode{}[label=\"{}\"]\n", this_id as usize, &node_type); 
+    let node_type = node.type_.to_string().replace("\\", "\\\\").replace("\"", "\\\"");
+    self.text += &format!("\tnode{}[label=\"{}\"]\n", this_id, &node_type); 
 
-    for ch in node.body.iter() {
-      let child_id = ch as *const Node;
-      self.text += &format!("\tnode{} -> node{}\n", this_id as usize, child_id as usize);
+    for child_id in node.child_indices(this_id) {
+      self.text += &format!("\tnode{} -> node{}\n", this_id, child_id);
     }
   }
 }
 
+
+// Serializes the tree to JSON, one node per line. `visit` is called
+// pre-order without a matching exit for every node type, so the visitor
+// records each node's fields and number of children in visiting order and
+// the text is assembled from the root afterwards.
+pub struct JsonVisitor {
+  nodes: Vec<(String, usize)>
+}
+
+impl JsonVisitor {
+  pub fn new() -> JsonVisitor {
+    JsonVisitor {
+      nodes: vec![]
+    }
+  }
+
+  pub fn text(&self) -> String {
+    let mut text = String::new();
+
+    if !self.nodes.is_empty() {
+      self.write_node(0, 0, &mut text);
+      text += "\n";
+    }
+
+    text
+  }
+
+  // Writes the node at `index` and its subtree, returns the index past it
+  fn write_node(&self, index: usize, depth: usize, text: &mut String) -> usize {
+    let &(ref fields, children) = &self.nodes[index];
+    let indent = "  ".repeat(depth);
+
+    *text += &format!("{}{{{}, \"children\": [", indent, fields);
+
+    let mut next = index + 1;
+    if children > 0 {
+      *text += "\n";
+
+      for i in 0..children {
+        next = self.write_node(next, depth + 1, text);
+        *text += if i + 1 < children { ",\n" } else { "\n" };
+      }
+
+      *text += &indent;
+    }
+
+    *text += "]}";
+    next
+  }
+}
+
+impl Visitor for JsonVisitor {
+  fn visit(&mut self, node: &mut Node) {
+    let fields = match node.type_ {
+      // JSON has no NaN and infinities, `JSON.stringify` writes them as null
+      NodeType::Number(n) if !n.is_finite() => "\"type\": \"Number\", \"value\": null".to_string(),
+      NodeType::Number(n) => format!("\"type\": \"Number\", \"value\": {}", n),
+      NodeType::String(ref s) => format!("\"type\": \"String\", \"value\": {}", json_string(s)),
+      NodeType::Symbol(ref s) => format!("\"type\": \"Symbol\", \"name\": {}", json_string(s)),
+      NodeType::Op(op) => format!("\"type\": \"Op\", \"op\": {}", json_string(&format!("{:?}", op))),
+      NodeType::Update { op, postfix } =>
+        format!("\"type\": \"Update\", \"op\": \"{:?}{:?}\", \"postfix\": {}", op, op, postfix),
+      ref t => format!("\"type\": \"{:?}\"", t)
+    };
+
+    self.nodes.push((fields, node.body.len()));
+  }
+}
+
+// Renders the tree on one line as `(block (var a (+ 1 2)))`. Terms are
+// written as is, strings quoted, every other node as a list of its name
+// and children. A plain recursion, as the visitor has no exit for every node.
+pub fn to_sexpr(node: &Node) -> String {
+  // names which differ from the display of the node type are spelled out,
+  // the rest is shared with it
+  let name = match node.type_ {
+    NodeType::String(ref s) => return json_string(s),
+    NodeType::Number(_) | NodeType::Null | NodeType::Undefined |
+    NodeType::Boolean(_) | NodeType::Symbol(_) => return node.type_.to_string(),
+    NodeType::Update { op, postfix: false } => format!("{:?}{:?}", op, op),
+    NodeType::Update { op, postfix: true } => format!("post{:?}{:?}", op, op),
+    NodeType::StmtIf | NodeType::StmtIfElse => "if".to_string(),
+    NodeType::StmtDoWhile => "do-while".to_string(),
+    NodeType::StmtForIn => "for-in".to_string(),
+    NodeType::ComputedKey => "key".to_string(),
+    NodeType::BlockExpr => "do".to_string(),
+    _ => node.type_.to_string()
+  };
+
+  let mut text = format!("({}", name);
+  for child in node.body.iter() {
+    text += " ";
+    text += &to_sexpr(child);
+  }
+  text + ")"
+}
+
+// Regenerates source from the tree, one statement per line indented by two
+// spaces. Operators are parenthesized only where their precedence requires
+// it, strings use single quotes. Sugar is written in its desugared form, e.g.
+// `a += 1` as `a = a + 1` and arrow functions as `fn`.
+pub fn format(node: &Node) -> String {
+  match node.type_ {
+    NodeType::Block => format_stmts(&node.body, 0),
+    _ => format_stmt(node, 0) + "\n"
+  }
+}
+
+// Binding strength of expressions, from the ternary up to literals. A number
+// binds weaker than a call so that `(1).x` keeps its parentheses.
+const PREC_TERNARY: usize = 0;
+const PREC_UNARY: usize = 11;
+const PREC_POSTFIX: usize = 12;
+const PREC_CALL: usize = 13;
+const PREC_PRIMARY: usize = 14;
+
+fn binary_prec(op: OpType) -> usize {
+  match op {
+    OpType::OpOr => 1,
+    OpType::OpNullish => 2,
+    OpType::OpAnd => 3,
+    OpType::OpBitOr => 4,
+    OpType::OpBitXor => 5,
+    OpType::OpBitAnd => 6,
+    OpType::OpLs | OpType::OpGt | OpType::OpLsEq | OpType::OpGtEq | OpType::OpEq | OpType::OpNotEq => 7,
+    OpType::OpShl | OpType::OpShr => 8,
+    OpType::OpPlus | OpType::OpMinus => 9,
+    _ => 10
+  }
+}
+
+fn indent(level: usize) -> String {
+  "  ".repeat(level)
+}
+
+fn format_stmts(stmts: &[Node], level: usize) -> String {
+  stmts.iter().map(|s| indent(level) + &format_stmt(s, level) + "\n").collect()
+}
+
+// `{`, the statements one level deeper and the closing `}`
+fn format_body(block: &Node, level: usize) -> String {
+  if block.body.is_empty() {
+    return "{}".to_string();
+  }
+  format!("{{\n{}{}}}", format_stmts(&block.body, level + 1), indent(level))
+}
+
+// A statement without the indentation of its first line and the line break
+fn format_stmt(node: &Node, level: usize) -> String {
+  let expr = |node: &Node| format_expr(node, PREC_TERNARY, level);
+
+  match node.type_ {
+    NodeType::StmtVar | NodeType::StmtConst => format_simple(node, level) + ";",
+    NodeType::StmtFunction => {
+      let fun = &node.body[1];
+      format!("function {}({}) {}", expr(&node.body[0]), format_list(&fun.body[0], level), format_body(&fun.body[1], level))
+    },
+    NodeType::StmtIf | NodeType::StmtIfElse => {
+      let mut text = format!("if ({}) {}", expr(&node.body[0]), format_body(&node.body[1], level));
+
+      if let Some(else_block) = node.body.get(2) {
+        let chained = else_block.body.len() == 1 &&
+          (else_block.body[0].type_ == NodeType::StmtIf || else_block.body[0].type_ == NodeType::StmtIfElse);
+
+        text += " else ";
+        text += &if chained { format_stmt(&else_block.body[0], level) } else { format_body(else_block, level) };
+      }
+      text
+    },
+    NodeType::StmtWhile => format!("while ({}) {}", expr(&node.body[0]), format_body(&node.body[1], level)),
+    NodeType::StmtDoWhile => format!("do {} while ({});", format_body(&node.body[0], level), expr(&node.body[1])),
+    NodeType::StmtSwitch => {
+      let mut text = format!("switch ({}) {{\n", expr(&node.body[0]));
+      for case in node.body[1..].iter() {
+        text += &indent(level + 1);
+        text += &match case.body[0].type_ {
+          NodeType::Empty => "default:\n".to_string(),
+          _ => format!("case {}:\n", expr(&case.body[0]))
+        };
+        text += &format_stmts(&case.body[1].body, level + 2);
+      }
+      text + &indent(level) + "}"
+    },
+    NodeType::StmtFor => {
+      let part = |node: &Node| match node.type_ {
+        NodeType::Empty => String::new(),
+        _ => " ".to_string() + &format_simple(node, level)
+      };
+      format!("for ({};{};{}) {}", format_simple(&node.body[0], level), part(&node.body[1]), part(&node.body[2]),
+              format_body(&node.body[3], level))
+    },
+    NodeType::StmtForIn => {
+      let target = &node.body[0];
+      let keyword = if target.type_ == NodeType::StmtVar { "var " } else { "" };
+      format!("for ({}{} in {}) {}", keyword, expr(&target.body[0]), expr(&node.body[1]), format_body(&node.body[2], level))
+    },
+    NodeType::StmtReturn | NodeType::StmtBreak | NodeType::StmtContinue => {
+      let keyword = match node.type_ {
+        NodeType::StmtReturn => "return",
+        NodeType::StmtBreak => "break",
+        _ => "continue"
+      };
+      match node.body.get(0) {
+        Some(value) => format!("{} {};", keyword, expr(value)),
+        None => format!("{};", keyword)
+      }
+    },
+    NodeType::StmtLabel => format!("{}: {}", expr(&node.body[0]), format_stmt(&node.body[1], level)),
+    NodeType::Block => format_body(node, level),
+    _ => {
+      // a brace would start a block rather than a dict or a block expression
+      let text = format_simple(node, level);
+      if text.starts_with('{') { format!("({});", text) } else { text + ";" }
+    }
+  }
+}
+
+// Declarations, assignments and expressions without `;`, as in `for (...)`
+fn format_simple(node: &Node, level: usize) -> String {
+  let expr = |node: &Node| format_expr(node, PREC_TERNARY, level);
+
+  match node.type_ {
+    NodeType::StmtVar | NodeType::StmtConst => {
+      let keyword = if node.type_ == NodeType::StmtVar { "var" } else { "const" };
+      format!("{} {} = {}", keyword, expr(&node.body[0]), expr(&node.body[1]))
+    },
+    // several declarations in a block
+    NodeType::Block => {
+      let decls: Vec<String> = node.body.iter().map(|d| format!("{} = {}", expr(&d.body[0]), expr(&d.body[1]))).collect();
+      format!("var {}", decls.join(", "))
+    },
+    NodeType::Assign => format!("{} = {}", expr(&node.body[0]), expr(&node.body[1])),
+    NodeType::Empty => String::new(),
+    _ => expr(node)
+  }
+}
+
+fn format_list(list: &Node, level: usize) -> String {
+  let items: Vec<String> = list.body.iter().map(|n| format_expr(n, PREC_TERNARY, level)).collect();
+  items.join(", ")
+}
+
+// Formats an expression, in parentheses if it binds weaker than `min_prec`
+fn format_expr(node: &Node, min_prec: usize, level: usize) -> String {
+  let operand = |node: &Node, prec: usize| format_expr(node, prec, level);
+
+  let (text, prec) = match node.type_ {
+    NodeType::Number(n) => (format!("{}", n), PREC_POSTFIX),
+    NodeType::String(ref s) => (source_string(s), PREC_PRIMARY),
+    NodeType::Null | NodeType::Undefined | NodeType::Boolean(_) => (node.type_.to_string(), PREC_PRIMARY),
+    NodeType::Symbol(ref s) => (s.clone(), PREC_PRIMARY),
+    NodeType::Op(op) if node.body.len() == 1 => {
+      let value = operand(&node.body[0], PREC_UNARY);
+      let sign = format!("{:?}", op);
+      // `- -x` must not become `--x`, words need a space anyway
+      let space = sign.chars().all(char::is_alphabetic) || value.starts_with(&sign);
+      (format!("{}{}{}", sign, if space { " " } else { "" }, value), PREC_UNARY)
+    },
+    NodeType::Op(op) => {
+      let prec = binary_prec(op);
+      (format!("{} {:?} {}", operand(&node.body[0], prec), op, operand(&node.body[1], prec + 1)), prec)
+    },
+    NodeType::Update { op, postfix: false } => (format!("{:?}{:?}{}", op, op, operand(&node.body[0], PREC_UNARY)), PREC_UNARY),
+    NodeType::Update { op, postfix: true } => (format!("{}{:?}{:?}", operand(&node.body[0], PREC_CALL), op, op), PREC_POSTFIX),
+    NodeType::Ternary => (format!("{} ? {} : {}", operand(&node.body[0], PREC_TERNARY + 1),
+                                  operand(&node.body[1], PREC_TERNARY), operand(&node.body[2], PREC_TERNARY)), PREC_TERNARY),
+    NodeType::Call => (format!("{}({})", operand(&node.body[0], PREC_CALL), format_list(&node.body[1], level)), PREC_CALL),
+    NodeType::Member => (format!("{}.{}", operand(&node.body[1], PREC_CALL), operand(&node.body[0], PREC_PRIMARY)), PREC_CALL),
+    NodeType::OptMember => (format!("{}?.{}", operand(&node.body[1], PREC_CALL), operand(&node.body[0], PREC_PRIMARY)), PREC_CALL),
+    NodeType::Index => (format!("{}[{}]", operand(&node.body[1], PREC_CALL), operand(&node.body[0], PREC_TERNARY)), PREC_CALL),
+    NodeType::Function => (format!("fn({}) {}", format_list(&node.body[0], level), format_body(&node.body[1], level)), PREC_PRIMARY),
+    NodeType::Array => (format!("[{}]", format_list(node, level)), PREC_PRIMARY),
+    NodeType::Dict => {
+      let pairs: Vec<String> = node.body.chunks(2).map(|pair| {
+        let key = match pair[0].type_ {
+          NodeType::ComputedKey => format!("[{}]", operand(&pair[0].body[0], PREC_TERNARY)),
+          _ => operand(&pair[0], PREC_PRIMARY)
+        };
+        format!("{}: {}", key, operand(&pair[1], PREC_TERNARY))
+      }).collect();
+      (format!("{{{}}}", pairs.join(", ")), PREC_PRIMARY)
+    },
+    NodeType::BlockExpr => {
+      let mut text = format!("{{\n{}", format_stmts(&node.body[0].body, level + 1));
+      if node.body[1].type_ != NodeType::Empty {
+        text += &format!("{}{}\n", indent(level + 1), operand(&node.body[1], PREC_TERNARY));
+      }
+      (text + &indent(level) + "}", PREC_PRIMARY)
+    },
+    _ => (node.type_.to_string(), PREC_PRIMARY)
+  };
+
+  if prec < min_prec { format!("({})", text) } else { text }
+}
+
+// A single-quoted string literal with the escapes the tokenizer reads
+fn source_string(s: &str) -> String {
+  let mut text = "'".to_string();
+
+  for c in s.chars() {
+    match c {
+      '\'' => text += "\\'",
+      '\\' => text += "\\\\",
+      '\n' => text += "\\n",
+      '\t' => text += "\\t",
+      c if (c as u32) < 0x20 => text += &format!("\\u{:04x}", c as u32),
+      c => text.push(c)
+    }
+  }
+
+  text + "'"
+}
+
+pub fn json_string(s: &str) -> String {
+  let mut text = "\"".to_string();
+
+  for c in s.chars() {
+    match c {
+      '"' => text += "\\\"",
+      '\\' => text += "\\\\",
+      '\n' => text += "\\n",
+      '\t' => text += "\\t",
+      c if (c as u32) < 0x20 => text += &format!("\\u{:04x}", c as u32),
+      c => text.push(c)
+    }
+  }
+
+  text + "\""
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tokenizer::Tokenizer;
+  use parser::Parser;
+
+  #[test]
+  fn test_json() {
+    let mut ast = Parser::new(Tokenizer::new("var a = 1 + 2;").tokenize().unwrap()).parse();
+    let mut json = JsonVisitor::new();
+    ast.visit(&mut json);
+
+    assert_eq!(json.text(), concat!(
+      "{\"type\": \"Block\", \"children\": [\n",
+      "  {\"type\": \"StmtVar\", \"children\": [\n",
+      "    {\"type\": \"Symbol\", \"name\": \"a\", \"children\": []},\n",
+      "    {\"type\": \"Op\", \"op\": \"+\", \"children\": [\n",
+      "      {\"type\": \"Number\", \"value\": 1, \"children\": []},\n",
+      "      {\"type\": \"Number\", \"value\": 2, \"children\": []}\n",
+      "    ]}\n",
+      "  ]}\n",
+      "]}\n"));
+
+    // folding may leave numbers which JSON can't represent
+    let mut ast = Parser::new(Tokenizer::new("var a = 1;").tokenize().unwrap()).parse();
+    ast.body[0].body[1].type_ = NodeType::Number(::std::f32::INFINITY);
+    let mut json = JsonVisitor::new();
+    ast.visit(&mut json);
+    assert!(json.text().contains("{\"type\": \"Number\", \"value\": null, \"children\": []}"));
+  }
+
+  #[test]
+  fn test_sexpr() {
+    let sexpr = |text: &str| to_sexpr(&Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse());
+
+    assert_eq!(sexpr("var a = 1 + 2;"), "(block (var a (+ 1 2)))");
+    assert_eq!(sexpr("a.b[c] = f('x', -y) * 2.5;"), "(block (= ([] c (. b a)) (* (call f (block \"x\" (- y))) 2.5)))");
+    assert_eq!(sexpr("x = !a && b || c ? i++ : --j;"),
+               "(block (= x (?: (|| (&& (! a) b) c) (post++ i) (-- j))))");
+    assert_eq!(sexpr("if (a < 1) { b = 2; } else { return; }"),
+               "(block (if (< a 1) (block (= b 2)) (block (return))))");
+  }
+
+  #[test]
+  fn test_format() {
+    let parse = |text: &str| Parser::new(Tokenizer::new(text).tokenize().unwrap()).parse();
+
+    assert_eq!(format(&parse("var a=(1+2)*3;b = a-(2-1);f(x)( y , 'it\\'s');")),
+               "var a = (1 + 2) * 3;\nb = a - (2 - 1);\nf(x)(y, 'it\\'s');\n");
+    assert_eq!(format(&parse("if (a) { b(); } else if (c) { while (d) { e -= 1; } } else { }")),
+               "if (a) {\n  b();\n} else if (c) {\n  while (d) {\n    e = e - 1;\n  }\n} else {}\n");
+
+    let snippets = [
+      "var x = -(-a) - -1 + !(a && b) * ~c; var y = a ? b ? 1 : 2 : (c ? 3 : 4) || d;",
+      "var o = {a: 1, 'b c': [1, 2, {}], [k + 1]: fn(x) { return x?.y ?? (x.z)[0]; }}; ({a: 1}).a;",
+      "function f(a, b) { return a << b >> 1 | a & b ^ c; } var g = (x) => { return x++ + ++x; };",
+      "for (var i = 0, j = 1; i < 3; i += 1) { continue; } for (;;) { break; } for (k in o) {}",
+      "outer: do { switch (x) { case 1: y = 'a\\n\\t\\u0001'; break outer; default: } } while (x < (1 == 2));",
+      "var v = { var t = typeof a; void t }; label: { f(v); } (1).toString(); a = b == c;"
+    ];
+
+    for text in snippets.iter() {
+      let formatted = format(&parse(text));
+      assert_eq!(parse(&formatted), parse(text), "{}", formatted);
+      assert_eq!(format(&parse(&formatted)), formatted);
+    }
+  }
+
+  #[test]
+  fn test_graphviz_ids() {
+    let graph = || {
+      let mut ast = Parser::new(Tokenizer::new("var a = f(1, 2);").tokenize().unwrap()).parse();
+      let mut graphviz = GraphvizVisitor::new();
+      ast.visit(&mut graphviz);
+      graphviz.text()
+    };
+
+    let text = graph();
+    assert!(text.starts_with("\tnode0[label=\"block\"]\n\tnode0 -> node1\n\tnode1[label=\"var\"]\n"));
+    assert!(text.contains("\tnode1 -> node2\n\tnode1 -> node3\n\tnode2[label=\"a\"]\n\tnode3[label="));
+    assert!(text.contains("\tnode7[label=\"2\"]\n"));
+    assert_eq!(text, graph());
+  }
+}
@@ -2,17 +2,72 @@ use syntax_tree::Visitor;
 use syntax_tree::Node;
 use syntax_tree::NodeType;
 use frame_stack::FrameStackTree;
+use diagnostic::Diagnostic;
 
 pub fn build_frame_stack(ast: &mut Node) -> FrameStackTree {
   let mut fstack = FrameStackTree::new();
-  ast.visit(&mut LocalPass::new(&mut fstack));
+  analyze_locals(&mut fstack, ast);
   fstack.reset();
-  ast.visit(&mut GlobalPass::new(&mut fstack));
+  analyze_globals(&mut fstack, ast);
   fstack.reset();
 
   fstack
 }
 
+// The passes of `build_frame_stack` for a program analyzed a statement at a
+// time: every statement goes through `analyze_locals`, in program order, and
+// then through `analyze_globals`. The stack is reset between the passes.
+pub fn analyze_locals(fstack: &mut FrameStackTree, stmt: &mut Node) {
+  stmt.visit(&mut LocalPass::new(fstack));
+}
+
+pub fn analyze_globals(fstack: &mut FrameStackTree, stmt: &mut Node) {
+  stmt.visit(&mut GlobalPass::new(fstack));
+}
+
+// Errors for assignments, updates and redeclarations of variables declared
+// with `const`. `fstack` must have gone through `analyze_locals` and be reset.
+pub fn check_consts(fstack: &mut FrameStackTree, stmt: &mut Node) -> Vec<Diagnostic> {
+  ConstChecker::new().check(fstack, stmt)
+}
+
+// `check_consts` for a program checked a statement at a time, in program
+// order. The constants declared by the previous statements are remembered.
+pub struct ConstChecker {
+  // (frame, name) of the constants declared so far
+  declared: Vec<(usize, String)>
+}
+
+impl ConstChecker {
+  pub fn new() -> ConstChecker {
+    ConstChecker { declared: vec![] }
+  }
+
+  pub fn check(&mut self, fstack: &mut FrameStackTree, stmt: &mut Node) -> Vec<Diagnostic> {
+    let mut pass = ConstPass { fstack: fstack, declared: &mut self.declared, errors: vec![] };
+    stmt.visit(&mut pass);
+    pass.errors
+  }
+}
+
+// Whether the function refers to `arguments`, which is then a local
+// variable holding the array of all the arguments it was called with
+pub fn uses_arguments(func: &Node) -> bool {
+  let is_param = func.body[0].body.iter()
+    .any(|p| p.type_ == NodeType::Symbol("arguments".to_string()));
+
+  !is_param && refers_to_arguments(&func.body[1])
+}
+
+fn refers_to_arguments(node: &Node) -> bool {
+  match node.type_ {
+    NodeType::Symbol(ref name) => name == "arguments",
+    // nested functions have their own
+    NodeType::Function => false,
+    _ => node.body.iter().any(refers_to_arguments)
+  }
+}
+
 struct LocalPass<'a> {
   fstack: &'a mut FrameStackTree
 }
@@ -31,7 +86,11 @@ impl<'a> Visitor for LocalPass<'a> {
       NodeType::Symbol(ref s) => s,
       _ => panic!()
     };
-    self.fstack.put_var(&name); 
+    if node.type_ == NodeType::StmtConst {
+      self.fstack.put_const(&name);
+    } else {
+      self.fstack.put_var(&name);
+    }
   }
 
   fn enter_fun(&mut self, node: &mut Node) {
@@ -46,6 +105,10 @@ impl<'a> Visitor for LocalPass<'a> {
         self.fstack.frames()[frame].var_offsets.insert(0, s.clone());
       }
     }
+
+    if uses_arguments(node) {
+      self.fstack.put_var(&"arguments".to_string());
+    }
   }
 
   fn exit_fun(&mut self, _node: &mut Node) {
@@ -86,6 +149,70 @@ impl<'a> Visitor for GlobalPass<'a> {
   }
 }
 
+struct ConstPass<'a> {
+  fstack: &'a mut FrameStackTree,
+  declared: &'a mut Vec<(usize, String)>,
+  errors: Vec<Diagnostic>
+}
+
+impl<'a> ConstPass<'a> {
+  fn check_target(&mut self, target: &Node) {
+    let name = match target.type_ {
+      NodeType::Symbol(ref s) => s,
+      _ => { return; }
+    };
+
+    if let Some(var) = self.fstack.find_var(name) {
+      if self.fstack.frames()[var.frame_id].consts.contains(name) {
+        let msg = format!("cannot assign to '{}', it is declared as a constant", name);
+        self.errors.push(Diagnostic::error(msg, target.span.map(|s| s.start)));
+      }
+    }
+  }
+}
+
+impl<'a> Visitor for ConstPass<'a> {
+  fn enter_assign(&mut self, node: &mut Node) {
+    self.check_target(&node.body[0]);
+  }
+
+  fn enter_var(&mut self, node: &mut Node) {
+    let name = match node.body[0].type_ {
+      NodeType::Symbol(ref s) => s.clone(),
+      _ => { return; }
+    };
+    let frame = self.fstack.cur_frame();
+
+    let redeclared = match node.type_ {
+      // function declarations are hoisted above the constant
+      NodeType::StmtFunction => self.fstack.frames()[frame].consts.contains(&name),
+      _ => self.declared.iter().any(|&(f, ref n)| f == frame && *n == name)
+    };
+    if redeclared {
+      let msg = format!("cannot redeclare '{}', it is declared as a constant", name);
+      self.errors.push(Diagnostic::error(msg, node.body[0].span.map(|s| s.start)));
+    }
+
+    if node.type_ == NodeType::StmtConst {
+      self.declared.push((frame, name));
+    }
+  }
+
+  fn visit(&mut self, node: &mut Node) {
+    if let NodeType::Update { .. } = node.type_ {
+      self.check_target(&node.body[0]);
+    }
+  }
+
+  fn enter_fun(&mut self, _node: &mut Node) {
+    self.fstack.enter();
+  }
+
+  fn exit_fun(&mut self, _node: &mut Node) {
+    self.fstack.exit();
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -141,5 +268,43 @@ mod tests {
       assert!(frame_has_var(&global_pass.fstack.frames()[0], "g3"));
     }
   }
-}
 
+  fn const_errors(text: &str) -> Vec<String> {
+    let mut ast = Parser::new(Tokenizer::new(&text).tokenize().unwrap()).parse();
+    let mut fstack = FrameStackTree::new();
+    analyze_locals(&mut fstack, &mut ast);
+    fstack.reset();
+    check_consts(&mut fstack, &mut ast).into_iter().map(|e| e.message).collect()
+  }
+
+  #[test]
+  fn test_consts() {
+    assert_eq!(const_errors("const a = 1; a = 2;"),
+      vec!["cannot assign to 'a', it is declared as a constant"]);
+    assert_eq!(const_errors("const a = 1; var f = fn() { a += 1; a++; };").len(), 2);
+    assert!(const_errors("var a = 1; a = 2; a++;").is_empty());
+    assert!(const_errors("const a = {}; a.b = 2;").is_empty());
+    // a local variable shadows the constant
+    assert!(const_errors("const a = 1; var f = fn(a) { a = 2; }; var g = fn() { var a = 1; a = 2; };").is_empty());
+
+    let mut ast = Parser::new(Tokenizer::new("const a = 1;\n  a = 2;").tokenize().unwrap()).parse();
+    let mut fstack = FrameStackTree::new();
+    analyze_locals(&mut fstack, &mut ast);
+    fstack.reset();
+    let errors = check_consts(&mut fstack, &mut ast);
+    assert_eq!(errors[0].pos.map(|p| (p.line, p.col)), Some((2, 2)));
+  }
+
+  #[test]
+  fn test_const_redeclaration() {
+    assert_eq!(const_errors("const a = 1; var a = 2;"),
+      vec!["cannot redeclare 'a', it is declared as a constant"]);
+    assert_eq!(const_errors("const a = 1; const a = 3;"),
+      vec!["cannot redeclare 'a', it is declared as a constant"]);
+    assert_eq!(const_errors("const a = 1; function a() {}"),
+      vec!["cannot redeclare 'a', it is declared as a constant"]);
+    assert_eq!(const_errors("const a = 1; fn a() {}").len(), 1);
+    // other scopes may reuse the name
+    assert!(const_errors("const a = 1; var f = fn() { var a = 2; const b = 1; }; const b = 2;").is_empty());
+  }
+}
@@ -0,0 +1,2068 @@
+use std::fmt;
+
+use assembler::{Encoding, OpCode};
+use util::json_string;
+
+// 2^24, integers below it are exactly representable as f32
+const MAX_INDEX: f32 = 16777216.0;
+
+// allocations between automatic collections
+const GC_THRESHOLD: usize = 4096;
+
+pub type Native = fn(&mut Vm, &[Value]) -> Result<Value, VmError>;
+
+#[derive(Clone, Copy, Debug)]
+pub struct NativeFn {
+  pub func: Native,
+  // the parameter count, missing arguments are null; none for natives
+  // taking any number of arguments
+  pub params: Option<u32>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Key {
+  Index(usize),
+  Name(String)
+}
+
+#[derive(Clone, Debug)]
+pub struct Function {
+  pub start: u32,
+  pub env: Vec<Value>,
+  pub frame_size: u32,
+  // the parameter count of a function using `arguments`
+  pub params: Option<u32>
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+  // `null`, `undefined` and missing values alike, falsy and equal only to itself.
+  // `typeof` names it "undefined".
+  Null,
+  Bool(bool),
+  Int(u32),
+  Num(f32),
+  Str(String),
+  Fn(Function),
+  Native(NativeFn),
+  // reference to a heap object (frame, array or dict)
+  Obj(usize),
+  // address of a variable or a field inside a heap object
+  Slot(usize, Key)
+}
+
+#[derive(Debug)]
+pub enum Object {
+  Frame(Vec<Value>),
+  Array(Vec<Value>),
+  Dict(Vec<(String, Value)>)
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VmError {
+  UnknownOpCode(u8, usize),
+  UnexpectedEnd(usize),
+  StackUnderflow(usize),
+  // (parameters, arguments) of a native called with more arguments than it takes
+  TooManyArguments(u32, usize),
+  TypeError(String),
+  // array index which is not an integer exactly representable as f32
+  InvalidIndex(f32)
+}
+
+impl fmt::Display for VmError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      &VmError::UnknownOpCode(op, ip) => write!(f, "Unknown opcode 0x{:02x} at {}", op, ip),
+      &VmError::UnexpectedEnd(ip) => write!(f, "Unexpected end of code at {}", ip),
+      &VmError::StackUnderflow(ip) => write!(f, "Stack underflow at {}", ip),
+      &VmError::TooManyArguments(params, args) =>
+        write!(f, "Too many arguments: expected at most {}, got {}", params, args),
+      &VmError::TypeError(ref msg) => write!(f, "Type error: {}", msg),
+      &VmError::InvalidIndex(n) => write!(f, "Invalid array index {}", n)
+    }
+  }
+}
+
+// Wraps the integer part modulo 2^32, NaN and infinities become 0
+fn to_int32(n: f32) -> i32 {
+  if n.is_finite() {
+    (n as f64).trunc().rem_euclid(4294967296.0) as u32 as i32
+  } else {
+    0
+  }
+}
+
+pub struct Vm {
+  code: Vec<u8>,
+  encoding: Encoding,
+  ip: usize,
+  stack: Vec<Value>,
+  heap: Vec<Option<Object>>,
+  // slots of collected objects, reused by `alloc`
+  free: Vec<usize>,
+  // allocations since the last collection
+  allocated: usize,
+  sys: Vec<Value>,
+  output: Option<String>,
+  halted: bool,
+  exit_code: Option<i32>
+}
+
+impl Vm {
+  // The bytecode may start with an encoding header
+  pub fn new(bytecode: Vec<u8>) -> Vm {
+    let (encoding, code) = Encoding::read_header(&bytecode);
+
+    let mut vm = Vm {
+      code: code.to_vec(),
+      encoding: encoding,
+      ip: 0,
+      stack: vec![],
+      heap: vec![],
+      free: vec![],
+      allocated: 0,
+      sys: vec![],
+      output: None,
+      halted: false,
+      exit_code: None
+    };
+
+    let io = vm.alloc(Object::Dict(vec![
+      ("println".to_string(), native(native_println, None)),
+      ("print".to_string(), native(native_print, None))
+    ]));
+    let sys = vm.alloc(Object::Dict(vec![
+      ("exit".to_string(), native(native_exit, Some(1)))
+    ]));
+    let std = vm.alloc(Object::Dict(vec![
+      ("io".to_string(), io),
+      ("sys".to_string(), sys),
+      ("exit".to_string(), native(native_exit, Some(1))),
+      ("apply".to_string(), native(native_apply, Some(2))),
+      ("array".to_string(), native(native_array, Some(2))),
+      ("map".to_string(), native(native_map, Some(2))),
+      ("filter".to_string(), native(native_filter, Some(2))),
+      ("iter".to_string(), native(native_iter, Some(1))),
+      ("jsonStringify".to_string(), native(native_json_stringify, Some(1))),
+      ("jsonParse".to_string(), native(native_json_parse, Some(1)))
+    ]));
+
+    let math = vm.alloc(Object::Dict(vec![
+      ("max".to_string(), native(native_max, None)),
+      ("min".to_string(), native(native_min, None))
+    ]));
+
+    // indexed by the ids of the compiler's system objects
+    vm.sys.push(std);
+    vm.sys.push(math);
+
+    vm
+  }
+
+  pub fn code(&self) -> &[u8] {
+    &self.code
+  }
+
+  pub fn encoding(&self) -> Encoding {
+    self.encoding
+  }
+
+  pub fn stack(&self) -> &[Value] {
+    &self.stack
+  }
+
+  // Collect program output instead of printing it to stdout
+  pub fn capture_output(&mut self) {
+    self.output = Some(String::new());
+  }
+
+  pub fn output(&self) -> &str {
+    self.output.as_ref().map(|s| s.as_str()).unwrap_or("")
+  }
+
+  pub fn write(&mut self, text: &str) {
+    if let Some(ref mut out) = self.output {
+      out.push_str(text);
+    } else {
+      print!("{}", text);
+    }
+  }
+
+  pub fn halt(&mut self) {
+    self.halted = true;
+  }
+
+  pub fn halted(&self) -> bool {
+    self.halted
+  }
+
+  // Length of the code, where the code passed to `extend` is loaded
+  pub fn code_len(&self) -> usize {
+    self.code.len()
+  }
+
+  // Appends the code of a program continuing the one run so far, compiled by
+  // `pipeline::compile_continuation`, and makes it the next to run. It runs in
+  // the global frame, grown to `frame_size` variables; whatever a failed run
+  // left on the stack above the frame is dropped.
+  pub fn extend(&mut self, bytecode: &[u8], frame_size: usize) {
+    let (_, code) = Encoding::read_header(bytecode);
+
+    self.stack.truncate(1);
+    if let Some(&Value::Obj(frame)) = self.stack.first() {
+      if let Object::Frame(ref mut vars) = *self.object_mut(frame) {
+        if vars.len() < frame_size {
+          vars.resize(frame_size, Value::Null);
+        }
+      }
+    }
+
+    self.ip = self.code.len();
+    self.code.extend_from_slice(code);
+  }
+
+  // Exit status of the program: the code passed to `exit`, or the value of
+  // a top-level `return` if it is an integer in 0..255
+  pub fn exit_code(&self) -> Option<i32> {
+    if self.exit_code.is_some() {
+      return self.exit_code;
+    }
+
+    match self.stack.last() {
+      Some(&Value::Num(n)) if n >= 0.0 && n <= 255.0 && n.fract() == 0.0 => Some(n as i32),
+      _ => None
+    }
+  }
+
+  pub fn alloc(&mut self, obj: Object) -> Value {
+    self.allocated += 1;
+
+    if let Some(slot) = self.free.pop() {
+      self.heap[slot] = Some(obj);
+      Value::Obj(slot)
+    } else {
+      self.heap.push(Some(obj));
+      Value::Obj(self.heap.len() - 1)
+    }
+  }
+
+  // Number of heap objects which haven't been collected
+  pub fn live_objects(&self) -> usize {
+    self.heap.len() - self.free.len()
+  }
+
+  // Mark and sweep: frees every heap object which is not reachable from
+  // the stack or the system objects, cycles included.
+  pub fn collect(&mut self) {
+    let mut marked = vec![false; self.heap.len()];
+    let mut pending = vec![];
+
+    for value in self.stack.iter().chain(self.sys.iter()) {
+      references(value, &mut pending);
+    }
+
+    while let Some(obj) = pending.pop() {
+      if marked[obj] {
+        continue;
+      }
+      marked[obj] = true;
+
+      match self.heap[obj] {
+        Some(Object::Frame(ref values)) |
+        Some(Object::Array(ref values)) => {
+          for value in values.iter() {
+            references(value, &mut pending);
+          }
+        },
+        Some(Object::Dict(ref entries)) => {
+          for &(_, ref value) in entries.iter() {
+            references(value, &mut pending);
+          }
+        },
+        None => {}
+      }
+    }
+
+    for (obj, &live) in marked.iter().enumerate() {
+      if !live && self.heap[obj].is_some() {
+        self.heap[obj] = None;
+        self.free.push(obj);
+      }
+    }
+
+    self.allocated = 0;
+  }
+
+  pub fn run(&mut self) -> Result<(), VmError> {
+    self.run_traced(|_, _| {})
+  }
+
+  // Runs the program calling `trace` after each top-level instruction
+  // with the address the instruction was read from
+  pub fn run_traced<F: FnMut(&Vm, usize)>(&mut self, mut trace: F) -> Result<(), VmError> {
+    while self.ip < self.code.len() && !self.halted {
+      let ip = self.ip;
+      self.step()?;
+      trace(self, ip);
+
+      if self.allocated >= GC_THRESHOLD {
+        self.collect();
+      }
+    }
+
+    Ok(())
+  }
+
+  pub fn step(&mut self) -> Result<(), VmError> {
+    let ip = self.ip;
+    let byte = self.code[ip];
+    let op = OpCode::from_u8(byte).ok_or(VmError::UnknownOpCode(byte, ip))?;
+    self.ip += 1;
+
+    match op {
+      OpCode::PushNum => {
+        let value = self.read_f32()?;
+        self.stack.push(Value::Num(value));
+      },
+      OpCode::PushStr => {
+        let length = self.read_u32()? as usize;
+        let value = String::from_utf8_lossy(self.read(length)?).into_owned();
+        self.stack.push(Value::Str(value));
+      },
+      OpCode::PushInt => {
+        let value = self.read_u32()?;
+        self.stack.push(Value::Int(value));
+      },
+      OpCode::PushNull => {
+        self.stack.push(Value::Null);
+      },
+      OpCode::PushBool => {
+        let value = self.read_u32()?;
+        self.stack.push(Value::Bool(value != 0));
+      },
+      OpCode::PushFn | OpCode::PushFnArgs => {
+        let frames_count = self.read_u32()? as usize;
+        let frames_offset = self.read_u32()? as usize;
+        let frame_size = self.read_u32()?;
+        let params = if op == OpCode::PushFnArgs { Some(self.read_u32()?) } else { None };
+
+        let begin = self.stack.len().checked_sub(frames_offset + 1)
+          .ok_or(VmError::StackUnderflow(ip))?;
+        let env = self.stack[begin..begin + frames_count].to_vec();
+
+        let start = match self.pop()? {
+          Value::Int(addr) => addr,
+          v => return Err(VmError::TypeError(format!("function address expected, got {:?}", v)))
+        };
+
+        self.stack.push(Value::Fn(Function { start: start, env: env, frame_size: frame_size, params: params }));
+      },
+      OpCode::Take => {
+        let offset = self.read_u32()? as usize;
+        let value = self.peek(offset)?.clone();
+        self.stack.push(value);
+      },
+      OpCode::Swap => {
+        let a = self.read_u32()? as usize;
+        let b = self.read_u32()? as usize;
+        let len = self.stack.len();
+
+        if a >= len || b >= len {
+          return Err(VmError::StackUnderflow(ip));
+        }
+
+        self.stack.swap(len - a - 1, len - b - 1);
+      },
+      OpCode::Pop => {
+        let n = self.read_u32()? as usize;
+        let len = self.stack.len().checked_sub(n).ok_or(VmError::StackUnderflow(ip))?;
+        self.stack.truncate(len);
+      },
+      OpCode::Load => {
+        let offset = self.read_u32()? as usize;
+        let addr = self.pop()?;
+        let value = match addr {
+          Value::Int(id) => self.sys.get(id as usize + offset).cloned()
+            .ok_or(VmError::TypeError(format!("no system object {}", id)))?,
+          Value::Slot(obj, Key::Index(i)) => self.load(obj, &Key::Index(i + offset))?,
+          Value::Slot(obj, key) => self.load(obj, &key)?,
+          v => return Err(VmError::TypeError(format!("cannot load from {:?}", v)))
+        };
+        self.stack.push(value);
+      },
+      OpCode::Store => {
+        let addr = self.pop()?;
+        let value = self.pop()?;
+
+        match addr {
+          Value::Slot(obj, key) => self.store(obj, key, value)?,
+          v => return Err(VmError::TypeError(format!("cannot store to {:?}", v)))
+        }
+      },
+      OpCode::Offset => {
+        // address arithmetic is done on integers only, never on numbers
+        let offset = self.read_u32()? as usize;
+        let addr = match self.pop()? {
+          Value::Obj(obj) => Value::Slot(obj, Key::Index(offset)),
+          Value::Slot(obj, Key::Index(i)) => Value::Slot(obj, Key::Index(i + offset)),
+          v => return Err(VmError::TypeError(format!("cannot offset {:?}", v)))
+        };
+        self.stack.push(addr);
+      },
+      OpCode::JumpIf => {
+        let addr = self.pop_addr()?;
+        let cond = self.pop()?;
+
+        if self.truthy(&cond) {
+          self.ip = addr;
+        }
+      },
+      OpCode::Jump => {
+        self.ip = self.pop_addr()?;
+      },
+      OpCode::Call => {
+        let callee = self.pop()?;
+        let n_args = match self.pop()? {
+          Value::Int(n) => n as usize,
+          v => return Err(VmError::TypeError(format!("argument count expected, got {:?}", v)))
+        };
+
+        let (func, this) = match callee {
+          Value::Slot(obj, key) => {
+            let this = match *self.object(obj) {
+              Object::Frame(_) => Value::Null,
+              _ => Value::Obj(obj)
+            };
+            (self.load(obj, &key)?, this)
+          },
+          v => (v, Value::Null)
+        };
+
+        self.call(func, this, n_args)?;
+      },
+      OpCode::Nop => {},
+      OpCode::Add => {
+        let b = self.pop()?;
+        let a = self.pop()?;
+
+        let value = match (a, b) {
+          (a @ Value::Str(_), b) | (a, b @ Value::Str(_)) =>
+            Value::Str(self.to_string(&a) + &self.to_string(&b)),
+          (a, b) => Value::Num(self.to_number(&a)? + self.to_number(&b)?)
+        };
+
+        self.stack.push(value);
+      },
+      OpCode::Sub => self.op_arith(|a, b| a - b)?,
+      OpCode::Mul => self.op_arith(|a, b| a * b)?,
+      OpCode::Div => self.op_arith(|a, b| a / b)?,
+      OpCode::Mod => self.op_arith(|a, b| a % b)?,
+      OpCode::Neg => {
+        let a = self.pop()?;
+        let value = -self.to_number(&a)?;
+        self.stack.push(Value::Num(value));
+      },
+      OpCode::ToNumber => {
+        let a = self.pop()?;
+        let value = self.coerce_number(&a);
+        self.stack.push(Value::Num(value));
+      },
+      OpCode::TypeOf => {
+        let a = self.pop()?;
+        let name = match a {
+          Value::Null => "undefined",
+          Value::Bool(_) => "boolean",
+          Value::Int(_) | Value::Num(_) => "number",
+          Value::Str(_) => "string",
+          Value::Fn(_) | Value::Native(_) => "function",
+          Value::Obj(_) | Value::Slot(_, _) => "object"
+        };
+        self.stack.push(Value::Str(name.to_string()));
+      },
+      OpCode::BitAnd => self.op_bitwise(|a, b| a & b)?,
+      OpCode::BitOr => self.op_bitwise(|a, b| a | b)?,
+      OpCode::BitXor => self.op_bitwise(|a, b| a ^ b)?,
+      OpCode::Shl => self.op_bitwise(|a, b| a.wrapping_shl(b as u32 & 31))?,
+      OpCode::Shr => self.op_bitwise(|a, b| a.wrapping_shr(b as u32 & 31))?,
+      OpCode::BitNot => {
+        let a = self.pop()?;
+        let value = !to_int32(self.to_number(&a)?);
+        self.stack.push(Value::Num(value as f32));
+      },
+      OpCode::Lt => self.op_compare(|a, b| a < b, |a, b| a < b)?,
+      OpCode::Gt => self.op_compare(|a, b| a > b, |a, b| a > b)?,
+      OpCode::Leq => self.op_compare(|a, b| a <= b, |a, b| a <= b)?,
+      OpCode::Geq => self.op_compare(|a, b| a >= b, |a, b| a >= b)?,
+      OpCode::Eq | OpCode::NotEq => {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let eq = self.equals(&a, &b);
+        self.stack.push(Value::Bool(if let OpCode::Eq = op { eq } else { !eq }));
+      },
+      OpCode::And | OpCode::Or => {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        let a_truthy = self.truthy(&a);
+        let pick_a = if let OpCode::And = op { !a_truthy } else { a_truthy };
+        self.stack.push(if pick_a { a } else { b });
+      },
+      OpCode::Not => {
+        let a = self.pop()?;
+        let value = !self.truthy(&a);
+        self.stack.push(Value::Bool(value));
+      },
+      OpCode::Get => {
+        let key = self.pop()?;
+        let obj = match self.pop()? {
+          Value::Obj(obj) => obj,
+          v => return Err(VmError::TypeError(format!("cannot get property '{}' of {}",
+                                                     self.to_string(&key), self.to_string(&v))))
+        };
+        let key = self.to_key(obj, &key)?;
+        self.stack.push(Value::Slot(obj, key));
+      },
+      OpCode::PushDict => {
+        let length = self.read_u32()? as usize;
+        let begin = self.stack.len().checked_sub(length * 2).ok_or(VmError::StackUnderflow(ip))?;
+        let pairs = self.stack.split_off(begin);
+
+        // a repeated key keeps its first position and takes the last value
+        let mut entries: Vec<(String, Value)> = vec![];
+        for pair in pairs.chunks(2) {
+          let key = self.to_string(&pair[0]);
+          match entries.iter().position(|&(ref k, _)| *k == key) {
+            Some(i) => entries[i].1 = pair[1].clone(),
+            None => entries.push((key, pair[1].clone()))
+          }
+        }
+
+        let dict = self.alloc(Object::Dict(entries));
+        self.stack.push(dict);
+      },
+      OpCode::PushArray => {
+        let length = self.read_u32()? as usize;
+        let begin = self.stack.len().checked_sub(length).ok_or(VmError::StackUnderflow(ip))?;
+        let items = self.stack.split_off(begin);
+
+        let array = self.alloc(Object::Array(items));
+        self.stack.push(array);
+      },
+      OpCode::Len => {
+        let value = self.pop()?;
+        let length = match value {
+          Value::Str(ref s) => Value::Num(s.chars().count() as f32),
+          // a dict's own `length` key comes first, as with `get`
+          Value::Obj(obj) => match self.object(obj) {
+            &Object::Frame(_) => return Err(VmError::TypeError("frame has no length".to_string())),
+            _ => self.load(obj, &Key::Name("length".to_string()))?
+          },
+          v => return Err(VmError::TypeError(format!("{} has no length", self.to_string(&v))))
+        };
+        self.stack.push(length);
+      }
+    }
+
+    Ok(())
+  }
+
+  // Calls a function from native code and runs it until it returns.
+  // The return address points past the end of the code, so it can't be
+  // mistaken for a real one, and the caller's ip is restored afterwards.
+  pub fn call_value(&mut self, func: Value, args: &[Value]) -> Result<Value, VmError> {
+    self.call_method(func, Value::Null, args)
+  }
+
+  // Same as `call_value` with `this` bound to the given value
+  pub fn call_method(&mut self, func: Value, this: Value, args: &[Value]) -> Result<Value, VmError> {
+    let ip = self.ip;
+    let ret = self.code.len();
+
+    self.stack.push(Value::Int(ret as u32));
+    self.stack.extend(args.iter().cloned());
+    self.call(func, this, args.len())?;
+
+    while self.ip != ret && !self.halted {
+      self.step()?;
+    }
+
+    let result = if self.halted { Value::Null } else { self.pop()? };
+    self.ip = ip;
+
+    Ok(result)
+  }
+
+  fn call(&mut self, func: Value, this: Value, n_args: usize) -> Result<(), VmError> {
+    let ip = self.ip;
+    let begin = self.stack.len().checked_sub(n_args).ok_or(VmError::StackUnderflow(ip))?;
+
+    if let Value::Native(NativeFn { params: Some(params), .. }) = func {
+      if n_args > params as usize {
+        return Err(VmError::TooManyArguments(params, n_args));
+      }
+    }
+
+    let args = self.stack.split_off(begin);
+
+    match func {
+      Value::Native(native) => {
+        let ret = self.pop_addr()?;
+        let result = (native.func)(self, &args)?;
+        self.stack.push(result);
+        self.ip = ret;
+      },
+      Value::Fn(func) => {
+        // arguments are stored in reverse order, followed by `this`. A function
+        // using `arguments` gets them laid out by its parameters instead.
+        let n_slots = func.params.map_or(n_args, |n| n as usize);
+        let mut frame = vec![Value::Null; func.frame_size as usize];
+        for (i, slot) in frame.iter_mut().take(n_slots).enumerate() {
+          *slot = args.get(n_slots - i - 1).cloned().unwrap_or(Value::Null);
+        }
+        if n_slots < frame.len() {
+          frame[n_slots] = this;
+        }
+
+        let frame = self.alloc(Object::Frame(frame));
+        self.stack.push(frame);
+        self.stack.extend(func.env.into_iter());
+
+        if func.params.is_some() {
+          let array = self.alloc(Object::Array(args));
+          self.stack.push(array);
+        }
+
+        self.ip = func.start as usize;
+      },
+      v => return Err(VmError::TypeError(format!("{} is not a function", self.to_string(&v))))
+    }
+
+    Ok(())
+  }
+
+  fn to_key(&self, obj: usize, key: &Value) -> Result<Key, VmError> {
+    match (self.object(obj), key) {
+      (&Object::Dict(_), key) => Ok(Key::Name(self.to_string(key))),
+      (_, &Value::Int(i)) => Ok(Key::Index(i as usize)),
+      // from 2^24 on integers can't be told apart from their neighbours
+      (_, &Value::Num(n)) if n >= 0.0 => if n.fract() == 0.0 && n < MAX_INDEX {
+        Ok(Key::Index(n as usize))
+      } else {
+        Err(VmError::InvalidIndex(n))
+      },
+      (_, &Value::Str(ref s)) => Ok(Key::Name(s.clone())),
+      (_, key) => Err(VmError::TypeError(format!("invalid index {}", self.to_string(key))))
+    }
+  }
+
+  fn load(&self, obj: usize, key: &Key) -> Result<Value, VmError> {
+    let value = match (self.object(obj), key) {
+      (&Object::Frame(ref vars), &Key::Index(i)) => vars.get(i).cloned()
+        .ok_or(VmError::TypeError(format!("frame slot {} out of range", i)))?,
+      (&Object::Array(ref items), &Key::Index(i)) => items.get(i).cloned().unwrap_or(Value::Null),
+      (&Object::Array(ref items), &Key::Name(ref name)) if name == "length" =>
+        Value::Num(items.len() as f32),
+      (&Object::Dict(ref entries), &Key::Name(ref name)) => {
+        match entries.iter().find(|e| &e.0 == name) {
+          Some(e) => e.1.clone(),
+          None if name == "length" => Value::Num(entries.len() as f32),
+          None => Value::Null
+        }
+      },
+      _ => Value::Null
+    };
+
+    Ok(value)
+  }
+
+  fn store(&mut self, obj: usize, key: Key, value: Value) -> Result<(), VmError> {
+    // assigning the length of an array truncates it or pads it with nulls
+    if let (&Object::Array(_), &Key::Name(ref name)) = (self.object(obj), &key) {
+      if name == "length" {
+        let n = self.to_number(&value)?;
+
+        if n < 0.0 || n.fract() != 0.0 || n >= MAX_INDEX {
+          return Err(VmError::InvalidIndex(n));
+        }
+
+        if let Object::Array(ref mut items) = *self.object_mut(obj) {
+          items.resize(n as usize, Value::Null);
+        }
+
+        return Ok(());
+      }
+    }
+
+    match (self.object_mut(obj), key) {
+      (&mut Object::Frame(ref mut vars), Key::Index(i)) if i < vars.len() => {
+        vars[i] = value;
+      },
+      (&mut Object::Array(ref mut items), Key::Index(i)) => {
+        if i >= items.len() {
+          items.resize(i + 1, Value::Null);
+        }
+        items[i] = value;
+      },
+      (&mut Object::Dict(ref mut entries), Key::Name(name)) => {
+        match entries.iter().position(|e| e.0 == name) {
+          Some(pos) => entries[pos].1 = value,
+          None => entries.push((name, value))
+        }
+      },
+      (_, key) => return Err(VmError::TypeError(format!("cannot store to {:?}", key)))
+    }
+
+    Ok(())
+  }
+
+  fn op_arith<F>(&mut self, op: F) -> Result<(), VmError> where F: Fn(f32, f32) -> f32 {
+    let b = self.pop()?;
+    let a = self.pop()?;
+    let value = op(self.to_number(&a)?, self.to_number(&b)?);
+    self.stack.push(Value::Num(value));
+    Ok(())
+  }
+
+  // Operands are converted to 32-bit integers
+  fn op_bitwise<F>(&mut self, op: F) -> Result<(), VmError> where F: Fn(i32, i32) -> i32 {
+    let b = self.pop()?;
+    let a = self.pop()?;
+    let value = op(to_int32(self.to_number(&a)?), to_int32(self.to_number(&b)?));
+    self.stack.push(Value::Num(value as f32));
+    Ok(())
+  }
+
+  fn op_compare<F, G>(&mut self, num: F, string: G) -> Result<(), VmError>
+    where F: Fn(f32, f32) -> bool, G: Fn(&str, &str) -> bool {
+    let b = self.pop()?;
+    let a = self.pop()?;
+    let value = match (&a, &b) {
+      (&Value::Str(ref a), &Value::Str(ref b)) => string(a, b),
+      (a, b) => num(self.to_number(a)?, self.to_number(b)?)
+    };
+    self.stack.push(Value::Bool(value));
+    Ok(())
+  }
+
+  pub fn equals(&self, a: &Value, b: &Value) -> bool {
+    match (a, b) {
+      (&Value::Null, &Value::Null) => true,
+      (&Value::Str(ref a), &Value::Str(ref b)) => a == b,
+      (&Value::Obj(a), &Value::Obj(b)) => a == b,
+      (&Value::Bool(a), &Value::Bool(b)) => a == b,
+      // closures are the same if they share the code and the captured frames
+      (&Value::Fn(ref a), &Value::Fn(ref b)) =>
+        a.start == b.start && a.env.len() == b.env.len() &&
+          a.env.iter().zip(b.env.iter()).all(|(a, b)| self.equals(a, b)),
+      (&Value::Native(a), &Value::Native(b)) => a.func as usize == b.func as usize,
+      (&Value::Fn(_), _) | (_, &Value::Fn(_)) |
+      (&Value::Native(_), _) | (_, &Value::Native(_)) => false,
+      (a, b) => match (self.to_number(a), self.to_number(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false
+      }
+    }
+  }
+
+  pub fn truthy(&self, value: &Value) -> bool {
+    match value {
+      &Value::Null => false,
+      &Value::Bool(b) => b,
+      &Value::Int(i) => i != 0,
+      &Value::Num(n) => n != 0.0 && !n.is_nan(),
+      &Value::Str(ref s) => !s.is_empty(),
+      _ => true
+    }
+  }
+
+  pub fn to_number(&self, value: &Value) -> Result<f32, VmError> {
+    match value {
+      &Value::Int(i) => Ok(i as f32),
+      &Value::Num(n) => Ok(n),
+      &Value::Bool(b) => Ok(if b { 1.0 } else { 0.0 }),
+      v => Err(VmError::TypeError(format!("{} is not a number", self.to_string(v))))
+    }
+  }
+
+  // Unary plus conversion, unlike `to_number` never fails: strings are
+  // parsed and values which aren't numbers become NaN
+  pub fn coerce_number(&self, value: &Value) -> f32 {
+    match value {
+      &Value::Null => 0.0,
+      &Value::Str(ref s) if s.trim().is_empty() => 0.0,
+      &Value::Str(ref s) => s.trim().parse::<f32>().unwrap_or(::std::f32::NAN),
+      v => self.to_number(v).unwrap_or(::std::f32::NAN)
+    }
+  }
+
+  pub fn to_string(&self, value: &Value) -> String {
+    match value {
+      &Value::Str(ref s) => s.clone(),
+      v => self.format_value(v)
+    }
+  }
+
+  // Formats value the way it is shown when nested inside arrays and dicts
+  pub fn format_value(&self, value: &Value) -> String {
+    match value {
+      &Value::Null => "null".to_string(),
+      &Value::Bool(b) => b.to_string(),
+      &Value::Int(i) => i.to_string(),
+      &Value::Num(n) => n.to_string(),
+      &Value::Str(ref s) => format!("'{}'", s),
+      &Value::Fn(ref f) => format!("fn@{}", f.start),
+      &Value::Native(_) => "native fn".to_string(),
+      &Value::Obj(obj) => match *self.object(obj) {
+        Object::Frame(_) => format!("frame@{}", obj),
+        Object::Array(ref items) => {
+          let items: Vec<String> = items.iter().map(|v| self.format_value(v)).collect();
+          format!("[{}]", items.join(", "))
+        },
+        Object::Dict(ref entries) => {
+          let entries: Vec<String> = entries.iter()
+            .map(|&(ref k, ref v)| format!("{}: {}", k, self.format_value(v))).collect();
+          format!("{{{}}}", entries.join(", "))
+        }
+      },
+      &Value::Slot(obj, ref key) => format!("&{}{:?}", obj, key)
+    }
+  }
+
+  fn object(&self, obj: usize) -> &Object {
+    self.heap[obj].as_ref().expect("reference to a collected object")
+  }
+
+  fn object_mut(&mut self, obj: usize) -> &mut Object {
+    self.heap[obj].as_mut().expect("reference to a collected object")
+  }
+
+  fn read(&mut self, n: usize) -> Result<&[u8], VmError> {
+    let begin = self.ip;
+    if begin + n > self.code.len() {
+      return Err(VmError::UnexpectedEnd(begin));
+    }
+    self.ip += n;
+    Ok(&self.code[begin..begin + n])
+  }
+
+  // Reads an operand, whatever its width in the encoding
+  fn read_u32(&mut self) -> Result<u32, VmError> {
+    let encoding = self.encoding;
+    Ok(encoding.read_operand(self.read(encoding.operand_size)?))
+  }
+
+  fn read_f32(&mut self) -> Result<f32, VmError> {
+    let encoding = self.encoding;
+    Ok(encoding.read_f32(self.read(4)?))
+  }
+
+  fn peek(&self, offset: usize) -> Result<&Value, VmError> {
+    let len = self.stack.len();
+    if offset >= len {
+      return Err(VmError::StackUnderflow(self.ip));
+    }
+    Ok(&self.stack[len - offset - 1])
+  }
+
+  fn pop(&mut self) -> Result<Value, VmError> {
+    self.stack.pop().ok_or(VmError::StackUnderflow(self.ip))
+  }
+
+  fn pop_addr(&mut self) -> Result<usize, VmError> {
+    match self.pop()? {
+      Value::Int(addr) => Ok(addr as usize),
+      v => Err(VmError::TypeError(format!("code address expected, got {:?}", v)))
+    }
+  }
+}
+
+fn native(func: Native, params: Option<u32>) -> Value {
+  Value::Native(NativeFn { func: func, params: params })
+}
+
+fn native_print(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let text: Vec<String> = args.iter().map(|v| vm.to_string(v)).collect();
+  vm.write(&text.join(" "));
+  Ok(Value::Null)
+}
+
+fn native_println(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  native_print(vm, args)?;
+  vm.write("\n");
+  Ok(Value::Null)
+}
+
+// exit(code) stops the program, code defaults to 0
+fn native_exit(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let code = match args.get(0) {
+    Some(value) => vm.to_number(value)?,
+    None => 0.0
+  };
+
+  if code < 0.0 || code > 255.0 || code.fract() != 0.0 {
+    return Err(VmError::TypeError(format!("exit code expected, got {}", code)));
+  }
+
+  vm.exit_code = Some(code as i32);
+  vm.halt();
+  Ok(Value::Null)
+}
+
+// apply(f, array) calls f with the items of the array as arguments
+fn native_apply(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let func = args.get(0).cloned().unwrap_or(Value::Null);
+  let items = array_items(vm, args.get(1))?;
+  vm.call_value(func, &items)
+}
+
+// array(n, init) creates an array of n copies of init. Objects are not
+// cloned, all elements refer to the same one.
+fn native_array(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let n = vm.to_number(args.get(0).unwrap_or(&Value::Null))?;
+  let init = args.get(1).cloned().unwrap_or(Value::Null);
+
+  if n < 0.0 || n.fract() != 0.0 || n >= MAX_INDEX {
+    return Err(VmError::InvalidIndex(n));
+  }
+
+  Ok(vm.alloc(Object::Array(vec![init; n as usize])))
+}
+
+fn array_items(vm: &Vm, value: Option<&Value>) -> Result<Vec<Value>, VmError> {
+  if let Some(&Value::Obj(obj)) = value {
+    if let Object::Array(ref items) = *vm.object(obj) {
+      return Ok(items.clone());
+    }
+  }
+
+  let value = value.map(|v| vm.to_string(v)).unwrap_or("nothing".to_string());
+  Err(VmError::TypeError(format!("array expected, got {}", value)))
+}
+
+// map(array, f) returns a new array of f(item) for every item
+fn native_map(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let items = array_items(vm, args.get(0))?;
+  let func = args.get(1).cloned().unwrap_or(Value::Null);
+
+  let mut result = vec![];
+  for item in items.into_iter() {
+    result.push(vm.call_value(func.clone(), &[item])?);
+  }
+
+  Ok(vm.alloc(Object::Array(result)))
+}
+
+// filter(array, f) returns a new array of the items for which f(item) is true
+fn native_filter(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let items = array_items(vm, args.get(0))?;
+  let func = args.get(1).cloned().unwrap_or(Value::Null);
+
+  let mut result = vec![];
+  for item in items.into_iter() {
+    let keep = vm.call_value(func.clone(), &[item.clone()])?;
+    if vm.truthy(&keep) {
+      result.push(item);
+    }
+  }
+
+  Ok(vm.alloc(Object::Array(result)))
+}
+
+fn native_max(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let mut max = ::std::f32::NEG_INFINITY;
+  for arg in args.iter() {
+    max = max.max(vm.to_number(arg)?);
+  }
+  Ok(Value::Num(max))
+}
+
+fn native_min(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let mut min = ::std::f32::INFINITY;
+  for arg in args.iter() {
+    min = min.min(vm.to_number(arg)?);
+  }
+  Ok(Value::Num(min))
+}
+
+// iter(x) returns the items a `for-in` loop walks: indices of an array,
+// keys of a dict, or the array returned by the dict's `__iter__` method
+fn native_iter(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let value = args.get(0).cloned().unwrap_or(Value::Null);
+
+  let (items, method) = match value {
+    Value::Obj(obj) => match *vm.object(obj) {
+      Object::Array(ref items) =>
+        ((0..items.len()).map(|i| Value::Num(i as f32)).collect(), None),
+      Object::Dict(ref entries) => match entries.iter().find(|e| e.0 == "__iter__") {
+        Some(e) => (vec![], Some(e.1.clone())),
+        None => (entries.iter().map(|e| Value::Str(e.0.clone())).collect(), None)
+      },
+      Object::Frame(_) => (vec![], None)
+    },
+    ref v => return Err(VmError::TypeError(format!("{} is not iterable", vm.to_string(v))))
+  };
+
+  match method {
+    Some(func) => {
+      let result = vm.call_method(func, value, &[])?;
+      array_items(vm, Some(&result))?;
+      Ok(result)
+    },
+    None => Ok(vm.alloc(Object::Array(items)))
+  }
+}
+
+fn native_json_stringify(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let mut text = String::new();
+  json_write(vm, args.get(0).unwrap_or(&Value::Null), &mut vec![], &mut text)?;
+  Ok(Value::Str(text))
+}
+
+// `path` holds the objects being written, meeting one of them again means a cycle
+fn json_write(vm: &Vm, value: &Value, path: &mut Vec<usize>, out: &mut String) -> Result<(), VmError> {
+  match value {
+    &Value::Null => *out += "null",
+    &Value::Bool(b) => *out += &b.to_string(),
+    &Value::Int(i) => *out += &i.to_string(),
+    &Value::Num(n) if n.is_finite() => *out += &n.to_string(),
+    &Value::Num(_) => *out += "null",
+    &Value::Str(ref s) => *out += &json_string(s),
+    &Value::Obj(obj) => {
+      if path.contains(&obj) {
+        return Err(VmError::TypeError("cannot convert a cyclic structure to JSON".to_string()));
+      }
+      path.push(obj);
+
+      match *vm.object(obj) {
+        Object::Array(ref items) => {
+          out.push('[');
+          for (i, item) in items.iter().enumerate() {
+            if i > 0 { out.push(','); }
+            json_write(vm, item, path, out)?;
+          }
+          out.push(']');
+        },
+        Object::Dict(ref entries) => {
+          out.push('{');
+          for (i, &(ref key, ref item)) in entries.iter().enumerate() {
+            if i > 0 { out.push(','); }
+            *out += &json_string(key);
+            out.push(':');
+            json_write(vm, item, path, out)?;
+          }
+          out.push('}');
+        },
+        Object::Frame(_) => return Err(VmError::TypeError("cannot convert a frame to JSON".to_string()))
+      }
+
+      path.pop();
+    },
+    v => return Err(VmError::TypeError(format!("cannot convert {} to JSON", vm.format_value(v))))
+  }
+
+  Ok(())
+}
+
+fn native_json_parse(vm: &mut Vm, args: &[Value]) -> Result<Value, VmError> {
+  let text = vm.to_string(args.get(0).unwrap_or(&Value::Null));
+  let chars: Vec<char> = text.chars().collect();
+
+  let mut parser = JsonParser { chars: &chars, pos: 0 };
+  let value = parser.value(vm)?;
+  parser.skip_spaces();
+
+  if parser.pos < chars.len() {
+    return Err(parser.error());
+  }
+
+  Ok(value)
+}
+
+struct JsonParser<'a> {
+  chars: &'a [char],
+  pos: usize
+}
+
+impl<'a> JsonParser<'a> {
+  fn error(&self) -> VmError {
+    VmError::TypeError(format!("invalid JSON at character {}", self.pos))
+  }
+
+  fn peek(&self) -> Option<char> {
+    self.chars.get(self.pos).cloned()
+  }
+
+  fn skip_spaces(&mut self) {
+    while self.peek().map_or(false, |c| c.is_whitespace()) {
+      self.pos += 1;
+    }
+  }
+
+  fn expect(&mut self, c: char) -> Result<(), VmError> {
+    self.skip_spaces();
+    if self.peek() != Some(c) {
+      return Err(self.error());
+    }
+    self.pos += 1;
+    Ok(())
+  }
+
+  fn word(&mut self, word: &str, value: Value) -> Result<Value, VmError> {
+    for c in word.chars() {
+      self.expect(c)?;
+    }
+    Ok(value)
+  }
+
+  fn value(&mut self, vm: &mut Vm) -> Result<Value, VmError> {
+    self.skip_spaces();
+
+    match self.peek() {
+      Some('n') => self.word("null", Value::Null),
+      Some('t') => self.word("true", Value::Bool(true)),
+      Some('f') => self.word("false", Value::Bool(false)),
+      Some('"') => Ok(Value::Str(self.string()?)),
+      Some('[') => {
+        self.pos += 1;
+        let mut items = vec![];
+
+        self.skip_spaces();
+        if self.peek() == Some(']') {
+          self.pos += 1;
+        } else {
+          loop {
+            items.push(self.value(vm)?);
+            self.skip_spaces();
+            match self.peek() {
+              Some(',') => self.pos += 1,
+              Some(']') => { self.pos += 1; break; },
+              _ => return Err(self.error())
+            }
+          }
+        }
+
+        Ok(vm.alloc(Object::Array(items)))
+      },
+      Some('{') => {
+        self.pos += 1;
+        let mut entries = vec![];
+
+        self.skip_spaces();
+        if self.peek() == Some('}') {
+          self.pos += 1;
+        } else {
+          loop {
+            self.skip_spaces();
+            let key = self.string()?;
+            self.expect(':')?;
+            entries.push((key, self.value(vm)?));
+            self.skip_spaces();
+            match self.peek() {
+              Some(',') => self.pos += 1,
+              Some('}') => { self.pos += 1; break; },
+              _ => return Err(self.error())
+            }
+          }
+        }
+
+        Ok(vm.alloc(Object::Dict(entries)))
+      },
+      Some(c) if c == '-' || c.is_digit(10) => {
+        let begin = self.pos;
+        while self.peek().map_or(false, |c| c.is_digit(10) || "+-.eE".contains(c)) {
+          self.pos += 1;
+        }
+        let number: String = self.chars[begin..self.pos].iter().collect();
+        number.parse::<f32>().map(Value::Num).map_err(|_| { self.pos = begin; self.error() })
+      },
+      _ => Err(self.error())
+    }
+  }
+
+  fn string(&mut self) -> Result<String, VmError> {
+    if self.peek() != Some('"') {
+      return Err(self.error());
+    }
+    self.pos += 1;
+
+    let mut s = String::new();
+    loop {
+      let c = self.peek().ok_or(self.error())?;
+      self.pos += 1;
+
+      match c {
+        '"' => return Ok(s),
+        '\\' => {
+          let c = self.peek().ok_or(self.error())?;
+          self.pos += 1;
+          match c {
+            'n' => s.push('\n'),
+            'r' => s.push('\r'),
+            't' => s.push('\t'),
+            'b' => s.push('\u{8}'),
+            'f' => s.push('\u{c}'),
+            'u' => {
+              let code: String = self.chars.iter().skip(self.pos).take(4).collect();
+              let c = u32::from_str_radix(&code, 16).ok().and_then(::std::char::from_u32);
+              s.push(c.ok_or(self.error())?);
+              self.pos += 4;
+            },
+            c => s.push(c)
+          }
+        },
+        c => s.push(c)
+      }
+    }
+  }
+}
+
+// Heap objects directly referenced by the value
+fn references(value: &Value, out: &mut Vec<usize>) {
+  match value {
+    &Value::Obj(obj) | &Value::Slot(obj, _) => out.push(obj),
+    &Value::Fn(ref f) => {
+      for v in f.env.iter() {
+        references(v, out);
+      }
+    },
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pipeline::{compile, CompileOptions};
+  use syntax_tree::{NodeType, OpType};
+
+  fn run(text: &str) -> String {
+    run_with(text, &CompileOptions::default())
+  }
+
+  fn run_optimized(text: &str) -> String {
+    run_with(text, &CompileOptions { optimize: true, ..CompileOptions::default() })
+  }
+
+  fn run_with(text: &str, options: &CompileOptions) -> String {
+    let code = compile(text, options, None).ok().unwrap().bytecode;
+
+    let mut vm = Vm::new(code);
+    vm.capture_output();
+    vm.run().unwrap();
+    vm.output().to_string()
+  }
+
+  #[test]
+  fn test_basics() {
+    assert_eq!(run("std.io.println(1 + 2 * 3, 'a' + 'b', 7 % 4);"), "7 ab 3\n");
+    assert_eq!(run("var a = [1, 2]; a[2] = 3; std.io.println(a, a.length);"), "[1, 2, 3] 3\n");
+    assert_eq!(run("var d = {x: 1}; d.y = d.x + 1; std.io.println(d);"), "{x: 1, y: 2}\n");
+    assert_eq!(run("var f = fn(a, b) { return a - b; }; std.io.println(f(5, 3));"), "2\n");
+    assert_eq!(run("var o = {v: 2, get: fn() { return this.v; }}; std.io.println(o.get());"), "2\n");
+    assert_eq!(run("var i = 0; while (i < 3) { i = i + 1; } std.io.println(i);"), "3\n");
+    assert_eq!(run("if (1 > 2) { std.io.println('a'); } else { std.io.println('b'); }"), "b\n");
+  }
+
+  #[test]
+  fn test_assign_ops() {
+    let text = "
+      var a = 10; a %= 3;
+      var b = [2]; b[0] *= 4; b[0] -= 1;
+      var c = 'x'; c += 'y';
+      std.io.println(a, b, c);";
+    assert_eq!(run(text), "1 [7] xy\n");
+
+    let text = "
+      var a = 3; a <<= 2;
+      var b = -20; b >>= 2;
+      var c = {x: 12}; c.x &= 10;
+      var d = [5]; d[0] |= 2;
+      var e = 6; e ^= 3;
+      std.io.println(a, b, c.x, d[0], e);";
+    assert_eq!(run(text), "12 -5 8 7 5\n");
+  }
+
+  #[test]
+  fn test_short_circuit() {
+    let text = "
+      var calls = 0;
+      var f = fn(x) { calls = calls + 1; return x; };
+      var a = 0 && f(1);
+      var b = 2 && f(3);
+      var c = 'x' || f(4);
+      var d = void 0 || f(0);
+      var o = void 0;
+      var e = o && o.field;
+      std.io.println(a, b, c, d, e, calls);";
+    assert_eq!(run(text), "0 3 x 0 null 2\n");
+  }
+
+  #[test]
+  fn test_ternary() {
+    let text = "
+      var sign = fn(x) { return x > 0 ? 'pos' : x < 0 ? 'neg' : 'zero'; };
+      var calls = 0;
+      var f = fn(x) { calls = calls + 1; return x; };
+      var a = 2;
+      var b = (a > 1 ? f(10) : f(20)) + a;
+      std.io.println(sign(3), sign(-1), sign(0), b, calls, 1 ? 2 ? 'x' : 'y' : 'z');";
+    assert_eq!(run(text), "pos neg zero 12 1 x\n");
+  }
+
+  #[test]
+  fn test_increments() {
+    let text = "
+      var x = 5;
+      var a = x++;
+      var b = ++x;
+      var c = x--;
+      var d = --x;
+      var o = {n: 1, items: [10]};
+      o.n++;
+      ++o.items[0];
+      var i = 0;
+      var e = [i++, i++, i];
+      var s = '';
+      for (var k = 0; k < 3; k++) { s = s + k; }
+      std.io.println(a, b, c, d, x, o, e, s);";
+    assert_eq!(run(text), "5 7 7 5 5 {n: 2, items: [11]} [0, 1, 2] 012\n");
+  }
+
+  #[test]
+  fn test_computed_keys() {
+    let text = "
+      var k = 'b';
+      var d = {a: 1, ['a' + k]: 2, [1 + 1]: 3, ['len' + 'gth']: 4};
+      std.io.println(d, d.ab, d[2], d.length);";
+    assert_eq!(run(text), "{a: 1, ab: 2, 2: 3, length: 4} 2 3 4\n");
+  }
+
+  #[test]
+  fn test_duplicate_keys() {
+    let text = "
+      var k = 'a';
+      var d = {a: 1, b: 2, a: 3};
+      var e = {[k]: 1, b: 2, a: 3};
+      var keys = '';
+      for (var key in d) { keys = keys + key; }
+      std.io.println(d, d.a, d.length, keys, e, e.a, e.length);";
+    assert_eq!(run(text), "{a: 3, b: 2} 3 2 ab {a: 3, b: 2} 3 2\n");
+  }
+
+  #[test]
+  fn test_literal_length() {
+    let text = "
+      var f = fn() { return 1; };
+      std.io.println([f(), f()].length, {a: 1, b: 2, c: 3}.length, {length: 5}.length, 'при'.length, [].length);";
+    assert_eq!(run(text), "2 3 5 3 0\n");
+  }
+
+  #[test]
+  fn test_string_comparison() {
+    let text = "
+      var s = 'ab';
+      std.io.println('abc' == 'abc', 'abc' != 'abc', s + 'c' == 'abc', 'abc' == 'abd');
+      std.io.println('abc' < 'abd', 'b' > 'abc', 'ab' <= 'ab', '10' < '9');";
+    assert_eq!(run(text), "true false true false\ntrue true true true\n");
+  }
+
+  #[test]
+  fn test_const() {
+    let text = "
+      const a = 2;
+      const f = fn(x) { const b = x * a; return b; };
+      std.io.println(f(3), a);";
+    assert_eq!(run(text), "6 2\n");
+  }
+
+  #[test]
+  fn test_void() {
+    let text = "
+      var calls = 0;
+      var f = fn() { calls = calls + 1; return 5; };
+      var r = void f();
+      std.io.println(r, calls);";
+    assert_eq!(run(text), "null 1\n");
+  }
+
+  #[test]
+  fn test_nested_assignment() {
+    let text = "
+      var a = [{x: 0, b: [1, 2]}];
+      a[0].x = 5;
+      a[0].b[1] = 7;
+      a[0].y = {};
+      a[0].y.z = 3;
+      std.io.println(a);";
+    assert_eq!(run(text), "[{x: 5, b: [1, 7], y: {z: 3}}]\n");
+
+    let text = "
+      var calls = 0;
+      var id = fn(x) { calls = calls + 1; return x; };
+      var a = [{b: [1, 2]}];
+      a[id(0)].b[id(1)] = 7;
+      std.io.println(a, calls);";
+    assert_eq!(run(text), "[{b: [1, 7]}] 2\n");
+  }
+
+  #[test]
+  fn test_offset() {
+    use std::io::Cursor;
+    use assembler::Assembler;
+
+    let mut code = Cursor::new(vec![]);
+    {
+      let mut asm = Assembler::new(&mut code, None);
+      asm.push_array(0);
+      asm.offset(16777216);
+      asm.offset(1);
+      asm.finish().unwrap();
+    }
+
+    let mut vm = Vm::new(code.into_inner());
+    vm.run().unwrap();
+
+    // 16777217 is not representable as f32
+    match vm.stack.last() {
+      Some(&Value::Slot(_, Key::Index(i))) => assert_eq!(i, 16777217),
+      v => panic!("address expected, got {:?}", v)
+    }
+  }
+
+  #[test]
+  fn test_invalid_index() {
+    let run_err = |text: &str| {
+      let code = compile(text, &CompileOptions::default(), None).ok().unwrap().bytecode;
+      Vm::new(code).run().err().unwrap()
+    };
+
+    assert_eq!(run_err("var a = [1, 2]; var b = a[0.5];"), VmError::InvalidIndex(0.5));
+    assert_eq!(run_err("var a = [1, 2]; a[16777217] = 1;"), VmError::InvalidIndex(16777216.0));
+    assert_eq!(run("var a = [1, 2]; a[1.0] = 3; std.io.println(a);"), "[1, 3]\n");
+    assert_eq!(run_err("var a = [1, 2]; a.length = -1;"), VmError::InvalidIndex(-1.0));
+    assert!(match run_err("var s = 'ab'; s.length = 1;") { VmError::TypeError(_) => true, _ => false });
+  }
+
+  #[test]
+  fn test_optional_member() {
+    let text = "
+      var o = {a: {b: {c: 1}}};
+      var n = void 0;
+      std.io.println(o?.a?.b?.c, o.a?.b.c, n?.a, n?.a?.b, o?.x?.y);";
+    assert_eq!(run(text), "1 1 null null null\n");
+
+    // a null object skips the rest of the chain, including calls and their arguments
+    let text = "
+      var o = {v: 2, m: fn(x) { return this.v + x; }, p: {q: {r: 3}}};
+      var n = void 0; var calls = 0;
+      var f = fn() { calls = calls + 1; return 1; };
+      std.io.println(n?.a.b, n?.a.b.c(f()), n?.m(f()), n?.a[f()], calls);
+      std.io.println(o?.m(1), o?.p.q.r, o.p?.q.r, o?.p.x, o?.m(f()) + 1, calls);";
+    assert_eq!(run(text), "null null null null 0\n3 3 3 null 4 1\n");
+    assert_eq!(run_optimized(text), "null null null null 0\n3 3 3 null 4 1\n");
+  }
+
+  #[test]
+  fn test_array_native() {
+    let text = "
+      var a = std.array(3, 0);
+      var b = std.array(2, a);
+      a[1] = 5;
+      std.io.println(a, a.length, b[0][1], std.array(0, 1));";
+    assert_eq!(run(text), "[0, 5, 0] 3 5 []\n");
+  }
+
+  #[test]
+  fn test_native_callbacks() {
+    assert_eq!(run("std.io.println(std.map([1, 2, 3], fn(x) { return x * 2; }));"), "[2, 4, 6]\n");
+
+    let text = "
+      var k = 10;
+      var odd = std.filter([1, 2, 3, 4, 5], fn(x) { return x % 2 == 1; });
+      var nested = std.map([1, 2], fn(x) {
+        return std.map([x, k], fn(y) { return x + y; });
+      });
+      std.io.println(odd, nested);";
+    assert_eq!(run(text), "[1, 3, 5] [[2, 11], [4, 12]]\n");
+  }
+
+  #[test]
+  fn test_nullish() {
+    let text = "
+      var n = void 0;
+      var calls = 0;
+      var f = fn() { calls = calls + 1; return 1; };
+      std.io.println(0 ?? 5, 0 || 5, n ?? 5, '' ?? 'x', n ?? n ?? 2, 3 ?? f(), calls);";
+    assert_eq!(run(text), "0 5 5  2 3 0\n");
+  }
+
+  #[test]
+  fn test_labels() {
+    let text = "
+      var i = 0; var n = 0;
+      outer: while (i < 3) {
+        i = i + 1;
+        var j = 0;
+        while (j < 3) {
+          j = j + 1;
+          if (j == 2) { continue outer; }
+          if (i == 3) { break outer; }
+          n = n + 1;
+        }
+      }
+      skip: { n = n * 10; break skip; n = 0; }
+      std.io.println(i, n);";
+    assert_eq!(run(text), "3 20\n");
+  }
+
+  #[test]
+  fn test_break_continue() {
+    let text = "
+      var x = 0;
+      while (1) { x = x + 1; if (x > 3) { break; } }
+      var odd = [];
+      for (var i = 0; i < 6; i += 1) {
+        if (i % 2 == 0) { continue; }
+        odd[odd.length] = i;
+      }
+      var pairs = 0;
+      outer: while (1) {
+        for (var k in [1, 2, 3]) { if (k == 1) { break; } pairs = pairs + 1; }
+        { break; }
+      }
+      std.io.println(x, odd, pairs);";
+    assert_eq!(run(text), "4 [1, 3, 5] 1\n");
+  }
+
+  #[test]
+  fn test_function_declarations() {
+    let code = compile("function foo(){ return 1; } return foo();", &CompileOptions::default(), None)
+      .ok().unwrap().bytecode;
+    let mut vm = Vm::new(code);
+    vm.run().unwrap();
+    assert_eq!(vm.stack.last().map(|v| vm.format_value(v)), Some("1".to_string()));
+
+    let text = "
+      std.io.println(even(4), odd(4));
+      function even(n) { if (n == 0) { return 1; } return odd(n - 1); }
+      function odd(n) { if (n == 0) { return 0; } return even(n - 1); }";
+    assert_eq!(run(text), "1 0\n");
+  }
+
+  #[test]
+  fn test_block_expressions() {
+    let text = "
+      var x = { var a = 1; a + 1 };
+      var y = 10 * { var i = 0; while (i < 3) { i = i + 1; } i } + 1;
+      var z = { x = x + 1; };
+      var d = {a: 1};
+      std.io.println(x, y, z, d.a, {}, { 'b' });";
+    assert_eq!(run(text), "3 31 null 1 {} b\n");
+  }
+
+  #[test]
+  fn test_string_length() {
+    let text = "var s = 'при' + 'вет'; std.io.println(s.length, 'abc'.length, s['length']);";
+    assert_eq!(run(text), "6 3 6\n");
+
+    assert_eq!(run_optimized(text), "6 3 6\n");
+
+    // no temporary objects are allocated for the lengths of strings
+    let code = compile("var s = 'abc'; var n = s.length + s?.length;", &CompileOptions::default(), None).ok().unwrap();
+    let mut vm = Vm::new(code.bytecode);
+    let heap = vm.heap.len();
+    vm.run().unwrap();
+    assert_eq!(vm.heap.len(), heap + 1);
+
+    let text = "
+      var d = {length: 5, a: 1}; var e = {a: 1}; var a = [1, 2];
+      e.length = 'x';
+      std.io.println(d.length, d['length'], e.length, a.length, d?.length);";
+    assert_eq!(run(text), "5 5 x 2 5\n");
+
+    let text = "var a = [1, 2, 3]; a.length = 1; var b = [1]; b['length'] += 2; std.io.println(a, b);";
+    assert_eq!(run(text), "[1] [1, null, null]\n");
+  }
+
+  #[test]
+  fn test_do_while() {
+    let text = "
+      var n = 0;
+      do { n = n + 1; } while (n > 5);
+      var s = '';
+      outer: do {
+        n = n + 1;
+        if (n % 2 == 0) { continue outer; }
+        if (n > 8) { break; }
+        s = s + n;
+      } while (n < 10);
+      std.io.println(n, s);";
+    assert_eq!(run(text), "9 357\n");
+  }
+
+  #[test]
+  fn test_switch() {
+    let text = "
+      var name = fn(n) {
+        var s = '';
+        switch (n) {
+          case 1: s = 'one'; break;
+          default: s = 'many';
+          case 0: s = s + 'none'; break;
+          case 'x': return 'str';
+        }
+        return s;
+      };
+      var skipped = 0;
+      for (var i = 0; i < 4; i += 1) {
+        switch (i % 2) { case 0: continue; }
+        skipped = skipped + 1;
+      }
+      std.io.println(name(0), name(1), name(5), name('x'), skipped);";
+    assert_eq!(run(text), "none one manynone str 2\n");
+  }
+
+  #[test]
+  fn test_string_switch() {
+    let text = "
+      var color = fn(s) {
+        switch (s) {
+          case 'r': return 'red';
+          case 'g': case 'G': return 'green';
+          default: return 'none';
+          case 'b': return 'blue';
+        }
+      };
+      std.io.println(color('r'), color('G'), color('b'), color('rg'), color(1));";
+    assert_eq!(run(text), "red green blue none none\n");
+  }
+
+  #[test]
+  fn test_arrow_functions() {
+    let text = "
+      var add = (a, b) => a + b;
+      var twice = f => x => f(f(x));
+      var abs = (x) => { if (x < 0) { return -x; } return x; };
+      std.io.println(add(1, 2), twice(x => x * 3)(2), abs(-4), std.map([1, 2], x => x + 1));";
+    assert_eq!(run(text), "3 18 4 [2, 3]\n");
+
+    // `this` is the object called on, not the one of the enclosing function
+    let text = "
+      var o = {v: 1, get: () => this.v, outer: fn() { var f = () => this; return f(); }};
+      std.io.println(o.get(), o.outer());";
+    assert_eq!(run(text), "1 null\n");
+  }
+
+  #[test]
+  fn test_for_loop() {
+    let text = "
+      var s = '';
+      for (var i = 0; i < 5; i += 1) { s = s + i; }
+      var n = 0;
+      outer: for (;; n += 1) {
+        if (n < 3) { continue outer; }
+        break outer;
+      }
+      var j = 10;
+      for (j = 0; j < 6;) {
+        j += 2;
+        loop: for (var k in [1, 2]) { s = s + '.'; continue loop; }
+      }
+      for (var a = 0, b = 3; a < b; a += 1) { s = s + b; }
+      std.io.println(s, i, n, j);";
+    assert_eq!(run(text), "01234......333 5 3 6\n");
+  }
+
+  #[test]
+  fn test_for_in() {
+    let text = "
+      var n = 0; var keys = '';
+      for (var i in [5, 6, 7]) { n = n + i; }
+      for (k in {a: 1, b: 2}) { keys = keys + k; }
+      var range = {n: 3, __iter__: fn() { return std.map(std.array(this.n, 0), fn(x) { return 'x'; }); }};
+      var r = '';
+      outer: for (var x in range) {
+        inner: for (var y in [1, 2]) {
+          if (y == 1) { continue inner; }
+          if (r == 'xx') { break outer; }
+          r = r + x;
+        }
+      }
+      std.io.println(n, keys, r, i, k);";
+    assert_eq!(run(text), "3 ab xx 2 b\n");
+  }
+
+  #[test]
+  fn test_variadic_natives() {
+    assert_eq!(run("std.io.println(Math.max(1, 5, 3));"), "5\n");
+    assert_eq!(run("var a = [Math.max(2), 7, Math.min(4, -1, 3, 0)]; std.io.println(a);"), "[2, 7, -1]\n");
+
+    use std::io::Cursor;
+    use assembler::Assembler;
+
+    // the count is checked against the parameters of the native, here it
+    // claims more arguments than were pushed
+    let mut code = Cursor::new(vec![]);
+    {
+      let mut asm = Assembler::new(&mut code, None);
+      let ret = asm.gen_label();
+      asm.put_label(ret);
+      asm.push_float(1.0);
+      asm.push_float(2.0);
+      asm.push_int(3);
+      asm.push_int(0);
+      asm.load(0);
+      asm.push_str("iter");
+      asm.get();
+      asm.call(3);
+      asm.fill_label(ret);
+      asm.finish().unwrap();
+    }
+
+    let mut vm = Vm::new(code.into_inner());
+    assert_eq!(vm.run(), Err(VmError::TooManyArguments(1, 3)));
+
+    let code = compile("std.jsonParse('1', 2);", &CompileOptions::default(), None).ok().unwrap().bytecode;
+    assert_eq!(Vm::new(code).run(), Err(VmError::TooManyArguments(1, 2)));
+  }
+
+  #[test]
+  fn test_json() {
+    let text = "
+      var v = {a: [1, 'x', {b: 2.5}], c: 'say \"hi\"', d: [[], {}], e: 1 == 1, f: void 0};
+      var s = std.jsonStringify(v);
+      var w = std.jsonParse(s);
+      std.io.println(s);
+      std.io.println(std.jsonStringify(w) == s, w.a[2].b, w.d.length);";
+    assert_eq!(run(text), "{\"a\":[1,\"x\",{\"b\":2.5}],\"c\":\"say \\\"hi\\\"\",\"d\":[[],{}],\"e\":true,\"f\":null}\n\
+                           true 2.5 2\n");
+
+    let parsed = "var v = std.jsonParse(' [ -1.5e1, \"\\\\u0041\\\\\"\", null, false, {\"k\": []} ] ');
+                  std.io.println(v);";
+    assert_eq!(run(parsed), "[-15, 'A\"', null, false, {k: []}]\n");
+
+    let err = |text: &str| {
+      let code = compile(text, &CompileOptions::default(), None).ok().unwrap().bytecode;
+      Vm::new(code).run().err().unwrap()
+    };
+    assert_eq!(err("var a = [1]; a[1] = a; std.jsonStringify(a);"),
+               VmError::TypeError("cannot convert a cyclic structure to JSON".to_string()));
+    assert_eq!(err("std.jsonParse('[1,');"),
+               VmError::TypeError("invalid JSON at character 3".to_string()));
+  }
+
+  #[test]
+  fn test_nop() {
+    use std::io::Cursor;
+    use assembler::Assembler;
+
+    let run_code = |padded: bool| {
+      let mut code = Cursor::new(vec![]);
+      {
+        let mut asm = Assembler::new(&mut code, None);
+        let nop = |asm: &mut Assembler<Cursor<Vec<u8>>>| if padded { asm.nop(); asm.nop(); };
+
+        let skip = asm.gen_label();
+        asm.push_float(2.0);
+        nop(&mut asm);
+        asm.push_float(3.0);
+        asm.op_binary(&NodeType::Op(OpType::OpMul));
+        asm.push_float(1.0);
+        asm.put_label(skip);
+        nop(&mut asm);
+        asm.jump_if();
+        asm.push_float(100.0);
+        asm.fill_label(skip);
+        nop(&mut asm);
+        asm.push_str("end");
+        asm.finish().unwrap();
+      }
+
+      let padded_len = code.get_ref().len();
+      let mut vm = Vm::new(code.into_inner());
+      vm.run().unwrap();
+      let stack: Vec<String> = vm.stack.iter().map(|v| vm.format_value(v)).collect();
+      (stack, padded_len)
+    };
+
+    let (compact, compact_len) = run_code(false);
+    let (padded, padded_len) = run_code(true);
+    assert_eq!(compact, vec!["6", "'end'"]);
+    assert_eq!(padded, compact);
+    assert_eq!(padded_len, compact_len + 6);
+  }
+
+  #[test]
+  fn test_collect_cycles() {
+    let live_after = |text: &str| {
+      let code = compile(text, &CompileOptions::default(), None).ok().unwrap().bytecode;
+      let mut vm = Vm::new(code);
+      vm.run().unwrap();
+      let before = vm.live_objects();
+      vm.collect();
+      (before, vm.live_objects())
+    };
+
+    let base = "var f = fn() { var a = {}; a.self = a; return 0; };";
+    let (_, baseline) = live_after(base);
+    let (before, after) = live_after(&(base.to_string() + "f(); f();"));
+
+    // two frames of `f` and two self-referencing dicts
+    assert_eq!(before, baseline + 4);
+    assert_eq!(after, baseline);
+  }
+
+  #[test]
+  fn test_returned_closures() {
+    // Frames live on the heap as long as a closure refers to them, so inner
+    // functions keep reading variables of callers which have returned
+    let text = "
+      var a = fn() {
+        var b = 13;
+        var c = fn() {
+          var d = 12;
+          return fn() { b = b + 1; return d + b; };
+        };
+        return c;
+      };
+      var inner = a()();
+      var other = a()();
+      std.io.println(inner(), inner(), other());";
+    assert_eq!(run(text), "26 27 26\n");
+  }
+
+  #[test]
+  fn test_closures_in_loop() {
+    // Closures capture a reference to the defining frame rather than copies
+    // of its variables, so every function created in the loop sees the final
+    // value of a shared loop variable.
+    let text = "
+      var fns = [];
+      var i = 0;
+      while (i < 3) {
+        fns[i] = fn() { return i; };
+        i = i + 1;
+      }
+      std.io.println(fns[0](), fns[1](), fns[2]());";
+    assert_eq!(run(text), "3 3 3\n");
+
+    // A per-iteration value has to be captured by a frame created for it
+    let text = "
+      var fns = [];
+      var i = 0;
+      while (i < 3) {
+        fns[i] = fn(j) { return fn() { return j; }; }(i);
+        i = i + 1;
+      }
+      std.io.println(fns[0](), fns[1](), fns[2]());";
+    assert_eq!(run(text), "0 1 2\n");
+  }
+
+  #[test]
+  fn test_exit_code() {
+    let exit_code = |text: &str| {
+      let code = compile(text, &CompileOptions::default(), None).ok().unwrap().bytecode;
+      let mut vm = Vm::new(code);
+      vm.capture_output();
+      vm.run().unwrap();
+      (vm.exit_code(), vm.output().to_string())
+    };
+
+    assert_eq!(exit_code("std.io.print('a'); std.exit(2); std.io.print('b');"), (Some(2), "a".to_string()));
+    assert_eq!(exit_code("var f = fn() { std.exit(); }; f(); return 5;"), (Some(0), "".to_string()));
+    assert_eq!(exit_code("return 3;"), (Some(3), "".to_string()));
+    assert_eq!(exit_code("return 'x';"), (None, "".to_string()));
+    assert_eq!(exit_code("var x = 1;"), (None, "".to_string()));
+  }
+
+  #[test]
+  fn test_unary_plus() {
+    assert_eq!(run("std.io.println(+'42', +(1 == 1), +(1 == 2), +' 7 ' + 1);"), "42 1 0 8\n");
+    assert_eq!(run("var x = +'abc'; std.io.println(x == x, +[1], +void 0);"), "false NaN 0\n");
+  }
+
+  #[test]
+  fn test_radix_literals() {
+    assert_eq!(run("std.io.println(0x1F, 0o17, 0b1010, 0xff + 1, {0x10: 'a'}[16]);"), "31 15 10 256 a\n");
+  }
+
+  #[test]
+  fn test_bitwise_ops() {
+    assert_eq!(run("std.io.println(6 & 3, 6 | 3, 6 ^ 3, ~5, 1 << 4, -16 >> 2, 1 << 33);"), "2 7 5 -6 16 -4 2\n");
+    // operands are truncated to 32-bit integers
+    assert_eq!(run("std.io.println(5.7 | 0, -5.7 | 0, 4294968320 | 0, 2147483648 | 0, +'x' | 0);"),
+               "5 -5 1024 -2147483600 0\n");
+    assert_eq!(run("std.io.println(1 | 2 == 2, (1 | 2) == 3);"), "1 true\n");
+  }
+
+  #[test]
+  fn test_quoted_keys() {
+    let text = "
+      var o = {'return': 5, 'with space': 2};
+      var key = 'with' + ' space';
+      o['return'] += 1;
+      std.io.println(o['return'], o[key], o);";
+    assert_eq!(run(text), "6 2 {return: 6, with space: 2}\n");
+  }
+
+  #[test]
+  fn test_typeof() {
+    let text = "
+      std.io.println(typeof notDeclared, typeof notDeclared == 'undefined');
+      var f = fn() {};
+      std.io.println(typeof 1, typeof 'a', typeof (1 == 1), typeof void 0);
+      std.io.println(typeof f, typeof std.io.println, typeof [1], typeof std);";
+    assert_eq!(run(text), "undefined true\nnumber string boolean undefined\nfunction function object object\n");
+  }
+
+  #[test]
+  fn test_arguments() {
+    assert_eq!(run("std.io.println(std.apply(fn(a, b) { return a + b; }, [3, 4]));"), "7\n");
+
+    let text = "
+      var count = fn() { return arguments.length; };
+      var first = fn(a) { var b = 2; return [a, b, arguments[1], arguments]; };
+      var o = {v: 1, m: fn(x) { return this.v + x + arguments.length; }};
+      std.io.println(count(), count(1, 2, 3), std.apply(count, [1, 2]));
+      std.io.println(first(1, 'x', 3), first());
+      std.io.println(o.m(10, 20, 30));";
+    assert_eq!(run(text), "0 3 2\n[1, 2, 'x', [1, 'x', 3]] [null, 2, null, []]\n14\n");
+    assert_eq!(run_optimized(text), run(text));
+  }
+
+  #[test]
+  fn test_common_subexpressions() {
+    let text = "
+      var a = [3, 4]; var d = {x: 2, a: a}; var i = 0; var s = 'ab';
+      var x = a[i] * a[i] + d.x * d.x;
+      a[i + 1] = (a[i + 1] + d.a[i]) * (a[i + 1] + d.a[i]);
+      var n = 0;
+      var y = (n + 1) * (n + 1) + n++ + (n + 1);
+      var z = (s + 'c') + (s + 'c') + (s + 'c').length;
+      std.io.println(x, a, y, z);";
+    assert_eq!(run(text), "13 [3, 49] 3 abcabc3\n");
+    assert_eq!(run_optimized(text), run(text));
+  }
+
+  #[test]
+  fn test_method_shorthand() {
+    let text = "
+      var o = {name: 'hi', greet() { return this.name; }, add(a, b) { return a + b; }};
+      std.io.println(o.greet(), o.add(1, 2));";
+    assert_eq!(run(text), "hi 3\n");
+
+    let text = "
+      var x = 1;
+      var make = fn(y) { return {x, y, z: x + y}; };
+      std.io.println(make(2));";
+    assert_eq!(run(text), "{x: 1, y: 2, z: 3}\n");
+
+    assert_eq!(run("var x = 1; var d = {x}; std.io.println(d, d.x);"), "{x: 1} 1\n");
+  }
+
+  #[test]
+  fn test_function_values() {
+    let text = "
+      var ops = {add: fn(a, b) { return a + b; }, mul: fn(a, b) { return a * b; }};
+      var name = 'm' + 'ul';
+      var table = [ops.add, ops[name]];
+      std.io.println(ops['add'](2, 3), ops[name](2, 3), table[1](4, 5));
+
+      var f = ops.add;
+      var make = fn() { return fn() { return 1; }; };
+      std.io.println(f == ops.add, f == ops.mul, make() == make(), table[0] == f);
+      std.io.println(std.io.print == std.io.print, std.io.print == std.io.println, f == 1);";
+    assert_eq!(run(text), "5 6 20\ntrue false false true\ntrue false false\n");
+  }
+
+  #[test]
+  fn test_negative_zero() {
+    let text = "
+      var z = 0;
+      std.io.println(-0 == 0, -z == z, -0 != 0, -0 < 0);
+      std.io.println(1 / -0, 1 / 0, 1 / (-0.0), 1 / -z, 1 / (0 * -1));";
+    let expected = "true true false false\n-inf inf -inf -inf -inf\n";
+    assert_eq!(run(text), expected);
+
+    // the sign survives constant folding
+    assert_eq!(run_optimized(text), expected);
+  }
+
+  #[test]
+  fn test_encodings() {
+    let text = "
+      var f = fn(n) { if (n < 2) { return n; } return f(n - 1) + f(n - 2); };
+      var s = ''; var i = 0;
+      while (i < 5) { s = s + f(i); i = i + 1; }
+      std.io.println(s, {a: [1.5, 'x']}, f(6));";
+    let default = compile(text, &CompileOptions::default(), None).ok().unwrap().bytecode;
+
+    for name in ["be32", "le16", "be16"].iter() {
+      let encoding = Encoding::from_name(name).unwrap();
+      let options = CompileOptions { encoding: encoding, ..CompileOptions::default() };
+      let code = compile(text, &options, None).ok().unwrap().bytecode;
+      assert!(code != default);
+      assert_eq!(Encoding::read_header(&code).0, encoding);
+
+      let mut vm = Vm::new(code);
+      vm.capture_output();
+      vm.run().unwrap();
+      assert_eq!(vm.output(), "01123 {a: [1.5, 'x']} 8\n", "{}", name);
+    }
+
+    assert_eq!(Encoding::read_header(&default), (Encoding::default(), &default[..]));
+  }
+
+  #[test]
+  fn test_folded_comparisons() {
+    let text = "
+      var a = 'ab' == 'a' + 'b'; var b = 'ab' != 'ab'; var c = 2 * 2 <= 3;
+      std.io.println(a, b, c, !a, 1 < 2, 'x' + (1 > 2));";
+    let expected = "true false false false true xfalse\n";
+    assert_eq!(run(text), expected);
+    assert_eq!(run_optimized(text), expected);
+  }
+
+  #[test]
+  fn test_peephole() {
+    let text = "
+      var f = fn(n) { if (n < 2) { return n; } return f(n - 1) + f(n - 2); };
+      var s = ''; var i = 0;
+      do { i = i + 1; if (i == 2) { continue; } s = s + f(i); } while (i < 8);
+      for (var k in {a: 1, b: 2}) { switch (k) { case 'a': s = s + k; break; default: s = s + '-'; } }
+      std.io.println(s);";
+    assert_eq!(run(text), "123581321a-\n");
+    assert_eq!(run_optimized(text), "123581321a-\n");
+  }
+
+  #[test]
+  fn test_division_by_zero() {
+    let text = "var x = 5; var z = 0; std.io.println(x / 0, -x / 0, z / 0, x % 0, 1 / 0, 1 % 0, -1 / 0);";
+    assert_eq!(run(text), "inf -inf NaN NaN inf NaN -inf\n");
+
+    // the operands are converted first
+    let code = compile("var n = void 0; var y = n % 0;", &CompileOptions::default(), None).ok().unwrap().bytecode;
+    assert_eq!(Vm::new(code).run(), Err(VmError::TypeError("null is not a number".to_string())));
+  }
+
+  #[test]
+  fn test_null() {
+    let text = "
+      var a = null; var b = undefined;
+      std.io.println(a, b, a == b, a == 0, a != '', typeof b, !a);
+      std.io.println([1][5] == null, {}.x == undefined, a ?? 'default', b?.x);";
+    assert_eq!(run(text), "null null true false true undefined true\ntrue true default null\n");
+  }
+
+  #[test]
+  fn test_nan_conditions() {
+    let text = "
+      var z = 0; var n = z / z; var s = '';
+      if (n < 1) { s = s + 'lt'; } else { s = s + '-'; }
+      if (n >= 1) { s = s + 'geq'; } else { s = s + '-'; }
+      while (n > 1) { s = s + 'gt'; n = 0; }
+      for (; n <= 1;) { s = s + 'leq'; n = 2; }
+      std.io.println(s, n < 1, n >= 1);";
+    assert_eq!(run(text), "-- false false\n");
+  }
+}